@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// One request as the access log records it.
+pub struct Access<'a> {
+    pub token: &'a str,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub bytes: Option<u64>,
+    pub duration_ms: u128,
+    pub client: Option<&'a str>,
+}
+
+/// Appends a combined-log-format line per request to `access_log`, with the token name as the user and the duration last.
+/// Lines go over a channel to a writer thread, so no request waits on the disk or a rotation.
+pub struct AccessLog {
+    lines: mpsc::Sender<String>,
+}
+
+/// Owns the file on the writer thread. Once it would pass `max_bytes` it moves to `<path>.1`, older ones shift up,
+/// and anything past `keep` is dropped.
+struct Writer {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, max_bytes: Option<u64>, keep: usize) -> Result<Self> {
+        let file = append(path).with_context(|| format!("Failed to open access_log {path:?}"))?;
+        let size = file.metadata()?.len();
+
+        let mut writer = Writer {
+            path: path.to_owned(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        };
+
+        let (lines, received) = mpsc::channel::<String>();
+        thread::Builder::new().name("access-log".to_string()).spawn(move || {
+            for line in received {
+                writer.write(&line);
+            }
+        })?;
+
+        Ok(Self { lines })
+    }
+
+    pub fn record(&self, access: &Access) {
+        let line = format!(
+            "- - {} [{}] \"{} {} {}\" {} {} \"-\" \"{}\" {}\n",
+            access.token,
+            clf_date(SystemTime::now()),
+            access.method,
+            access.uri,
+            access.version,
+            access.status,
+            access.bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+            access.client.unwrap_or("-").replace('"', "'"),
+            access.duration_ms
+        );
+
+        let _ = self.lines.send(line);
+    }
+}
+
+impl Writer {
+    fn write(&mut self, line: &str) {
+        if let Some(max_bytes) = self.max_bytes
+            && self.size > 0
+            && self.size + line.len() as u64 > max_bytes
+        {
+            match self.rotate() {
+                Ok(fresh) => (self.file, self.size) = (fresh, 0),
+                Err(err) => warn!("Failed to rotate access log {:?}: {err}", self.path),
+            }
+        }
+
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(err) => warn!("Failed to write access log {:?}: {err}", self.path),
+        }
+    }
+
+    fn rotate(&self) -> std::io::Result<File> {
+        let numbered = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+            return append(&self.path);
+        }
+
+        let _ = fs::remove_file(numbered(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(numbered(n), numbered(n + 1));
+        }
+
+        fs::rename(&self.path, numbered(1))?;
+        append(&self.path)
+    }
+}
+
+fn append(path: &Path) -> std::io::Result<File> { OpenOptions::new().create(true).append(true).open(path) }
+
+/// `16/Oct/2026:14:05:09 +0000`, the timestamp access log tooling expects.
+fn clf_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // days since the epoch to a civil date, Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000", MONTHS[month as usize - 1], rest / 3600, rest % 3600 / 60, rest % 60)
+}
@@ -0,0 +1,237 @@
+use crate::storage::{Blob, ByteStream, Storage, Totals};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{StreamExt, stream};
+use ring::{
+    aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{io, path::Path, sync::Arc};
+use tracing::warn;
+
+/// Starts every sealed blob, followed by that blob's random nonce prefix.
+const MAGIC: &[u8; 8] = b"VOLTENC1";
+const PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = MAGIC.len() + PREFIX_LEN;
+
+/// Plaintext bytes per sealed segment, so blobs stream through without being held in memory whole.
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Reads the 32 byte key from `encryption_key` or `encryption_key_file`, both as hex.
+pub fn load_key(inline: Option<&str>, file: Option<&Path>) -> Result<Option<[u8; 32]>> {
+    let hex = match (inline, file) {
+        (Some(_), Some(_)) => bail!("set only one of encryption_key and encryption_key_file"),
+        (Some(key), None) => key.to_string(),
+        (None, Some(path)) => std::fs::read_to_string(path).with_context(|| format!("Failed to read encryption_key_file {path:?}"))?,
+        (None, None) => return Ok(None),
+    };
+
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("the encryption key must be 64 hex characters, generate one with `openssl rand -hex 32`");
+    }
+
+    let mut key = [0u8; 32];
+    for (n, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[n * 2..n * 2 + 2], 16)?;
+    }
+
+    Ok(Some(key))
+}
+
+/// Seals archives with ChaCha20-Poly1305 on their way into another backend and opens them on the way out.
+/// Each blob is cut into segments under one random nonce prefix kept in its header, the segment counter and a last-segment flag
+/// make up the rest of each nonce, so reordered or truncated blobs fail to open instead of restoring partially.
+/// The key lives with the server, so this protects disks and backups, not archives from the server itself.
+pub struct Encrypted {
+    inner: Arc<dyn Storage>,
+    key: [u8; 32],
+}
+
+impl Encrypted {
+    pub fn wrap(inner: Arc<dyn Storage>, key: Option<[u8; 32]>) -> Arc<dyn Storage> {
+        match key {
+            Some(key) => Arc::new(Self { inner, key }),
+            None => inner,
+        }
+    }
+
+    fn cipher(&self) -> LessSafeKey { LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.key).expect("chacha20-poly1305 takes a 32 byte key")) }
+
+    /// Reads the header off a stored blob. Blobs written before encryption was turned on are reported missing,
+    /// so they turn into a cache miss and get replaced by the next push instead of being served unauthenticated.
+    async fn open(&self, volt_id: &str, blob: Option<Blob>) -> io::Result<Option<Blob>> {
+        let Some(mut blob) = blob else { return Ok(None) };
+        let mut buffer = BytesMut::new();
+
+        while buffer.len() < HEADER_LEN {
+            match blob.stream.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+
+        if buffer.len() < HEADER_LEN || &buffer[..MAGIC.len()] != MAGIC {
+            warn!("Archive for {volt_id} isn't encrypted, treating it as missing until it is pushed again");
+            return Ok(None);
+        }
+
+        let header = buffer.split_to(HEADER_LEN);
+        let prefix: [u8; PREFIX_LEN] = header[MAGIC.len()..].try_into().unwrap();
+
+        Ok(Some(Blob {
+            length: blob.length.and_then(plaintext_length),
+            modified: blob.modified,
+            stream: unseal(self.cipher(), prefix, buffer, blob.stream),
+        }))
+    }
+}
+
+#[async_trait]
+impl Storage for Encrypted {
+    async fn hash(&self, volt_id: &str) -> io::Result<Option<String>> { self.inner.hash(volt_id).await }
+
+    async fn put(&self, volt_id: &str, hash: &str, algorithm: Option<&str>, body: ByteStream) -> io::Result<()> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        SystemRandom::new().fill(&mut prefix).map_err(|_| io::Error::other("no randomness available for the nonce"))?;
+
+        let header = Bytes::from([MAGIC.as_slice(), &prefix].concat());
+        let sealed = stream::once(async move { Ok(header) }).chain(seal(self.cipher(), prefix, body));
+
+        self.inner.put(volt_id, hash, algorithm, sealed.boxed()).await
+    }
+
+    async fn get(&self, volt_id: &str) -> io::Result<Option<Blob>> { self.open(volt_id, self.inner.get(volt_id).await?).await }
+
+    async fn get_version(&self, volt_id: &str, hash: &str) -> io::Result<Option<Blob>> { self.open(volt_id, self.inner.get_version(volt_id, hash).await?).await }
+
+    async fn algorithm(&self, volt_id: &str) -> io::Result<Option<String>> { self.inner.algorithm(volt_id).await }
+
+    async fn size(&self, volt_id: &str) -> io::Result<Option<u64>> { self.inner.size(volt_id).await }
+
+    async fn totals(&self) -> io::Result<Totals> { self.inner.totals().await }
+
+    async fn ids(&self) -> io::Result<Vec<String>> { self.inner.ids().await }
+
+    async fn remove(&self, volt_id: &str) -> io::Result<bool> { self.inner.remove(volt_id).await }
+}
+
+struct Segments {
+    body: ByteStream,
+    cipher: LessSafeKey,
+    prefix: [u8; PREFIX_LEN],
+    buffer: BytesMut,
+    counter: u32,
+    done: bool,
+}
+
+impl Segments {
+    fn nonce(&mut self, last: bool) -> io::Result<Nonce> {
+        let mut nonce = [0u8; 12];
+        nonce[..PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[PREFIX_LEN..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+
+        self.counter = self.counter.checked_add(1).ok_or_else(|| io::Error::other("archive is too large to encrypt"))?;
+        self.done = last;
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Buffers one segment ahead, so the final segment, even an empty one, is the one sealed as last.
+fn seal(cipher: LessSafeKey, prefix: [u8; PREFIX_LEN], body: ByteStream) -> ByteStream {
+    let segments = Segments {
+        body,
+        cipher,
+        prefix,
+        buffer: BytesMut::new(),
+        counter: 0,
+        done: false,
+    };
+
+    stream::try_unfold(segments, |mut segments| async move {
+        loop {
+            if segments.done {
+                return Ok(None);
+            }
+
+            let last = match segments.buffer.len() > SEGMENT {
+                true => false,
+                false => match segments.body.next().await {
+                    Some(chunk) => {
+                        segments.buffer.extend_from_slice(&chunk?);
+                        continue;
+                    }
+                    None => true,
+                },
+            };
+
+            let mut segment = match last {
+                true => segments.buffer.split(),
+                false => segments.buffer.split_to(SEGMENT),
+            };
+
+            let nonce = segments.nonce(last)?;
+            segments
+                .cipher
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut segment)
+                .map_err(|_| io::Error::other("failed to encrypt archive"))?;
+
+            return Ok(Some((segment.freeze(), segments)));
+        }
+    })
+    .boxed()
+}
+
+fn unseal(cipher: LessSafeKey, prefix: [u8; PREFIX_LEN], buffer: BytesMut, body: ByteStream) -> ByteStream {
+    let segments = Segments {
+        body,
+        cipher,
+        prefix,
+        buffer,
+        counter: 0,
+        done: false,
+    };
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "archive failed to decrypt, it was modified on disk or sealed with another key");
+
+    stream::try_unfold(segments, move |mut segments| async move {
+        loop {
+            if segments.done {
+                return Ok(None);
+            }
+
+            let last = match segments.buffer.len() > SEGMENT + TAG_LEN {
+                true => false,
+                false => match segments.body.next().await {
+                    Some(chunk) => {
+                        segments.buffer.extend_from_slice(&chunk?);
+                        continue;
+                    }
+                    None => true,
+                },
+            };
+
+            let mut segment = match last {
+                true => segments.buffer.split(),
+                false => segments.buffer.split_to(SEGMENT + TAG_LEN),
+            };
+
+            let nonce = segments.nonce(last)?;
+            let length = segments.cipher.open_in_place(nonce, Aad::empty(), &mut segment).map_err(|_| corrupt())?.len();
+            segment.truncate(length);
+
+            return Ok(Some((segment.freeze(), segments)));
+        }
+    })
+    .boxed()
+}
+
+/// Plaintext size of a sealed blob: every segment but the last is full, and each carries one tag.
+fn plaintext_length(stored: u64) -> Option<u64> {
+    let sealed = stored.checked_sub(HEADER_LEN as u64)?.checked_sub(TAG_LEN as u64)?;
+    let full = sealed / (SEGMENT + TAG_LEN) as u64;
+
+    Some(sealed - full * TAG_LEN as u64)
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::broadcast;
+
+/// How many request lines are kept in memory for `GET /logs`.
+const CAPACITY: usize = 1000;
+
+#[derive(Clone, Serialize)]
+pub struct LogLine {
+    pub timestamp: u64,
+    pub method: String,
+    pub uri: String,
+    pub status: u16,
+    pub duration_ms: u128,
+    /// The client's `User-Agent`, or `volt/<version>` from `X-Volt-Client-Version` when it sends no user agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+}
+
+/// Ring buffer of recent requests, with a broadcast channel for followers.
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<LogLine>>,
+    live: broadcast::Sender<LogLine>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+            live: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    pub fn push(&self, line: LogLine) {
+        let mut lines = self.lines.lock().unwrap();
+
+        if lines.len() == CAPACITY {
+            lines.pop_front();
+        }
+
+        lines.push_back(line.clone());
+        let _ = self.live.send(line);
+    }
+
+    /// Returns the last `count` lines, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<LogLine> {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+    }
+
+    /// Snapshot of the tail plus a receiver for everything logged after it, taken under one lock so nothing is missed.
+    pub fn follow(&self, count: usize) -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+        let lines = self.lines.lock().unwrap();
+        let receiver = self.live.subscribe();
+        let tail = lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect();
+
+        (tail, receiver)
+    }
+}
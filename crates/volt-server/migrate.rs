@@ -0,0 +1,170 @@
+use crate::{
+    ServerConfig,
+    encryption::{self, Encrypted},
+    storage::{self, Backend, ByteStream, Storage},
+};
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::{
+    hash::{DefaultHasher, Hasher},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, info};
+
+/// The storage half of a server config, naming where `migrate` copies entries to.
+#[derive(Deserialize)]
+struct Target {
+    cache_dir: PathBuf,
+    #[serde(default)]
+    backend: Backend,
+    keep_versions: Option<usize>,
+    #[serde(default)]
+    hash_index: bool,
+    encryption_key: Option<String>,
+    encryption_key_file: Option<PathBuf>,
+}
+
+/// Copies the latest archive of every `volt_id` from the configured storage into the one `target` describes, checking each copy reads back intact.
+/// Entries the target already holds under the same hash are skipped, so an interrupted run can just be started again.
+pub async fn run(config: &ServerConfig, target: PathBuf) -> Result<ExitCode> {
+    let content = tokio::fs::read_to_string(&target).await.with_context(|| format!("Failed to read {target:?}"))?;
+    let target: Target = toml::from_str(&content).with_context(|| format!("Failed to parse {target:?}"))?;
+
+    // opening a layout can fold the other layout's hash files into its index, so the two never share a directory
+    if std::fs::canonicalize(&target.cache_dir).ok() == std::fs::canonicalize(&config.cache_dir).ok() || target.cache_dir == config.cache_dir {
+        bail!("the target cache_dir must differ from the current one, migrate into a new directory and then point cache_dir at it");
+    }
+
+    let source = storage::open(config.backend, config.cache_dir.clone(), config.keep_versions, config.hash_index).context("Failed to open the current storage")?;
+    let destination = storage::open(target.backend, target.cache_dir.clone(), target.keep_versions, target.hash_index).context("Failed to open the target storage")?;
+
+    // entries are opened with the current key and sealed with the target's, so migrating also turns encryption on, off or rotates its key
+    let source = Encrypted::wrap(source, encryption::load_key(config.encryption_key.as_deref(), config.encryption_key_file.as_deref())?);
+    let destination = Encrypted::wrap(destination, encryption::load_key(target.encryption_key.as_deref(), target.encryption_key_file.as_deref())?);
+
+    let mut ids = source.ids().await?;
+    ids.sort();
+
+    let (mut copied, mut skipped, mut failed) = (0, 0, 0);
+
+    for (n, volt_id) in ids.iter().enumerate() {
+        let progress = format!("[{}/{}]", n + 1, ids.len());
+
+        match copy(&*source, &*destination, volt_id).await {
+            Ok(Some(bytes)) => {
+                copied += 1;
+                info!("{progress} {volt_id}: copied {bytes} bytes");
+            }
+            Ok(None) => {
+                skipped += 1;
+                info!("{progress} {volt_id}: already migrated");
+            }
+            Err(e) => {
+                failed += 1;
+                error!("{progress} {volt_id}: {e:#}");
+            }
+        }
+    }
+
+    // tags and namespace claims live beside the archives, so they have to move along with them
+    for dir in ["tags", "namespaces"] {
+        if let Err(e) = copy_dir(&config.cache_dir.join(dir), &target.cache_dir.join(dir)) {
+            failed += 1;
+            error!("Failed to copy {dir}: {e:#}");
+        }
+    }
+
+    info!("Migration finished: {copied} copied, {skipped} skipped, {failed} failed into {:?}", target.cache_dir);
+
+    Ok(match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    })
+}
+
+/// Copies one entry, returning its size, or `None` when the target already has it.
+async fn copy(source: &dyn Storage, destination: &dyn Storage, volt_id: &str) -> Result<Option<u64>> {
+    let Some(hash) = source.hash(volt_id).await? else {
+        return Ok(None);
+    };
+    let algorithm = source.algorithm(volt_id).await?;
+
+    // a target records the hash only after the archive is fully written, so a match means a finished copy
+    if destination.hash(volt_id).await?.as_ref() == Some(&hash) && destination.algorithm(volt_id).await? == algorithm {
+        return Ok(None);
+    }
+
+    let blob = source.get(volt_id).await?.context("hash is recorded but the archive is missing")?;
+    let (stream, written) = checksummed(blob.stream);
+
+    destination.put(volt_id, &hash, algorithm.as_deref(), stream).await.context("Failed to write to the target")?;
+
+    let readback = async {
+        let stored = destination.get_version(volt_id, &hash).await?.context("the copy can't be read back from the target")?;
+        let (mut readback, read) = checksummed(stored.stream);
+        while let Some(chunk) = readback.next().await {
+            chunk?;
+        }
+
+        let (written, read) = (*written.lock().unwrap(), *read.lock().unwrap());
+        if written != read {
+            bail!("the copy doesn't match the original, re-run migrate to retry it");
+        }
+
+        Ok(written.1)
+    };
+
+    match readback.await {
+        Ok(size) => Ok(Some(size)),
+        Err(e) => {
+            // the target already records the hash, so a bad copy left in place would be skipped as finished on the next run
+            let _ = destination.remove(volt_id).await;
+            Err(e)
+        }
+    }
+}
+
+type Checksum = Arc<Mutex<(u64, u64)>>;
+
+/// Passes a stream through while tallying a checksum and length of its bytes, available once it's drained.
+fn checksummed(stream: ByteStream) -> (ByteStream, Checksum) {
+    let checksum: Checksum = Arc::default();
+    let hasher = Arc::new(Mutex::new(DefaultHasher::new()));
+    let tally = (checksum.clone(), hasher);
+
+    let stream = stream.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            let mut hasher = tally.1.lock().unwrap();
+            hasher.write(chunk);
+
+            let mut checksum = tally.0.lock().unwrap();
+            *checksum = (hasher.finish(), checksum.1 + chunk.len() as u64);
+        }
+    });
+
+    (stream.boxed(), checksum)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(from) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    std::fs::create_dir_all(to)?;
+
+    for entry in entries {
+        let entry = entry?;
+
+        match entry.file_type()?.is_dir() {
+            true => copy_dir(&entry.path(), &to.join(entry.file_name()))?,
+            false => std::fs::copy(entry.path(), to.join(entry.file_name())).map(drop)?,
+        }
+    }
+
+    Ok(())
+}
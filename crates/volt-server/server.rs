@@ -1,29 +1,82 @@
+mod access;
+mod encryption;
+mod logs;
+mod migrate;
+mod storage;
+mod verify;
+
 use axum::{
     Router,
-    body::Body,
-    extract::{Path, State},
+    body::{Body, HttpBody},
+    extract::{Extension, Path, Query, State},
     http::{HeaderMap, Request, StatusCode},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    response::{
+        IntoResponse, Json, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{delete, get, post, put},
 };
 
 use tokio::{
-    fs::{self, File, create_dir_all},
-    io::{AsyncWriteExt, BufWriter},
+    io::AsyncWriteExt,
     net::TcpListener,
+    sync::{RwLock, Semaphore, broadcast, mpsc},
 };
 
 use anyhow::{Context, Result};
-use futures::StreamExt;
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf, process::ExitCode, sync::Arc};
-use tokio_util::io::ReaderStream;
+use bytes::Bytes;
+use flate2::{
+    Compression,
+    read::{GzEncoder, MultiGzDecoder},
+};
+use futures::{StreamExt, TryStreamExt, stream};
+use logs::{LogBuffer, LogLine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use access::{Access, AccessLog};
+use encryption::Encrypted;
+use storage::{Backend, ByteStream, Storage};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
+use tower_http::compression::CompressionLayer;
 use tracing::{error, info, warn};
 
+/// Bumped whenever routes or headers change in a way older clients or servers can't handle.
+const PROTOCOL_VERSION: u32 = 1;
+
+const CONFIG_PATH: &str = "config.toml";
+
+/// Largest part a chunked push may send, what clients split archives into.
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+/// Parts a chunked push may have when `max_push_bytes` is unset, 80 GiB of them.
+const MAX_PARTS: u64 = 10_000;
+
+/// Seconds clients are told to wait before trying again while the server is in maintenance, unless it says otherwise.
+const MAINTENANCE_RETRY_AFTER: u64 = 300;
+
+/// Ids `volt server bench` pushes under, the client's `BENCH_ID_PREFIX`. Tokens without a namespace may delete these without admin.
+const BENCH_ID_PREFIX: &str = "00000000-0000-4000-8000-";
+
 #[derive(Clone)]
 struct AppState {
     config: ServerConfig,
+    storage: Arc<dyn Storage>,
+    limiter: Option<Arc<Semaphore>>,
+    tokens: Arc<RwLock<Vec<Token>>>,
+    logs: Arc<LogBuffer>,
+    access: Option<Arc<AccessLog>>,
+    maintenance: Arc<RwLock<Maintenance>>,
+    http: reqwest::Client,
 }
 
 #[derive(Clone, Deserialize)]
@@ -31,34 +84,382 @@ struct ServerConfig {
     auth_token: String,
     cache_dir: PathBuf,
     address: String,
+    max_concurrent_requests: Option<usize>,
+    min_push_files: Option<u64>,
+    min_push_bytes: Option<u64>,
+    /// Rejects pushes bigger than this, checked part by part for chunked pushes.
+    max_push_bytes: Option<u64>,
+    keep_versions: Option<usize>,
+
+    #[serde(default)]
+    hash_index: bool,
+
+    /// Lets clients reuse a `/check` answer for this many seconds. Answers are private, since they sit behind the token.
+    check_max_age: Option<u64>,
+
+    #[serde(default = "default_auth_header")]
+    auth_header: String,
+    #[serde(default = "default_auth_scheme")]
+    auth_scheme: String,
+
+    #[serde(default)]
+    backend: Backend,
+
+    #[serde(default)]
+    tokens: Vec<Token>,
+
+    upstream: Option<Upstream>,
+
+    #[serde(default)]
+    quotas: HashMap<String, u64>,
+
+    #[serde(default)]
+    id_format: IdFormat,
+
+    /// Serves an unauthenticated `/healthz` for load balancers, the token-checked `/health/{volt_id}` stays as it is.
+    #[serde(default)]
+    public_health: bool,
+
+    /// Hex key sealing archives at rest, or a file holding it. This is server-side encryption: it protects the disk and its backups,
+    /// but the server holds the key and sees every archive in the clear, so it isn't end-to-end.
+    encryption_key: Option<String>,
+    encryption_key_file: Option<PathBuf>,
+
+    /// Makes archives write-once: pushing a hash that's already stored only succeeds with byte-identical content.
+    /// Clients re-pushing need `[archive] reproducible`, and can't use `[security] encryption_key`, whose random nonces never repeat.
+    #[serde(default)]
+    immutable: bool,
+
+    /// Appends one combined-log-format line per request here, apart from the tracing output.
+    access_log: Option<PathBuf>,
+    /// Rotates the access log to `<access_log>.1` once it reaches this size.
+    access_log_max_bytes: Option<u64>,
+    /// Rotated access logs to keep, `<access_log>.1` being the newest.
+    #[serde(default = "default_access_log_keep")]
+    access_log_keep: usize,
+
+    /// The maintenance mode to start in, `/admin/maintenance` changes it at runtime without touching the config.
+    #[serde(default)]
+    maintenance: Maintenance,
 }
 
-async fn auth_middleware(State(state): State<Arc<AppState>>, request: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = request
-        .headers()
-        .get("Authorization")
-        .and_then(|value| value.to_str().ok())
-        .and_then(|value| value.strip_prefix("Bearer "))
-        .ok_or_else(|| {
-            warn!("Missing or malformed Authorization header");
-            StatusCode::UNAUTHORIZED
-        })?;
+/// Which requests the server turns away with a 503 while an upgrade or disk work is underway.
+#[derive(Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MaintenanceMode {
+    #[default]
+    Off,
+    /// Refuse pushes, tags and warming, keep serving pulls.
+    Push,
+    /// Refuse pulls and checks as well.
+    All,
+}
 
-    if auth_header != state.config.auth_token {
-        warn!("Invalid authentication token provided");
-        return Err(StatusCode::FORBIDDEN);
+impl MaintenanceMode {
+    fn name(self) -> &'static str {
+        match self {
+            MaintenanceMode::Off => "off",
+            MaintenanceMode::Push => "push",
+            MaintenanceMode::All => "all",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+struct Maintenance {
+    #[serde(default)]
+    mode: MaintenanceMode,
+    /// Sent as the body of every refused request.
+    message: Option<String>,
+    /// Sent as `Retry-After`, in seconds.
+    retry_after: Option<u64>,
+}
+
+/// What a `volt_id` must look like. Anything but `uuid` lets clients bring their own cache keys.
+#[derive(Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum IdFormat {
+    #[default]
+    Uuid,
+    Hex64,
+    Any,
+}
+
+impl IdFormat {
+    fn accepts(self, volt_id: &str) -> bool {
+        match self {
+            IdFormat::Uuid => uuid::Uuid::parse_str(volt_id).is_ok(),
+            IdFormat::Hex64 => volt_id.len() == 64 && volt_id.chars().all(|c| c.is_ascii_hexdigit()),
+            IdFormat::Any => path_safe(volt_id),
+        }
     }
+}
+
+/// Another volt server this one can prefetch archives from.
+#[derive(Clone, Deserialize)]
+struct Upstream {
+    address: String,
+    auth_token: String,
+}
+
+fn default_auth_header() -> String { "Authorization".to_string() }
+
+fn default_auth_scheme() -> String { "Bearer".to_string() }
+
+fn default_access_log_keep() -> usize { 5 }
+
+#[derive(Clone, Deserialize)]
+struct Token {
+    name: String,
+    token: String,
+
+    #[serde(default)]
+    admin: bool,
+
+    /// Pushes made with this token count against `quotas[namespace]`.
+    namespace: Option<String>,
+}
+
+/// Who `/whoami` found the presented token to be, `name` is unset for the config's `auth_token`.
+#[derive(Serialize)]
+struct Identity {
+    name: Option<String>,
+    admin: bool,
+    namespace: Option<String>,
+}
+
+/// The namespace of the token a request was authenticated with, if any.
+#[derive(Clone)]
+struct Namespace(Option<String>);
+
+#[derive(Serialize)]
+struct NamespaceUsage {
+    used: u64,
+    quota: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    #[serde(flatten)]
+    cache: storage::Totals,
+    disk: Option<DiskSpace>,
+    limits: Limits,
+    namespaces: BTreeMap<String, NamespaceUsage>,
+}
+
+/// Space on the filesystem holding `cache_dir`.
+#[derive(Serialize)]
+struct DiskSpace {
+    free: u64,
+    total: u64,
+}
+
+/// The configured limits that decide when pushes start failing or old archives go away.
+#[derive(Serialize)]
+struct Limits {
+    keep_versions: Option<usize>,
+    min_push_files: Option<u64>,
+    min_push_bytes: Option<u64>,
+    max_push_bytes: Option<u64>,
+    max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ArchiveFormat {
+    #[default]
+    Zstd,
+    Targz,
+}
+
+#[derive(Deserialize)]
+struct PullQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+    hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    /// Digest the stored bytes are expected to have, e.g. from an earlier verify.
+    sha256: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WarmRequest {
+    ids: Vec<WarmTarget>,
+}
+
+#[derive(Deserialize)]
+struct WarmTarget {
+    volt_id: String,
+    hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagRequest {
+    volt_id: String,
+}
+
+/// Which entries `/invalidate` deletes: `pattern` is a glob over volt_ids, or over tag names with `tags` set,
+/// and `namespace` narrows it to the ids that namespace's tokens pushed.
+#[derive(Deserialize)]
+struct InvalidateRequest {
+    pattern: String,
+    #[serde(default)]
+    tags: bool,
+    namespace: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Invalidated {
+    removed: usize,
+    ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LogsQuery {
+    #[serde(default = "default_tail")]
+    tail: usize,
+    #[serde(default)]
+    follow: bool,
+}
+
+fn default_tail() -> usize { 100 }
 
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    protocol: u32,
+}
+
+async fn auth_middleware(State(state): State<Arc<AppState>>, mut request: Request<Body>, next: Next) -> Result<Response, StatusCode> {
+    let namespace = authorize(&state, request.headers(), false).await?;
+    request.extensions_mut().insert(namespace);
+    Ok(next.run(request).await)
+}
+
+async fn admin_middleware(State(state): State<Arc<AppState>>, request: Request<Body>, next: Next) -> Result<Response, StatusCode> {
+    authorize(&state, request.headers(), true).await?;
     Ok(next.run(request).await)
 }
 
-async fn logging_middleware(request: Request<Body>, next: Next) -> Response {
+/// Matches the presented token against `auth_token` and the named tokens, `auth_token` always counts as an admin.
+async fn authorize(state: &AppState, headers: &HeaderMap, admin: bool) -> Result<Namespace, StatusCode> {
+    let auth_header = presented_token(&state.config, headers).ok_or_else(|| {
+        warn!("Missing or malformed {} header", state.config.auth_header);
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    let (is_admin, namespace) = if auth_header == state.config.auth_token {
+        (true, None)
+    } else {
+        match state.tokens.read().await.iter().find(|token| token.token == auth_header) {
+            Some(token) => (token.admin, token.namespace.clone()),
+            None => {
+                warn!("Invalid authentication token provided");
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+    };
+
+    if admin && !is_admin {
+        warn!("Admin route requested with a non-admin token");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(Namespace(namespace))
+}
+
+/// Reads the token from the configured `auth_header`/`auth_scheme`, with `Authorization: Bearer` always accepted too.
+fn presented_token<'a>(config: &ServerConfig, headers: &'a HeaderMap) -> Option<&'a str> {
+    let read = |name: &str, scheme: &str| {
+        let value = headers.get(name)?.to_str().ok()?;
+        match scheme {
+            "" => Some(value),
+            scheme => value.strip_prefix(scheme)?.strip_prefix(' '),
+        }
+    };
+
+    read(&config.auth_header, &config.auth_scheme).or_else(|| read("Authorization", "Bearer"))
+}
+
+/// Turns away what the current maintenance mode covers with a 503, marked with `X-Volt-Maintenance` so clients can tell it from a saturated server.
+async fn maintenance_middleware(State(state): State<Arc<AppState>>, request: Request<Body>, next: Next) -> Response {
+    let maintenance = state.maintenance.read().await.clone();
+    let path = request.uri().path();
+
+    let writes = path.starts_with("/push/") || path.starts_with("/tag/") || path.starts_with("/cache/") || path == "/warm";
+    let reads = path.starts_with("/pull/") || path.starts_with("/check/") || path.starts_with("/verify/");
+
+    let refused = match maintenance.mode {
+        MaintenanceMode::Off => false,
+        MaintenanceMode::Push => writes,
+        MaintenanceMode::All => writes || reads,
+    };
+
+    if !refused {
+        return next.run(request).await;
+    }
+
+    let retry_after = maintenance.retry_after.unwrap_or(MAINTENANCE_RETRY_AFTER).to_string();
+    let message = maintenance.message.unwrap_or_else(|| "the server is down for maintenance".to_string());
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [("Retry-After", retry_after), ("X-Volt-Maintenance", maintenance.mode.name().to_string())],
+        message,
+    )
+        .into_response()
+}
+
+async fn concurrency_middleware(State(state): State<Arc<AppState>>, request: Request<Body>, next: Next) -> Response {
+    let Some(limiter) = &state.limiter else {
+        return next.run(request).await;
+    };
+
+    let Ok(permit) = limiter.clone().try_acquire_owned() else {
+        warn!("Concurrency limit reached, rejecting request");
+        return (StatusCode::SERVICE_UNAVAILABLE, [("Retry-After", "1")]).into_response();
+    };
+
+    // hold the permit until the body is fully streamed, not just until the handler returns
+    let (parts, body) = next.run(request).await.into_parts();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+async fn logging_middleware(State(state): State<Arc<AppState>>, request: Request<Body>, next: Next) -> Response {
     let method = request.method().to_string();
     let uri = request.uri().to_string();
+    let version = format!("{:?}", request.version());
     let start = std::time::Instant::now();
 
-    info!(%method, %uri, "Request started");
-    let response = next.run(request).await;
+    // the name of the presented token, never the token itself
+    let token = match (&state.access, presented_token(&state.config, request.headers())) {
+        (None, _) | (_, None) => "-".to_string(),
+        (Some(_), Some(presented)) if presented == state.config.auth_token => "auth_token".to_string(),
+        (Some(_), Some(presented)) => state
+            .tokens
+            .read()
+            .await
+            .iter()
+            .find(|token| token.token == presented)
+            .map_or("-".to_string(), |token| token.name.clone()),
+    };
+
+    let client = {
+        let header = |name: &str| request.headers().get(name).and_then(|h| h.to_str().ok()).map(ToString::to_string);
+        header("User-Agent").or_else(|| header("X-Volt-Client-Version").map(|version| format!("volt/{version}")))
+    };
+    let client_field = client.as_deref().unwrap_or("unknown");
+
+    info!(%method, %uri, client = client_field, "Request started");
+    let mut response = next.run(request).await;
     let status = response.status().as_u16();
     let duration = start.elapsed();
 
@@ -67,29 +468,170 @@ async fn logging_middleware(request: Request<Body>, next: Next) -> Response {
         %uri,
         %status,
         duration_ms = duration.as_millis(),
+        client = client_field,
         "Request completed"
     );
 
+    if let Some(access) = &state.access {
+        let mut logged = Logged {
+            access: access.clone(),
+            token,
+            method: method.clone(),
+            uri: uri.clone(),
+            version,
+            status,
+            client: client.clone(),
+            start,
+            bytes: 0,
+        };
+
+        let length = response.headers().get("Content-Length").and_then(|h| h.to_str().ok()).and_then(|h| h.parse().ok());
+
+        // a streamed pull has no length up front, so its bytes are counted on the way out and the line written once it ends
+        match length.or(response.body().size_hint().exact()) {
+            Some(bytes) => logged.bytes = bytes,
+            None => {
+                response = response.map(|body| {
+                    Body::from_stream(body.into_data_stream().inspect(move |chunk| {
+                        if let Ok(chunk) = chunk {
+                            logged.sent(chunk.len());
+                        }
+                    }))
+                })
+            }
+        }
+    }
+
+    state.logs.push(LogLine {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+        method,
+        uri,
+        status,
+        duration_ms: duration.as_millis(),
+        client,
+    });
+
     response
 }
 
+/// One request on its way to the access log, written when dropped, which for a streamed body is once the stream is done with.
+struct Logged {
+    access: Arc<AccessLog>,
+    token: String,
+    method: String,
+    uri: String,
+    version: String,
+    status: u16,
+    client: Option<String>,
+    start: std::time::Instant,
+    bytes: u64,
+}
+
+impl Logged {
+    fn sent(&mut self, bytes: usize) { self.bytes += bytes as u64; }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        self.access.record(&Access {
+            token: &self.token,
+            method: &self.method,
+            uri: &self.uri,
+            version: &self.version,
+            status: self.status,
+            bytes: Some(self.bytes),
+            duration_ms: self.start.elapsed().as_millis(),
+            client: self.client.as_deref(),
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
     tracing_subscriber::fmt().with_max_level(tracing::Level::INFO).with_target(false).init();
 
-    let config: ServerConfig = toml::from_str(&tokio::fs::read_to_string("config.toml").await?)?;
-    let state = Arc::new(AppState { config: config.clone() });
+    let config: ServerConfig = toml::from_str(&tokio::fs::read_to_string(CONFIG_PATH).await?)?;
+
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        return match (command.as_str(), args.next()) {
+            ("migrate", Some(target)) => migrate::run(&config, PathBuf::from(target)).await,
+            _ => {
+                eprintln!("usage: volt-server [migrate <target-config.toml>]");
+                Ok(ExitCode::FAILURE)
+            }
+        };
+    }
+    // parts of chunked pushes interrupted by the last shutdown will never be completed
+    if let Err(e) = tokio::fs::remove_dir_all(config.cache_dir.join("uploads")).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to clear staged uploads: {}", e);
+    }
+
+    let limiter = config.max_concurrent_requests.map(|limit| Arc::new(Semaphore::new(limit)));
+    let storage = storage::open(config.backend, config.cache_dir.clone(), config.keep_versions, config.hash_index).context("Failed to open cache storage")?;
+    let storage = Encrypted::wrap(storage, encryption::load_key(config.encryption_key.as_deref(), config.encryption_key_file.as_deref())?);
+    let state = Arc::new(AppState {
+        config: config.clone(),
+        storage,
+        limiter,
+        tokens: Arc::new(RwLock::new(config.tokens.clone())),
+        logs: Arc::new(LogBuffer::new()),
+        access: match &config.access_log {
+            Some(path) => Some(Arc::new(AccessLog::open(path, config.access_log_max_bytes, config.access_log_keep)?)),
+            None => None,
+        },
+        maintenance: Arc::new(RwLock::new(config.maintenance.clone())),
+        http: reqwest::Client::new(),
+    });
     let addr = config.address.parse::<SocketAddr>().with_context(|| format!("Failed to parse address: {}", config.address))?;
 
     print_startup_message(&addr, &config);
 
+    // JSON responses are worth compressing, archives on the cache routes already are. `/logs?follow` streams events,
+    // which the default predicate never compresses.
+    let json = Router::new()
+        .route("/verify/{volt_id}", get(verify_entry))
+        .route("/whoami", get(whoami))
+        .route("/logs", get(logs))
+        .route("/usage", get(usage))
+        .route("/tags", get(list_tags))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .route("/version", get(version))
+        .layer(CompressionLayer::new());
+
+    let admin = Router::new()
+        .route("/admin/tokens", post(add_token))
+        .route("/admin/tokens/{name}", delete(revoke_token))
+        .route("/admin/maintenance", get(get_maintenance).put(set_maintenance))
+        .route("/invalidate", post(invalidate))
+        .route_layer(middleware::from_fn_with_state(state.clone(), admin_middleware))
+        .layer(CompressionLayer::new());
+
+    // probes skip the concurrency limit and request log, a saturated server should still answer them
+    let public = match config.public_health {
+        true => Router::new().route("/healthz", get(healthz)),
+        false => Router::new(),
+    };
+
     let app = Router::new()
         .route("/health/{volt_id}", get(health))
         .route("/push/{volt_id}", post(push))
+        .route("/push/{volt_id}/parts/{index}", put(push_part))
+        .route("/push/{volt_id}/complete", post(push_complete))
         .route("/pull/{volt_id}", get(pull))
+        .route("/cache/{volt_id}", delete(delete_entry))
         .route("/check/{volt_id}", get(check_hash))
-        .layer(middleware::from_fn(logging_middleware))
-        .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .route("/warm", post(warm))
+        .route("/tag/{name}", put(set_tag))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .merge(json)
+        .merge(admin)
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), concurrency_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), logging_middleware))
+        .merge(public)
         .with_state(state);
 
     let listener = TcpListener::bind(addr).await?;
@@ -112,36 +654,119 @@ fn print_startup_message(addr: &SocketAddr, config: &ServerConfig) {
 {}
 {}
 {}
+{}
+{}
+{}
+{}
+{}
 ║                                                              ║
 ╚══════════════════════════════════════════════════════════════╝
         "#,
         pad_line(&format!("listening on:     {}", addr)),
         pad_line(&format!("cache directory:  {:?}", config.cache_dir)),
         pad_line("authentication:   always on"),
+        pad_line(match config.encryption_key.is_some() || config.encryption_key_file.is_some() {
+            true => "encryption:       at rest, server-held key",
+            false => "encryption:       off",
+        }),
+        pad_line(match config.immutable {
+            true => "archives:         immutable, identical re-pushes only",
+            false => "archives:         overwritable",
+        }),
+        pad_line(&match config.max_concurrent_requests {
+            Some(limit) => format!("max requests:     {limit}"),
+            None => "max requests:     unlimited".to_string(),
+        }),
+        pad_line(&match &config.access_log {
+            Some(path) => format!("access log:       {path:?}"),
+            None => "access log:       off".to_string(),
+        }),
+        pad_line(&format!("maintenance:      {}", config.maintenance.mode.name())),
     );
 }
 
 async fn health(Path(volt_id): Path<String>) -> String { volt_id }
 
-async fn check_hash(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
-    uuid::Uuid::parse_str(&volt_id).map_err(|e| {
-        warn!("Invalid UUID format: {}", e);
-        StatusCode::BAD_REQUEST
+async fn healthz() -> &'static str { "ok" }
+
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        protocol: PROTOCOL_VERSION,
+    })
+}
+
+/// Reads a stored entry back through the decoder without sending it anywhere, answering 422 with the details when it doesn't hold up.
+async fn verify_entry(Path(volt_id): Path<String>, Query(query): Query<VerifyQuery>, State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, StatusCode> {
+    let volt_id = resolve_id(&state, volt_id).await?;
+    let hash = read_hash(&state, &volt_id).await?;
+
+    let blob = state
+        .storage
+        .get(&volt_id)
+        .await
+        .map_err(|e| {
+            error!("File open error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let reader = SyncIoBridge::new(StreamReader::new(blob.stream));
+    let mut verified = tokio::task::spawn_blocking(move || verify::verify(reader, hash)).await.map_err(|e| {
+        error!("Verify task failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    if let Some(expected) = query.sha256
+        && verified.ok
+        && !expected.eq_ignore_ascii_case(&verified.sha256)
+    {
+        verified.ok = false;
+        verified.detail = Some(format!("the stored bytes hash to {}, not the expected {expected}", verified.sha256));
+    }
+
+    match verified.ok {
+        true => info!("Verified {volt_id}: {} bytes, {:?} entries", verified.bytes, verified.entries),
+        false => warn!("Verify failed for {volt_id}: {}", verified.detail.as_deref().unwrap_or_default()),
+    }
+
+    let status = if verified.ok { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    Ok((status, Json(verified)))
+}
+
+async fn check_hash(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
+    let volt_id = resolve_id(&state, volt_id).await?;
+
     let client_hash = headers.get("X-Volt-Hash").and_then(|h| h.to_str().ok());
-    let server_hash_path = state.config.cache_dir.join(format!("{volt_id}.hash"));
-    let server_hash = tokio::fs::read_to_string(&server_hash_path).await.ok();
+    let server_hash = read_hash(&state, &volt_id).await?;
 
     info!("Hash check: client={client_hash:?} server={server_hash:?}");
 
     match (client_hash, server_hash) {
         (Some(client_hash), Some(server_hash)) => {
-            if client_hash == server_hash.trim() {
-                Ok(StatusCode::NOT_MODIFIED.into_response())
-            } else {
-                Ok(StatusCode::OK.into_response())
-            }
+            let algorithm = read_algorithm(&state, &volt_id).await?;
+            let etag = match &algorithm {
+                Some(algorithm) => format!("\"{algorithm}:{server_hash}\""),
+                None => format!("\"{server_hash}\""),
+            };
+
+            let revalidated = headers.get("If-None-Match").is_some_and(|tags| tags.as_bytes() == etag.as_bytes());
+            let same = revalidated || (client_hash == server_hash && algorithm.as_deref() == client_algorithm(&headers));
+            let status = if same { StatusCode::NOT_MODIFIED } else { StatusCode::OK };
+
+            let cache_control = match state.config.check_max_age {
+                Some(seconds) => format!("private, max-age={seconds}"),
+                None => "no-cache".to_string(),
+            };
+
+            let headers = [
+                ("X-Volt-Hash", server_hash),
+                ("ETag", etag),
+                ("Cache-Control", cache_control),
+                ("Vary", "Authorization, X-Volt-Hash, X-Volt-Hash-Algorithm, If-None-Match".to_string()),
+            ];
+
+            Ok((status, headers).into_response())
         }
         (_, None) => Ok(StatusCode::NOT_FOUND.into_response()),
         (None, _) => {
@@ -151,86 +776,1138 @@ async fn check_hash(Path(volt_id): Path<String>, State(state): State<Arc<AppStat
     }
 }
 
-async fn push(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, headers: HeaderMap, body: Body) -> Result<(), StatusCode> {
-    uuid::Uuid::parse_str(&volt_id).map_err(|e| {
-        warn!("Invalid UUID format: {}", e);
-        StatusCode::BAD_REQUEST
-    })?;
+async fn push(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, Extension(namespace): Extension<Namespace>, headers: HeaderMap, body: Body) -> Result<Response, StatusCode> {
+    if !state.config.id_format.accepts(&volt_id) {
+        warn!("Invalid volt_id: {volt_id}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-    create_dir_all(&state.config.cache_dir).await.map_err(|e| {
-        error!("Failed to create cache directory: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let incoming = headers.get("Content-Length").and_then(|h| h.to_str().ok()).and_then(|h| h.parse::<u64>().ok()).unwrap_or_default();
 
-    let file_path = state.config.cache_dir.join(format!("{}.zst", volt_id));
-    let file = File::create(&file_path).await.map_err(|e| {
-        error!("Failed to create file: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    // body errors are tagged so they surface as the client's fault rather than ours
+    let stream = body.into_data_stream().map(|chunk| chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e)));
 
-    let mut writer = BufWriter::new(file);
-    let mut stream = body.into_data_stream();
+    store(&state, &volt_id, &namespace, &headers, incoming, stream.boxed()).await
+}
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(|e| {
-            error!("Stream error: {}", e);
-            StatusCode::BAD_REQUEST
-        })?;
+/// Stages one part of a chunked push under `uploads/`, parts may arrive in any order and be sent again.
+async fn push_part(
+    Path((volt_id, index)): Path<(String, u32)>,
+    State(state): State<Arc<AppState>>,
+    Extension(namespace): Extension<Namespace>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, StatusCode> {
+    if !state.config.id_format.accepts(&volt_id) {
+        warn!("Invalid volt_id: {volt_id}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-        writer.write_all(&chunk).await.map_err(|e| {
-            error!("Write error: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    if index as u64 >= max_parts(&state.config) {
+        warn!("Rejected part {index} for {volt_id}: past the {} parts a push may have", max_parts(&state.config));
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
     }
 
-    writer.flush().await.map_err(|e| {
-        error!("Flush error: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let dir = upload_dir(&state, &volt_id, &headers)?;
+    let staging = dir.join(format!("{index}.partial"));
 
-    let hash = headers.get("X-Volt-Hash").and_then(|h| h.to_str().ok()).unwrap_or_default();
-    let hash_path = state.config.cache_dir.join(format!("{}.hash", volt_id));
+    // parts are held to the push limits as they arrive, so nothing unbounded piles up under uploads/ before complete checks them
+    let mut room = state.config.max_push_bytes.unwrap_or(u64::MAX);
+    if let Some(namespace) = &namespace.0
+        && let Some(quota) = state.config.quotas.get(namespace)
+    {
+        room = room.min(quota.saturating_sub(namespace_usage(&state, namespace, Some(&volt_id)).await?));
+    }
+    let room = room.saturating_sub(staged_size(&dir).await);
 
-    fs::write(hash_path, hash).await.map_err(|e| {
-        error!("Failed to write hash file: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let write = async {
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&staging).await?);
+        let mut stream = body.into_data_stream();
+        let mut written = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            written += chunk.len() as u64;
+
+            if written > PART_SIZE || written > room {
+                return Err(std::io::Error::new(std::io::ErrorKind::FileTooLarge, format!("part {index} is over the part size or the push limits")));
+            }
+
+            file.write_all(&chunk).await?;
+        }
+
+        file.flush().await?;
+        tokio::fs::rename(&staging, dir.join(index.to_string())).await
+    };
 
-    Ok(())
+    match write.await {
+        Ok(()) => Ok(StatusCode::OK),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staging).await;
+            match e.kind() {
+                std::io::ErrorKind::InvalidInput => {
+                    error!("Stream error: {}", e);
+                    Err(StatusCode::BAD_REQUEST)
+                }
+                std::io::ErrorKind::FileTooLarge => {
+                    warn!("Rejected push for {volt_id}: {e}");
+                    Err(StatusCode::PAYLOAD_TOO_LARGE)
+                }
+                _ => {
+                    error!("Failed to stage part {index} for {volt_id}: {}", e);
+                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+    }
 }
 
-async fn pull(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
-    uuid::Uuid::parse_str(&volt_id).map_err(|e| {
-        warn!("Invalid UUID format: {}", e);
-        StatusCode::BAD_REQUEST
-    })?;
+/// Joins the staged parts of a chunked push by index and stores them exactly as a single-request push would be.
+async fn push_complete(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, Extension(namespace): Extension<Namespace>, headers: HeaderMap) -> Result<Response, StatusCode> {
+    if !state.config.id_format.accepts(&volt_id) {
+        warn!("Invalid volt_id: {volt_id}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-    let client_hash = headers.get("X-Volt-Hash").and_then(|h| h.to_str().ok());
-    let server_hash_path = state.config.cache_dir.join(format!("{}.hash", volt_id));
-    let server_hash = tokio::fs::read_to_string(&server_hash_path).await.ok();
+    let dir = upload_dir(&state, &volt_id, &headers)?;
+    let Some(count) = headers.get("X-Volt-Parts").and_then(|h| h.to_str().ok()).and_then(|h| h.parse::<u32>().ok()) else {
+        warn!("Rejected push for {volt_id}: missing X-Volt-Parts");
+        return Err(StatusCode::BAD_REQUEST);
+    };
 
-    info!("{client_hash:?} to {server_hash:?}");
+    if count as u64 > max_parts(&state.config) {
+        warn!("Rejected push for {volt_id}: {count} parts is more than the {} allowed", max_parts(&state.config));
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
-    if let (Some(client_hash), Some(server_hash)) = (client_hash, server_hash) {
-        if client_hash == server_hash.trim() {
-            return Ok(StatusCode::NOT_MODIFIED.into_response());
+    let mut parts = Vec::new();
+    let mut incoming = 0;
+
+    for index in 0..count {
+        let path = dir.join(index.to_string());
+
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => incoming += metadata.len(),
+            Err(_) => {
+                warn!("Rejected push for {volt_id}: part {index} of {count} was never uploaded");
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, format!("part {index} of {count} was never uploaded")).into_response());
+            }
         }
+
+        parts.push(path);
     }
 
-    let file_path = state.config.cache_dir.join(format!("{}.zst", volt_id));
-    let file = File::open(&file_path).await.map_err(|e| {
-        if e.kind() == std::io::ErrorKind::NotFound {
-            warn!("File not found: {}", volt_id);
-            StatusCode::NOT_FOUND
-        } else {
-            error!("File open error: {}", e);
+    let stream = stream::iter(parts).then(tokio::fs::File::open).map_ok(ReaderStream::new).try_flatten();
+    let response = store(&state, &volt_id, &namespace, &headers, incoming, stream.boxed()).await;
+
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        warn!("Failed to clean up staged parts for {volt_id}: {}", e);
+    }
+
+    // only empty once no other push for this id is in flight
+    if let Some(parent) = dir.parent() {
+        let _ = tokio::fs::remove_dir(parent).await;
+    }
+
+    response
+}
+
+/// The most parts a chunked push can need to stay within `max_push_bytes`.
+fn max_parts(config: &ServerConfig) -> u64 { config.max_push_bytes.map_or(MAX_PARTS, |max| max.div_ceil(PART_SIZE)) }
+
+/// Bytes already staged for one chunked push, leaving out parts still being written.
+async fn staged_size(dir: &std::path::Path) -> u64 {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else { return 0 };
+    let mut size = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.path().extension().is_none()
+            && let Ok(metadata) = entry.metadata().await
+        {
+            size += metadata.len();
+        }
+    }
+
+    size
+}
+
+/// Where the parts of one chunked push are staged, keyed by the client's `X-Volt-Upload-Id`.
+fn upload_dir(state: &AppState, volt_id: &str, headers: &HeaderMap) -> Result<PathBuf, StatusCode> {
+    match headers.get("X-Volt-Upload-Id").and_then(|h| h.to_str().ok()) {
+        Some(upload_id) if path_safe(upload_id) => Ok(state.config.cache_dir.join("uploads").join(volt_id).join(upload_id)),
+        _ => {
+            warn!("Missing or invalid X-Volt-Upload-Id for {volt_id}");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// Checks and stores a push body, whether it came in one request or was joined from parts.
+async fn store(state: &AppState, volt_id: &str, namespace: &Namespace, headers: &HeaderMap, incoming: u64, stream: ByteStream) -> Result<Response, StatusCode> {
+    let hash = headers.get("X-Volt-Hash").and_then(|h| h.to_str().ok()).unwrap_or_default();
+    let algorithm = client_algorithm(headers);
+
+    // a retried push whose content is already committed doesn't need its body read again
+    if let Some(key) = headers.get("Idempotency-Key").and_then(|h| h.to_str().ok())
+        && read_hash(state, volt_id).await?.is_some_and(|stored| stored == key)
+        && same_algorithm(state, volt_id, headers).await?
+    {
+        info!("Push for {volt_id} already committed, skipping upload");
+        return Ok(StatusCode::OK.into_response());
+    }
+
+    if let Some(reason) = reject_push(&state.config, headers) {
+        warn!("Rejected push for {volt_id}: {reason}");
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, reason).into_response());
+    }
+
+    // Content-Length is only what the client claims and a chunked body sends none, so the limits are held to the bytes that arrive too
+    let mut stream = stream;
+
+    if let Some(max) = state.config.max_push_bytes {
+        if incoming > max {
+            warn!("Rejected push for {volt_id}: {incoming} bytes is over max_push_bytes");
+            return Ok((StatusCode::PAYLOAD_TOO_LARGE, format!("this {incoming} byte push is over the server's {max} byte limit")).into_response());
+        }
+
+        stream = limit_bytes(stream, max, std::io::ErrorKind::FileTooLarge, format!("this push is over the server's {max} byte limit")).boxed();
+    }
+
+    if let Some(namespace) = &namespace.0
+        && let Some(quota) = state.config.quotas.get(namespace)
+    {
+        let used = namespace_usage(state, namespace, Some(volt_id)).await?;
+
+        if used + incoming > *quota {
+            warn!("Rejected push for {volt_id}: namespace {namespace} would exceed its quota");
+            let reason = format!("namespace {namespace} is using {used} of its {quota} byte quota, this {incoming} byte push doesn't fit");
+            return Ok((StatusCode::INSUFFICIENT_STORAGE, reason).into_response());
+        }
+
+        let reason = format!("namespace {namespace} is using {used} of its {quota} byte quota, this push doesn't fit");
+        stream = limit_bytes(stream, quota.saturating_sub(used), std::io::ErrorKind::StorageFull, reason).boxed();
+    }
+
+    let Some(compression) = transfer_compression(headers) else {
+        warn!("Rejected push for {volt_id}: unsupported X-Volt-Compression {:?}", headers.get("X-Volt-Compression"));
+        return Ok((StatusCode::UNSUPPORTED_MEDIA_TYPE, "X-Volt-Compression must be one of zstd, gzip or identity").into_response());
+    };
+
+    let stream = match headers.get("X-Volt-Content-Sha256").and_then(|h| h.to_str().ok()) {
+        Some(expected) => verify_digest(stream, expected.to_ascii_lowercase()).boxed(),
+        None => stream.boxed(),
+    };
+
+    let stream = match compression {
+        TransferCompression::Zstd => stream,
+        compression => transcode(compression, stream).boxed(),
+    };
+
+    let stored = match state.config.immutable && !hash.is_empty() {
+        true => state.storage.get_version(volt_id, hash).await.map_err(|e| {
+            error!("Failed to read stored archive for {volt_id}: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+        false => None,
+    };
+
+    // an immutable archive is compared against instead of replaced, so a re-push never touches what's stored
+    let written = match stored {
+        Some(stored) => same_content(stored.stream, stream).await.map(Some),
+        None => state.storage.put(volt_id, hash, algorithm, stream).await.map(|()| None),
+    };
+
+    match written {
+        Ok(None) => {}
+        Ok(Some(true)) => info!("Identical re-push of {hash} for {volt_id}, keeping the stored archive"),
+        Ok(Some(false)) => {
+            warn!("Rejected push for {volt_id}: {hash} is already stored with different content");
+            let reason = format!("{hash} is already stored for {volt_id} with different content, this server only accepts identical re-pushes");
+            return Ok((StatusCode::CONFLICT, reason).into_response());
         }
-    })?;
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            warn!("Rejected push for {volt_id}: {e}");
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+            warn!("Rejected push for {volt_id}: {e}");
+            return Ok((StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+            warn!("Rejected push for {volt_id}: {e}");
+            return Ok((StatusCode::INSUFFICIENT_STORAGE, e.to_string()).into_response());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            error!("Stream error: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Err(e) => {
+            error!("Failed to store cache: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
 
-    let stream = ReaderStream::new(file);
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Encoding", "zstd".parse().unwrap());
+    if let Some(namespace) = &namespace.0 {
+        claim(state, namespace, volt_id).await?;
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Reads both streams to the end, comparing their sha256 rather than holding either in memory.
+async fn same_content(stored: ByteStream, incoming: ByteStream) -> std::io::Result<bool> {
+    async fn digest(mut stream: ByteStream) -> std::io::Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    // the incoming body goes first, so a corrupted or malformed upload fails as such instead of as a conflict
+    let incoming = digest(incoming).await?;
+    Ok(digest(stored).await? == incoming)
+}
+
+/// How a push body is compressed in transit. Archives are always stored as zstd, so anything else is recompressed on the way in.
+#[derive(Clone, Copy)]
+enum TransferCompression {
+    Zstd,
+    Gzip,
+    Identity,
+}
+
+/// Reads `X-Volt-Compression`, clients that don't send it upload zstd.
+fn transfer_compression(headers: &HeaderMap) -> Option<TransferCompression> {
+    match headers.get("X-Volt-Compression").map(|h| h.to_str().map(str::to_ascii_lowercase)) {
+        None => Some(TransferCompression::Zstd),
+        Some(Ok(value)) => match value.as_str() {
+            "zstd" => Some(TransferCompression::Zstd),
+            "gzip" => Some(TransferCompression::Gzip),
+            "identity" | "none" => Some(TransferCompression::Identity),
+            _ => None,
+        },
+        Some(Err(_)) => None,
+    }
+}
+
+#[derive(Debug)]
+struct DigestMismatch {
+    actual: String,
+    expected: String,
+}
+
+impl std::fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "upload has sha256 {} but X-Volt-Content-Sha256 is {}, it was corrupted in transit", self.actual, self.expected) }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// Hashes a push body as it streams through, ending it with an `InvalidData` error when the bytes don't match the client's `X-Volt-Content-Sha256`.
+/// The error reaches storage before the archive is committed, so a corrupted upload is thrown away instead of served.
+fn verify_digest<S>(body: S, expected: String) -> impl futures::Stream<Item = std::io::Result<Bytes>>
+where
+    S: futures::Stream<Item = std::io::Result<Bytes>> + Send + Unpin + 'static,
+{
+    stream::unfold(Some((body, Sha256::new(), expected)), |state| async move {
+        let (mut body, mut hasher, expected) = state?;
+
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                hasher.update(&chunk);
+                Some((Ok(chunk), Some((body, hasher, expected))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                let actual: String = hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect();
+                (actual != expected).then(|| (Err(std::io::Error::new(std::io::ErrorKind::InvalidData, DigestMismatch { actual, expected })), None))
+            }
+        }
+    })
+}
+
+/// Ends a push body with a `kind` error carrying `reason` once more than `limit` bytes have streamed through.
+/// Like a digest mismatch, the error reaches storage before the archive is committed, so nothing over the limit is kept.
+fn limit_bytes<S>(body: S, limit: u64, kind: std::io::ErrorKind, reason: String) -> impl futures::Stream<Item = std::io::Result<Bytes>>
+where
+    S: futures::Stream<Item = std::io::Result<Bytes>> + Send + Unpin + 'static,
+{
+    stream::unfold(Some((body, 0u64, reason)), move |state| async move {
+        let (mut body, seen, reason) = state?;
+
+        match body.next().await {
+            Some(Ok(chunk)) => match seen + chunk.len() as u64 {
+                seen if seen > limit => Some((Err(std::io::Error::new(kind, reason)), None)),
+                seen => Some((Ok(chunk), Some((body, seen, reason)))),
+            },
+            Some(Err(e)) => Some((Err(e), None)),
+            None => None,
+        }
+    })
+}
+
+/// Recompresses a push body to zstd as it streams in.
+/// Failures are nearly always a body that doesn't decode as declared, so they're all reported as the client's fault.
+fn transcode<S>(compression: TransferCompression, body: S) -> impl futures::Stream<Item = std::io::Result<Bytes>>
+where
+    S: futures::Stream<Item = std::io::Result<Bytes>> + Send + Unpin + 'static,
+{
+    let reader = SyncIoBridge::new(StreamReader::new(body));
+
+    let stream = read_blocking(move || {
+        let decoded: Box<dyn Read + Send> = match compression {
+            TransferCompression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            _ => Box::new(reader),
+        };
+        zstd::stream::read::Encoder::new(decoded, 3)
+    });
+
+    // a failed digest check or an exceeded limit passes through as is, so it's still told apart from a body that didn't decode
+    stream.map(|chunk| {
+        chunk.map_err(|e| match e.kind() {
+            std::io::ErrorKind::FileTooLarge | std::io::ErrorKind::StorageFull => e,
+            _ if e.get_ref().is_some_and(|inner| inner.is::<DigestMismatch>()) => e,
+            _ => std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+        })
+    })
+}
+
+/// Checks the client-declared archive size against the configured minimums, older clients that omit the headers are let through.
+fn reject_push(config: &ServerConfig, headers: &HeaderMap) -> Option<String> {
+    let declared = |name: &str| headers.get(name).and_then(|h| h.to_str().ok()).and_then(|h| h.parse::<u64>().ok());
+
+    if let (Some(min), Some(files)) = (config.min_push_files, declared("X-Volt-File-Count"))
+        && files < min
+    {
+        return Some(format!("your cache config matched {files} files, the server requires at least {min}"));
+    }
+
+    if let (Some(min), Some(size)) = (config.min_push_bytes, declared("X-Volt-Uncompressed-Size"))
+        && size < min
+    {
+        return Some(format!("your cache archive is only {size} bytes uncompressed, the server requires at least {min}"));
+    }
+
+    None
+}
+
+async fn pull(Path(volt_id): Path<String>, Query(query): Query<PullQuery>, State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
+    let volt_id = resolve_id(&state, volt_id).await?;
+
+    let client_hash = headers.get("X-Volt-Hash").and_then(|h| h.to_str().ok());
+    let uncompressed = prefers_uncompressed(&headers);
+    let server_hash = match &query.hash {
+        Some(hash) => Some(hash.clone()),
+        None => read_hash(&state, &volt_id).await?,
+    };
+
+    info!("{client_hash:?} to {server_hash:?}");
+
+    if let (Some(client_hash), Some(server_hash)) = (client_hash, &server_hash)
+        && client_hash == server_hash
+        && same_algorithm(&state, &volt_id, &headers).await?
+    {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+
+    let blob = match &query.hash {
+        Some(hash) => state.storage.get_version(&volt_id, hash).await,
+        None => state.storage.get(&volt_id).await,
+    };
+
+    let blob = blob
+        .map_err(|e| {
+            error!("File open error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or_else(|| {
+            warn!("File not found: {}", volt_id);
+            StatusCode::NOT_FOUND
+        })?;
+
+    let mut headers = HeaderMap::new();
+
+    // lets clients judge how stale a cache is before trusting it
+    if let Some(modified) = blob.modified {
+        headers.insert("Last-Modified", httpdate::fmt_http_date(modified).parse().unwrap());
+    }
+
+    if query.format == ArchiveFormat::Targz {
+        headers.insert("Content-Type", "application/gzip".parse().unwrap());
+        headers.insert("Content-Disposition", format!("attachment; filename=\"{volt_id}.tar.gz\"").parse().unwrap());
+
+        let reader = SyncIoBridge::new(StreamReader::new(blob.stream));
+        let stream = read_blocking(move || Ok(GzEncoder::new(zstd::stream::read::Decoder::new(reader)?, Compression::default())));
+        return Ok((headers, Body::from_stream(stream)).into_response());
+    }
+
+    if let Some(server_hash) = server_hash.and_then(|hash| hash.parse().ok()) {
+        headers.insert("X-Volt-Hash", server_hash);
+    }
+
+    // only the latest version's algorithm is recorded, an older one may have been pushed under another key scheme
+    let latest = match &query.hash {
+        Some(hash) => read_hash(&state, &volt_id).await?.as_ref() == Some(hash),
+        None => true,
+    };
+
+    if latest && let Some(algorithm) = read_algorithm(&state, &volt_id).await?.and_then(|algorithm| algorithm.parse().ok()) {
+        headers.insert("X-Volt-Hash-Algorithm", algorithm);
+    }
+
+    if uncompressed {
+        headers.insert("Content-Type", "application/x-tar".parse().unwrap());
+        headers.insert("Content-Disposition", format!("attachment; filename=\"{volt_id}.tar\"").parse().unwrap());
+
+        let reader = SyncIoBridge::new(StreamReader::new(blob.stream));
+        let stream = read_blocking(move || zstd::stream::read::Decoder::new(reader));
+        return Ok((headers, Body::from_stream(stream)).into_response());
+    }
+
+    headers.insert("Content-Encoding", "zstd".parse().unwrap());
+    headers.insert("Content-Type", "application/x-tar".parse().unwrap());
+    headers.insert("Content-Disposition", format!("attachment; filename=\"{volt_id}.tar.zst\"").parse().unwrap());
+
+    if let Some(length) = blob.length {
+        headers.insert("Content-Length", length.into());
+    }
+
+    Ok((headers, Body::from_stream(blob.stream)).into_response())
+}
+
+async fn warm(State(state): State<Arc<AppState>>, Json(request): Json<WarmRequest>) -> Result<StatusCode, StatusCode> {
+    let Some(upstream) = state.config.upstream.clone() else {
+        warn!("Warm requested but no upstream is configured");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if let Some(target) = request.ids.iter().find(|target| !state.config.id_format.accepts(&target.volt_id)) {
+        warn!("Invalid volt_id: {}", target.volt_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    for target in request.ids {
+        let (state, upstream) = (state.clone(), upstream.clone());
+
+        tokio::spawn(async move {
+            match fetch_upstream(&state, &upstream, &target).await {
+                Ok(true) => info!("Warmed {} from upstream", target.volt_id),
+                Ok(false) => info!("{} is already current", target.volt_id),
+                Err(e) => warn!("Failed to warm {}: {:#}", target.volt_id, e),
+            }
+        });
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Copies an archive from the upstream into local storage, returning whether anything was fetched.
+async fn fetch_upstream(state: &AppState, upstream: &Upstream, target: &WarmTarget) -> Result<bool> {
+    let current = state.storage.hash(&target.volt_id).await?;
+
+    if target.hash.is_some() && target.hash == current {
+        return Ok(false);
+    }
+
+    let url = format!("{}/pull/{}", upstream.address.trim_end_matches('/'), target.volt_id);
+    let mut request = state.http.get(url).bearer_auth(&upstream.auth_token);
+
+    request = match (&target.hash, &current) {
+        (Some(hash), _) => request.query(&[("hash", hash)]),
+        (None, Some(current)) => request.header("X-Volt-Hash", current),
+        (None, None) => request,
+    };
+
+    if let Some(algorithm) = state.storage.algorithm(&target.volt_id).await? {
+        request = request.header("X-Volt-Hash-Algorithm", algorithm);
+    }
+
+    let response = request.send().await?.error_for_status()?;
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(false);
+    }
+
+    let hash = response
+        .headers()
+        .get("X-Volt-Hash")
+        .and_then(|h| h.to_str().ok())
+        .map(ToString::to_string)
+        .or_else(|| target.hash.clone())
+        .context("upstream response carried no X-Volt-Hash")?;
+
+    let algorithm = client_algorithm(response.headers()).map(ToString::to_string);
+    let stream = response.bytes_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+    state.storage.put(&target.volt_id, &hash, algorithm.as_deref(), stream.boxed()).await?;
+
+    Ok(true)
+}
+
+async fn usage(State(state): State<Arc<AppState>>) -> Result<Json<Usage>, StatusCode> {
+    let mut names: Vec<String> = state.config.quotas.keys().cloned().collect();
+
+    if let Ok(mut entries) = tokio::fs::read_dir(state.config.cache_dir.join("namespaces")).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    let mut namespaces = BTreeMap::new();
+
+    for name in names {
+        let used = namespace_usage(&state, &name, None).await?;
+        let quota = state.config.quotas.get(&name).copied();
+        namespaces.insert(name, NamespaceUsage { used, quota });
+    }
+
+    let cache = state.storage.totals().await.map_err(|e| {
+        error!("Failed to total cache usage: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let limits = Limits {
+        keep_versions: state.config.keep_versions,
+        min_push_files: state.config.min_push_files,
+        min_push_bytes: state.config.min_push_bytes,
+        max_push_bytes: state.config.max_push_bytes,
+        max_concurrent_requests: state.config.max_concurrent_requests,
+    };
+
+    Ok(Json(Usage {
+        cache,
+        disk: disk_space(&state.config.cache_dir),
+        limits,
+        namespaces,
+    }))
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field widths differ between platforms
+fn disk_space(path: &std::path::Path) -> Option<DiskSpace> {
+    let path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(DiskSpace {
+        free: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total: stat.f_blocks as u64 * stat.f_frsize as u64,
+    })
+}
+
+#[cfg(not(unix))]
+fn disk_space(_path: &std::path::Path) -> Option<DiskSpace> { None }
+
+/// Accepts a `volt_id`, or a tag standing in for one on the read routes.
+/// Under `id_format = "any"` every tag is also a well-formed id, so an existing tag wins and anything else is taken as an id.
+async fn resolve_id(state: &AppState, volt_id: String) -> Result<String, StatusCode> {
+    let format = state.config.id_format;
+
+    if format != IdFormat::Any && format.accepts(&volt_id) {
+        return Ok(volt_id);
+    }
+
+    if !valid_tag(&volt_id, format) {
+        warn!("Invalid volt_id or tag: {volt_id}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match tokio::fs::read_to_string(state.config.cache_dir.join("tags").join(&volt_id)).await {
+        Ok(target) => Ok(target.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && format == IdFormat::Any => Ok(volt_id),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("Unknown tag: {volt_id}");
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(e) => {
+            error!("Failed to read tag {volt_id}: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Ids and tags double as file names, so they stay short and plain.
+fn path_safe(name: &str) -> bool { (1..=128).contains(&name.len()) && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) }
+
+/// Anything that already reads as a `volt_id` is left to mean one, except under `any` where tags take precedence.
+fn valid_tag(name: &str, format: IdFormat) -> bool { path_safe(name) && (format == IdFormat::Any || !format.accepts(name)) }
+
+async fn set_tag(Path(name): Path<String>, State(state): State<Arc<AppState>>, Json(request): Json<TagRequest>) -> Result<StatusCode, StatusCode> {
+    if !valid_tag(&name, state.config.id_format) {
+        warn!("Invalid tag name: {name}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if !state.config.id_format.accepts(&request.volt_id) {
+        warn!("Invalid volt_id: {}", request.volt_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let dir = state.config.cache_dir.join("tags");
+    let write = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(&name), &request.volt_id).await
+    };
+
+    write.await.map_err(|e: std::io::Error| {
+        error!("Failed to write tag {name}: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!("Tagged {} as {name}", request.volt_id);
+    Ok(StatusCode::OK)
+}
+
+async fn list_tags(State(state): State<Arc<AppState>>) -> Result<Json<BTreeMap<String, String>>, StatusCode> {
+    let mut entries = match tokio::fs::read_dir(state.config.cache_dir.join("tags")).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Json(BTreeMap::new())),
+        Err(e) => {
+            error!("Failed to read tags: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut tags = BTreeMap::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if let Ok(volt_id) = tokio::fs::read_to_string(entry.path()).await {
+            tags.insert(name, volt_id.trim().to_string());
+        }
+    }
+
+    Ok(Json(tags))
+}
+
+async fn invalidate(State(state): State<Arc<AppState>>, Json(request): Json<InvalidateRequest>) -> Result<Json<Invalidated>, StatusCode> {
+    if request.pattern.is_empty() {
+        warn!("Rejected invalidation with an empty pattern");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let read_names = |dir: PathBuf| async move {
+        let mut names = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    };
+
+    let failed = |e: std::io::Error| {
+        error!("Failed to collect entries to invalidate: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let tags_dir = state.config.cache_dir.join("tags");
+    let mut matched_tags = Vec::new();
+
+    let mut candidates = match request.tags {
+        false => state.storage.ids().await.map_err(failed)?.into_iter().filter(|volt_id| glob_match(&request.pattern, volt_id)).collect(),
+        true => {
+            let mut ids = Vec::new();
+            for tag in read_names(tags_dir.clone()).await.map_err(failed)? {
+                if glob_match(&request.pattern, &tag)
+                    && let Ok(volt_id) = tokio::fs::read_to_string(tags_dir.join(&tag)).await
+                {
+                    ids.push(volt_id.trim().to_string());
+                    matched_tags.push((tag, volt_id.trim().to_string()));
+                }
+            }
+            ids
+        }
+    };
+
+    if let Some(namespace) = &request.namespace {
+        if !path_safe(namespace) {
+            warn!("Invalid namespace: {namespace}");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let claimed = read_names(state.config.cache_dir.join("namespaces").join(namespace)).await.map_err(failed)?;
+        candidates.retain(|volt_id: &String| claimed.contains(volt_id));
+    }
+
+    candidates.sort();
+    candidates.dedup();
+    let kept = candidates.clone();
+
+    let namespaces = read_names(state.config.cache_dir.join("namespaces")).await.map_err(failed)?;
+    let mut removed = Vec::new();
+
+    for volt_id in candidates {
+        let existed = state.storage.remove(&volt_id).await.map_err(|e| {
+            error!("Failed to remove {volt_id}: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        // the claims would otherwise keep a deleted id counted against its namespace on the next push
+        unclaim(&state, &namespaces, &volt_id).await;
+
+        if existed {
+            removed.push(volt_id);
+        }
+    }
+
+    // a tag only goes with its id, so one pointing outside the namespace filter is left alone
+    for (tag, volt_id) in matched_tags {
+        if kept.contains(&volt_id) {
+            let _ = tokio::fs::remove_file(tags_dir.join(tag)).await;
+        }
+    }
+
+    info!("Invalidated {} entries matching {}", removed.len(), request.pattern);
+    Ok(Json(Invalidated { removed: removed.len(), ids: removed }))
+}
+
+/// Matches `*` against any run of characters and `?` against exactly one, everything else literally.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character and retry from there
+                Some((star, consumed)) => {
+                    backtrack = Some((star, consumed + 1));
+                    p = star + 1;
+                    n = consumed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Deletes one entry and the tags pointing at it. A token scoped to a namespace may only delete ids that namespace claimed,
+/// one without a namespace needs admin unless the id is a benchmark's, so clients can clean up after themselves without
+/// the admin token `/invalidate` takes.
+async fn delete_entry(Path(volt_id): Path<String>, State(state): State<Arc<AppState>>, Extension(namespace): Extension<Namespace>, headers: HeaderMap) -> Result<StatusCode, StatusCode> {
+    if !state.config.id_format.accepts(&volt_id) {
+        warn!("Invalid volt_id: {volt_id}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if state.config.immutable {
+        warn!("Refused to delete {volt_id}: archives are immutable");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let namespaces_dir = state.config.cache_dir.join("namespaces");
+
+    match &namespace.0 {
+        Some(namespace) if !tokio::fs::try_exists(namespaces_dir.join(namespace).join(&volt_id)).await.unwrap_or(false) => {
+            warn!("Refused to delete {volt_id}: namespace {namespace} never pushed it");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        None if !volt_id.starts_with(BENCH_ID_PREFIX) => {
+            authorize(&state, &headers, true).await?;
+        }
+        _ => {}
+    }
+
+    let existed = state.storage.remove(&volt_id).await.map_err(|e| {
+        error!("Failed to remove {volt_id}: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut namespaces = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(&namespaces_dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            namespaces.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    unclaim(&state, &namespaces, &volt_id).await;
+
+    // a tag left behind would resolve to an id that no longer exists
+    if let Ok(mut entries) = tokio::fs::read_dir(state.config.cache_dir.join("tags")).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if tokio::fs::read_to_string(entry.path()).await.is_ok_and(|tagged| tagged.trim() == volt_id) {
+                let _ = tokio::fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+
+    match existed {
+        true => {
+            info!("Deleted {volt_id}");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Drops every namespace's claim on `volt_id`, once it's gone from storage.
+async fn unclaim(state: &AppState, namespaces: &[String], volt_id: &str) {
+    for namespace in namespaces {
+        let _ = tokio::fs::remove_file(state.config.cache_dir.join("namespaces").join(namespace).join(volt_id)).await;
+    }
+}
+
+/// Records that `volt_id` belongs to `namespace`, as an empty marker file under `namespaces/`.
+async fn claim(state: &AppState, namespace: &str, volt_id: &str) -> Result<(), StatusCode> {
+    let dir = state.config.cache_dir.join("namespaces").join(namespace);
+    let write = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(volt_id), "").await
+    };
+
+    write.await.map_err(|e: std::io::Error| {
+        error!("Failed to record namespace for {volt_id}: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Sums the stored size of every `volt_id` claimed by `namespace`, leaving out `except` since a push replaces it.
+async fn namespace_usage(state: &AppState, namespace: &str, except: Option<&str>) -> Result<u64, StatusCode> {
+    let mut entries = match tokio::fs::read_dir(state.config.cache_dir.join("namespaces").join(namespace)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            error!("Failed to read namespace {namespace}: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut used = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let volt_id = entry.file_name().to_string_lossy().into_owned();
+
+        if Some(volt_id.as_str()) == except {
+            continue;
+        }
+
+        let size = state.storage.size(&volt_id).await.map_err(|e| {
+            error!("Failed to size {volt_id}: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        used += size.unwrap_or_default();
+    }
+
+    Ok(used)
+}
+
+async fn logs(Query(query): Query<LogsQuery>, State(state): State<Arc<AppState>>) -> Response {
+    if !query.follow {
+        return Json(state.logs.tail(query.tail)).into_response();
+    }
+
+    let (tail, receiver) = state.logs.follow(query.tail);
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(line) => return Some((line, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    let events = stream::iter(tail).chain(live).map(|line| Event::default().json_data(line));
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+async fn add_token(State(state): State<Arc<AppState>>, Json(token): Json<Token>) -> Result<StatusCode, StatusCode> {
+    if token.name.is_empty() || token.token.is_empty() {
+        warn!("Rejected token with an empty name or value");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // namespaces become directory names, so keep them to plain identifiers
+    if let Some(namespace) = &token.namespace
+        && (namespace.is_empty() || !namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    {
+        warn!("Rejected token with invalid namespace {namespace:?}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut tokens = state.tokens.write().await;
+
+    if tokens.iter().any(|existing| existing.name == token.name) {
+        warn!("Token {} already exists", token.name);
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut updated = tokens.clone();
+    updated.push(token.clone());
+    persist_tokens(&updated).await?;
+    *tokens = updated;
+
+    info!("Added token {} (admin: {})", token.name, token.admin);
+    Ok(StatusCode::CREATED)
+}
+
+async fn get_maintenance(State(state): State<Arc<AppState>>) -> Json<Maintenance> { Json(state.maintenance.read().await.clone()) }
+
+/// Switches maintenance mode until the next restart, which goes back to what the config says.
+async fn set_maintenance(State(state): State<Arc<AppState>>, Json(update): Json<Maintenance>) -> Json<Maintenance> {
+    let mut maintenance = state.maintenance.write().await;
+    let message = update.message.as_deref().map_or(String::new(), |message| format!(": {message}"));
+
+    match (maintenance.mode, update.mode) {
+        (MaintenanceMode::Off, MaintenanceMode::Off) => {}
+        (_, MaintenanceMode::Off) => info!("Leaving maintenance mode"),
+        (from, to) if from == to => info!("Updated maintenance mode ({}){message}", to.name()),
+        (_, to) => warn!("Entering maintenance mode ({}){message}", to.name()),
+    }
+
+    *maintenance = update;
+    Json(maintenance.clone())
+}
+
+/// Describes the token the request was made with, so a client can rotate it without knowing what it was named.
+async fn whoami(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<Identity>, StatusCode> {
+    let presented = presented_token(&state.config, &headers).ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if presented == state.config.auth_token {
+        return Ok(Json(Identity {
+            name: None,
+            admin: true,
+            namespace: None,
+        }));
+    }
+
+    let tokens = state.tokens.read().await;
+    let token = tokens.iter().find(|token| token.token == presented).ok_or(StatusCode::FORBIDDEN)?;
+
+    Ok(Json(Identity {
+        name: Some(token.name.clone()),
+        admin: token.admin,
+        namespace: token.namespace.clone(),
+    }))
+}
+
+async fn revoke_token(Path(name): Path<String>, State(state): State<Arc<AppState>>) -> Result<StatusCode, StatusCode> {
+    let mut tokens = state.tokens.write().await;
+
+    if !tokens.iter().any(|token| token.name == name) {
+        warn!("Token {name} not found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let updated: Vec<Token> = tokens.iter().filter(|token| token.name != name).cloned().collect();
+    persist_tokens(&updated).await?;
+    *tokens = updated;
+
+    info!("Revoked token {name}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Rewrites the `tokens` array in the config file through a temp file, so a crash never leaves it half-written.
+async fn persist_tokens(tokens: &[Token]) -> Result<(), StatusCode> {
+    let write = async {
+        let mut doc = tokio::fs::read_to_string(CONFIG_PATH).await?.parse::<DocumentMut>().map_err(std::io::Error::other)?;
+        let mut array = ArrayOfTables::new();
+
+        for token in tokens {
+            let mut table = Table::new();
+            table["name"] = value(&token.name);
+            table["token"] = value(&token.token);
+            if token.admin {
+                table["admin"] = value(true);
+            }
+            if let Some(namespace) = &token.namespace {
+                table["namespace"] = value(namespace);
+            }
+            array.push(table);
+        }
+
+        doc["tokens"] = Item::ArrayOfTables(array);
+
+        let tmp = format!("{CONFIG_PATH}.tmp");
+        tokio::fs::write(&tmp, doc.to_string()).await?;
+        tokio::fs::rename(&tmp, CONFIG_PATH).await
+    };
+
+    write.await.map_err(|e: std::io::Error| {
+        error!("Failed to persist tokens: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn read_hash(state: &AppState, volt_id: &str) -> Result<Option<String>, StatusCode> {
+    state.storage.hash(volt_id).await.map_err(|e| {
+        error!("Failed to read hash: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn read_algorithm(state: &AppState, volt_id: &str) -> Result<Option<String>, StatusCode> {
+    state.storage.algorithm(volt_id).await.map_err(|e| {
+        error!("Failed to read hash algorithm: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+fn client_algorithm(headers: &HeaderMap) -> Option<&str> { headers.get("X-Volt-Hash-Algorithm").and_then(|h| h.to_str().ok()) }
+
+/// Equal hashes only count as a match when both were computed with the same scheme, untagged hashes matching each other.
+async fn same_algorithm(state: &AppState, volt_id: &str, headers: &HeaderMap) -> Result<bool, StatusCode> {
+    let stored = read_algorithm(state, volt_id).await?;
+    let same = stored.as_deref() == client_algorithm(headers);
+
+    if !same {
+        info!("Hash algorithm changed for {volt_id}: client={:?} server={stored:?}", client_algorithm(headers));
+    }
+
+    Ok(same)
+}
+
+fn prefers_uncompressed(headers: &HeaderMap) -> bool { headers.get("X-Volt-Prefer-Uncompressed").is_some_and(|value| value == "1") }
+
+/// Drains a transcoding reader (e.g. zstd to gzip) on a blocking thread, streaming chunks as they're produced.
+fn read_blocking<R: Read>(open: impl FnOnce() -> std::io::Result<R> + Send + 'static) -> impl futures::Stream<Item = std::io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(8);
+
+    tokio::task::spawn_blocking(move || {
+        let mut reader = match open() {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e));
+                return;
+            }
+        };
+
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            let chunk = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => Ok(Bytes::copy_from_slice(&buffer[..n])),
+                Err(e) => Err(e),
+            };
+
+            let failed = chunk.is_err();
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
 
-    Ok((headers, Body::from_stream(stream)).into_response())
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) })
 }
@@ -0,0 +1,516 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{StreamExt, stream::BoxStream};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+use tokio::{
+    fs::{self, File, create_dir_all},
+    io::{AsyncWriteExt, BufWriter},
+    sync::Mutex,
+};
+use tokio_util::io::ReaderStream;
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// A stored archive, streamed back to the client.
+pub struct Blob {
+    pub stream: ByteStream,
+    pub length: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// How many `volt_id`s hold an archive, and the bytes they take up across every retained version.
+#[derive(Serialize, Default)]
+pub struct Totals {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Disk,
+    #[cfg(feature = "memory")]
+    Memory,
+}
+
+/// Where cache archives and their hashes live, keyed by `volt_id`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn hash(&self, volt_id: &str) -> io::Result<Option<String>>;
+    async fn put(&self, volt_id: &str, hash: &str, algorithm: Option<&str>, body: ByteStream) -> io::Result<()>;
+    async fn get(&self, volt_id: &str) -> io::Result<Option<Blob>>;
+
+    /// The hashing scheme the client tagged the stored hash with, if it sent one.
+    async fn algorithm(&self, volt_id: &str) -> io::Result<Option<String>>;
+
+    /// Bytes stored for a `volt_id`, counting every retained version.
+    async fn size(&self, volt_id: &str) -> io::Result<Option<u64>>;
+
+    async fn totals(&self) -> io::Result<Totals>;
+
+    /// Every `volt_id` with a stored hash, in no particular order.
+    async fn ids(&self) -> io::Result<Vec<String>>;
+
+    /// Deletes a `volt_id` with every retained version, returning whether anything was stored for it.
+    async fn remove(&self, volt_id: &str) -> io::Result<bool>;
+
+    /// Fetches the archive stored under a specific hash, backends without history only know the latest one.
+    async fn get_version(&self, volt_id: &str, hash: &str) -> io::Result<Option<Blob>> {
+        match self.hash(volt_id).await? {
+            Some(latest) if latest == hash => self.get(volt_id).await,
+            _ => Ok(None),
+        }
+    }
+}
+
+pub fn open(backend: Backend, cache_dir: PathBuf, keep_versions: Option<usize>, hash_index: bool) -> io::Result<Arc<dyn Storage>> {
+    match backend {
+        Backend::Disk => {
+            let keep_versions = keep_versions.filter(|keep| *keep > 0);
+            let index = match hash_index {
+                true => Some(HashIndex::open(&cache_dir, keep_versions.is_some())?),
+                false => None,
+            };
+
+            Ok(Arc::new(DiskBackend {
+                root: cache_dir,
+                keep_versions,
+                index,
+            }))
+        }
+        #[cfg(feature = "memory")]
+        Backend::Memory => Ok(Arc::new(InMemoryBackend::default())),
+    }
+}
+
+/// Stores `{volt_id}.zst` and `{volt_id}.hash`, or with `keep_versions` set, `{volt_id}/{hash}.zst` plus a `latest` pointer.
+/// With a hash index, the per-id hash files are replaced by the single `hashes.idx`.
+/// Either way a hash record is the hash, followed by its algorithm tag after a space when the client sent one.
+pub struct DiskBackend {
+    root: PathBuf,
+    keep_versions: Option<usize>,
+    index: Option<HashIndex>,
+}
+
+/// An append-only file of `volt_id hash` lines, replayed into memory at startup with later lines winning.
+/// A line holding only a `volt_id` is a tombstone left by a removal.
+struct HashIndex {
+    entries: RwLock<HashMap<String, String>>,
+    file: Mutex<File>,
+}
+
+impl HashIndex {
+    /// Loads the index, folding in hash files left over from the per-file layout and dropping superseded lines.
+    fn open(root: &Path, versioned: bool) -> io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+
+        let path = root.join("hashes.idx");
+        let mut entries = HashMap::new();
+        let mut lines = 0;
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                for line in content.lines().filter(|line| !line.is_empty()) {
+                    match line.split_once(' ') {
+                        Some((volt_id, hash)) => entries.insert(volt_id.to_string(), hash.to_string()),
+                        None => entries.remove(line),
+                    };
+                    lines += 1;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut migrated = Vec::new();
+
+        for entry in std::fs::read_dir(root)? {
+            let path = entry?.path();
+            let (volt_id, hash_file) = match versioned {
+                false if path.extension().is_some_and(|ext| ext == "hash") => (path.file_stem(), path.clone()),
+                true if path.is_dir() => (path.file_name(), path.join("latest")),
+                _ => continue,
+            };
+
+            if let Some(volt_id) = volt_id.and_then(|id| id.to_str())
+                && let Ok(hash) = std::fs::read_to_string(&hash_file)
+            {
+                entries.insert(volt_id.to_string(), hash.trim().to_string());
+                migrated.push(hash_file);
+            }
+        }
+
+        if !migrated.is_empty() || lines > entries.len() {
+            let compacted: String = entries.iter().map(|(volt_id, hash)| format!("{volt_id} {hash}\n")).collect();
+            let staging = root.join("hashes.idx.tmp");
+
+            std::fs::write(&staging, compacted)?;
+            std::fs::rename(&staging, &path)?;
+        }
+
+        // only once the index holds them, so an interrupted migration never loses a hash
+        for hash_file in migrated {
+            std::fs::remove_file(hash_file)?;
+        }
+
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            entries: RwLock::new(entries),
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+
+    fn get(&self, volt_id: &str) -> Option<String> { self.entries.read().unwrap().get(volt_id).cloned() }
+
+    async fn set(&self, volt_id: &str, hash: &str) -> io::Result<()> {
+        let mut file = self.file.lock().await;
+
+        file.write_all(format!("{volt_id} {hash}\n").as_bytes()).await?;
+        file.flush().await?;
+
+        self.entries.write().unwrap().insert(volt_id.to_string(), hash.to_string());
+        Ok(())
+    }
+
+    async fn remove(&self, volt_id: &str) -> io::Result<bool> {
+        let mut file = self.file.lock().await;
+
+        if !self.entries.read().unwrap().contains_key(volt_id) {
+            return Ok(false);
+        }
+
+        file.write_all(format!("{volt_id}\n").as_bytes()).await?;
+        file.flush().await?;
+
+        self.entries.write().unwrap().remove(volt_id);
+        Ok(true)
+    }
+}
+
+impl DiskBackend {
+    fn hash_path(&self, volt_id: &str) -> PathBuf {
+        match self.keep_versions {
+            Some(_) => self.root.join(volt_id).join("latest"),
+            None => self.root.join(format!("{volt_id}.hash")),
+        }
+    }
+
+    fn archive_path(&self, volt_id: &str, hash: &str) -> PathBuf {
+        match self.keep_versions {
+            Some(_) => self.root.join(volt_id).join(format!("{hash}.zst")),
+            None => self.root.join(format!("{volt_id}.zst")),
+        }
+    }
+
+    async fn record(&self, volt_id: &str) -> io::Result<Option<String>> {
+        if let Some(index) = &self.index {
+            return Ok(index.get(volt_id));
+        }
+
+        match fs::read_to_string(self.hash_path(volt_id)).await {
+            Ok(record) => Ok(Some(record.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the oldest versions of a `volt_id` beyond `keep`, by modification time.
+    async fn prune(&self, dir: &Path, keep: usize) -> io::Result<()> {
+        let mut versions = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "zst") {
+                versions.push((entry.metadata().await?.modified()?, path));
+            }
+        }
+
+        versions.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+        for (_, path) in versions.into_iter().skip(keep) {
+            fs::remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for DiskBackend {
+    async fn hash(&self, volt_id: &str) -> io::Result<Option<String>> {
+        let record = self.record(volt_id).await?;
+        Ok(record.map(|record| record.split(' ').next().unwrap_or_default().to_string()))
+    }
+
+    async fn algorithm(&self, volt_id: &str) -> io::Result<Option<String>> {
+        let record = self.record(volt_id).await?;
+        Ok(record.and_then(|record| Some(record.split_once(' ')?.1.to_string())))
+    }
+
+    async fn put(&self, volt_id: &str, hash: &str, algorithm: Option<&str>, mut body: ByteStream) -> io::Result<()> {
+        // the hash becomes a file name once versions are kept, so it can't be allowed to escape the directory
+        if self.keep_versions.is_some() && (hash.is_empty() || !hash.chars().all(|c| c.is_ascii_alphanumeric())) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "hash must be alphanumeric when keeping versions"));
+        }
+
+        if hash.contains(char::is_whitespace) || algorithm.is_some_and(|algorithm| algorithm.is_empty() || algorithm.contains(char::is_whitespace)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "hash and algorithm must not contain whitespace"));
+        }
+
+        let archive = self.archive_path(volt_id, hash);
+        create_dir_all(archive.parent().unwrap_or(&self.root)).await?;
+
+        // a body that fails partway, or doesn't match its digest, must never replace the archive being served
+        let staging = archive.with_extension(format!("zst.{}.partial", uuid::Uuid::new_v4().simple()));
+
+        if let Err(e) = write_body(&staging, &mut body).await {
+            let _ = fs::remove_file(&staging).await;
+            return Err(e);
+        }
+
+        fs::rename(&staging, &archive).await?;
+
+        let record = match algorithm {
+            Some(algorithm) => format!("{hash} {algorithm}"),
+            None => hash.to_string(),
+        };
+
+        match &self.index {
+            Some(index) => index.set(volt_id, &record).await?,
+            None => fs::write(self.hash_path(volt_id), record).await?,
+        }
+
+        match (self.keep_versions, archive.parent()) {
+            (Some(keep), Some(dir)) => self.prune(dir, keep).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn get(&self, volt_id: &str) -> io::Result<Option<Blob>> {
+        let hash = match self.keep_versions {
+            Some(_) => match self.hash(volt_id).await? {
+                Some(hash) => hash,
+                None => return Ok(None),
+            },
+            None => String::new(),
+        };
+
+        open_blob(&self.archive_path(volt_id, &hash)).await
+    }
+
+    async fn get_version(&self, volt_id: &str, hash: &str) -> io::Result<Option<Blob>> {
+        if self.keep_versions.is_none() || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return match self.hash(volt_id).await? {
+                Some(latest) if latest == hash => self.get(volt_id).await,
+                _ => Ok(None),
+            };
+        }
+
+        open_blob(&self.archive_path(volt_id, hash)).await
+    }
+
+    async fn size(&self, volt_id: &str) -> io::Result<Option<u64>> {
+        if self.keep_versions.is_none() {
+            return match fs::metadata(self.archive_path(volt_id, "")).await {
+                Ok(metadata) => Ok(Some(metadata.len())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+
+        let mut entries = match fs::read_dir(self.root.join(volt_id)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut total = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|ext| ext == "zst") {
+                total += entry.metadata().await?.len();
+            }
+        }
+
+        Ok(Some(total))
+    }
+
+    async fn totals(&self) -> io::Result<Totals> {
+        let mut totals = Totals::default();
+
+        let mut entries = match fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(totals),
+            Err(e) => return Err(e),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let bytes = match self.keep_versions {
+                None if entry.path().extension().is_some_and(|ext| ext == "zst") => entry.metadata().await?.len(),
+                // other directories, like `namespaces`, hold no archives and come out empty
+                Some(_) if entry.file_type().await?.is_dir() => match self.size(&entry.file_name().to_string_lossy()).await? {
+                    Some(bytes) if bytes > 0 => bytes,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            totals.entries += 1;
+            totals.bytes += bytes;
+        }
+
+        Ok(totals)
+    }
+
+    async fn ids(&self) -> io::Result<Vec<String>> {
+        if let Some(index) = &self.index {
+            return Ok(index.entries.read().unwrap().keys().cloned().collect());
+        }
+
+        let mut ids = Vec::new();
+        let mut entries = match fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let volt_id = match self.keep_versions {
+                None if path.extension().is_some_and(|ext| ext == "hash") => path.file_stem(),
+                Some(_) if path.join("latest").is_file() => path.file_name(),
+                _ => continue,
+            };
+
+            if let Some(volt_id) = volt_id.and_then(|id| id.to_str()) {
+                ids.push(volt_id.to_string());
+            }
+        }
+
+        Ok(ids)
+    }
+
+    async fn remove(&self, volt_id: &str) -> io::Result<bool> {
+        let mut removed = match &self.index {
+            Some(index) => index.remove(volt_id).await?,
+            None => false,
+        };
+
+        let found = |result: io::Result<()>| match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        match self.keep_versions {
+            Some(_) => removed |= found(fs::remove_dir_all(self.root.join(volt_id)).await)?,
+            None => {
+                removed |= found(fs::remove_file(self.root.join(format!("{volt_id}.zst"))).await)?;
+                removed |= found(fs::remove_file(self.hash_path(volt_id)).await)?;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+async fn write_body(path: &Path, body: &mut ByteStream) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path).await?);
+
+    while let Some(chunk) = body.next().await {
+        writer.write_all(&chunk?).await?;
+    }
+
+    writer.flush().await
+}
+
+async fn open_blob(path: &Path) -> io::Result<Option<Blob>> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let metadata = file.metadata().await.ok();
+    Ok(Some(Blob {
+        length: metadata.as_ref().map(|metadata| metadata.len()),
+        modified: metadata.and_then(|metadata| metadata.modified().ok()),
+        stream: ReaderStream::new(file).boxed(),
+    }))
+}
+
+#[cfg(feature = "memory")]
+struct MemoryEntry {
+    hash: String,
+    algorithm: Option<String>,
+    blob: Bytes,
+    modified: SystemTime,
+}
+
+/// Keeps archives and hashes in a `HashMap` for throwaway servers, nothing survives a restart.
+/// Only storage lives here, namespaces, tags and staged uploads are still written under `cache_dir`.
+#[cfg(feature = "memory")]
+#[derive(Default)]
+pub struct InMemoryBackend {
+    entries: RwLock<HashMap<String, MemoryEntry>>,
+}
+
+#[cfg(feature = "memory")]
+#[async_trait]
+impl Storage for InMemoryBackend {
+    async fn hash(&self, volt_id: &str) -> io::Result<Option<String>> { Ok(self.entries.read().unwrap().get(volt_id).map(|entry| entry.hash.clone())) }
+
+    async fn algorithm(&self, volt_id: &str) -> io::Result<Option<String>> { Ok(self.entries.read().unwrap().get(volt_id).and_then(|entry| entry.algorithm.clone())) }
+
+    async fn put(&self, volt_id: &str, hash: &str, algorithm: Option<&str>, mut body: ByteStream) -> io::Result<()> {
+        let mut blob = Vec::new();
+
+        while let Some(chunk) = body.next().await {
+            blob.extend_from_slice(&chunk?);
+        }
+
+        let entry = MemoryEntry {
+            hash: hash.to_string(),
+            algorithm: algorithm.map(ToString::to_string),
+            blob: Bytes::from(blob),
+            modified: SystemTime::now(),
+        };
+
+        self.entries.write().unwrap().insert(volt_id.to_string(), entry);
+        Ok(())
+    }
+
+    async fn get(&self, volt_id: &str) -> io::Result<Option<Blob>> {
+        let entries = self.entries.read().unwrap();
+
+        Ok(entries.get(volt_id).map(|entry| Blob {
+            length: Some(entry.blob.len() as u64),
+            modified: Some(entry.modified),
+            stream: futures::stream::once(futures::future::ready(Ok(entry.blob.clone()))).boxed(),
+        }))
+    }
+
+    async fn size(&self, volt_id: &str) -> io::Result<Option<u64>> { Ok(self.entries.read().unwrap().get(volt_id).map(|entry| entry.blob.len() as u64)) }
+
+    async fn totals(&self) -> io::Result<Totals> {
+        let entries = self.entries.read().unwrap();
+
+        Ok(Totals {
+            entries: entries.len() as u64,
+            bytes: entries.values().map(|entry| entry.blob.len() as u64).sum(),
+        })
+    }
+
+    async fn ids(&self) -> io::Result<Vec<String>> { Ok(self.entries.read().unwrap().keys().cloned().collect()) }
+
+    async fn remove(&self, volt_id: &str) -> io::Result<bool> { Ok(self.entries.write().unwrap().remove(volt_id).is_some()) }
+}
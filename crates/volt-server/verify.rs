@@ -0,0 +1,143 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Starts archives the client encrypted before pushing, which only the client can open.
+const SEALED_MAGIC: &[u8; 8] = b"VOLTE2E1";
+const BLOCK: usize = 512;
+
+/// What `/verify` found reading an entry back, `ok` is false with a `detail` when any byte of it doesn't hold up.
+#[derive(Serialize)]
+pub struct Verified {
+    pub ok: bool,
+    /// Stored size, as read from the backend.
+    pub bytes: u64,
+    /// Tar entries, not counting long-name and pax headers, unset when the archive couldn't be opened here.
+    pub entries: Option<u64>,
+    /// Size of the tar stream once decompressed.
+    pub unpacked: Option<u64>,
+    /// Digest of the stored bytes, what a client pulling the entry would receive.
+    pub sha256: String,
+    pub hash: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// Counts and hashes everything read through it, so the digest covers the whole entry even when decoding stops early.
+struct Tally<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<R: Read> Read for Tally<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads a stored entry to its end, decompressing it and walking every tar header on the way.
+/// Encrypted and dictionary-compressed archives can't be decoded without what only clients hold, so only their bytes are read.
+pub fn verify(reader: impl Read, hash: Option<String>) -> Verified {
+    let mut reader = BufReader::with_capacity(
+        64 * 1024,
+        Tally {
+            inner: reader,
+            hasher: Sha256::new(),
+            bytes: 0,
+        },
+    );
+
+    let skipped = match reader.fill_buf() {
+        Ok(head) if head.starts_with(SEALED_MAGIC) => Some(Unchecked::Skipped("the archive is encrypted by the client, only its bytes were read".to_string())),
+        Ok(head) => zstd::zstd_safe::get_dict_id_from_frame(head).map(|id| Unchecked::Skipped(format!("the archive is compressed with client dictionary {id}, only its bytes were read"))),
+        Err(err) => Some(Unchecked::Failed(err)),
+    };
+
+    let walked = match skipped {
+        Some(unchecked) => Err(unchecked),
+        None => walk(&mut reader).map_err(Unchecked::Failed),
+    };
+
+    // anything left after the tar end marker still has to come off the disk intact
+    let drained = io::copy(&mut reader, &mut io::sink());
+    let tally = reader.into_inner();
+    let sha256 = tally.hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect();
+
+    let (ok, entries, unpacked, detail) = match (walked, drained) {
+        (Err(Unchecked::Failed(err)), _) | (_, Err(err)) => (false, None, None, Some(err.to_string())),
+        (Err(Unchecked::Skipped(detail)), Ok(_)) => (true, None, None, Some(detail)),
+        (Ok((entries, unpacked)), Ok(_)) => (true, Some(entries), Some(unpacked), None),
+    };
+
+    Verified {
+        ok,
+        bytes: tally.bytes,
+        entries,
+        unpacked,
+        sha256,
+        hash,
+        detail,
+    }
+}
+
+/// Why an entry's tar stream wasn't walked to its end.
+enum Unchecked {
+    Skipped(String),
+    Failed(io::Error),
+}
+
+/// Walks the decompressed tar stream, checking each header's checksum and that every entry's data is all there.
+fn walk(reader: impl BufRead) -> io::Result<(u64, u64)> {
+    let mut tar = zstd::stream::read::Decoder::with_buffer(reader)?;
+
+    let corrupt = |detail: String| io::Error::new(io::ErrorKind::InvalidData, detail);
+    let mut header = [0u8; BLOCK];
+    let (mut entries, mut unpacked) = (0, 0);
+
+    loop {
+        tar.read_exact(&mut header).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt(format!("the archive ends after {entries} entries without a tar end marker")),
+            _ => err,
+        })?;
+        unpacked += BLOCK as u64;
+
+        if header.iter().all(|&byte| byte == 0) {
+            unpacked += io::copy(&mut tar, &mut io::sink())?;
+            return Ok((entries, unpacked));
+        }
+
+        let stored = octal(&header[148..156]).ok_or_else(|| corrupt(format!("tar header {} has an unreadable checksum", entries + 1)))?;
+        let actual: u64 = header.iter().enumerate().map(|(n, &byte)| if (148..156).contains(&n) { b' ' as u64 } else { byte as u64 }).sum();
+
+        if stored != actual {
+            return Err(corrupt(format!("tar header {} fails its checksum", entries + 1)));
+        }
+
+        let size = match header[124] & 0x80 {
+            0 => octal(&header[124..136]).ok_or_else(|| corrupt(format!("tar header {} has an unreadable size", entries + 1)))?,
+            _ => header[128..136].iter().fold(0, |size, &byte| size << 8 | byte as u64),
+        };
+
+        let padded = size.div_ceil(BLOCK as u64) * BLOCK as u64;
+        if io::copy(&mut (&mut tar).take(padded), &mut io::sink())? != padded {
+            return Err(corrupt(format!("the archive ends inside entry {}", entries + 1)));
+        }
+        unpacked += padded;
+
+        // long names and pax headers describe the entry after them
+        if !matches!(header[156], b'L' | b'K' | b'x' | b'g') {
+            entries += 1;
+        }
+    }
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(field).ok()?.trim_matches(|c: char| c == '\0' || c == ' ');
+    match digits.is_empty() {
+        true => Some(0),
+        false => u64::from_str_radix(digits, 8).ok(),
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{
+    config::{Archive, OnConflict},
+    pattern,
+};
+use anyhow::{Context, Result, bail};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tar::{Builder, HeaderMode};
+use walkdir::WalkDir;
+
+/// Generous enough that no real cache hits them, while still stopping an archive built to exhaust inodes or path lengths.
+const MAX_ENTRIES: usize = 5_000_000;
+const MAX_PATH_DEPTH: usize = 128;
+
+/// The mtime reproducible archives pin every entry to, which says nothing about whether a file changed.
+const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+/// How a downloaded archive is encoded, told apart by its leading bytes.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+    Tar,
+}
+
+/// Reads the encoding off the archive itself, so a proxy that drops or rewrites `Content-Encoding` can't send it to the wrong decoder.
+pub fn sniff(archive: &[u8]) -> Option<Encoding> {
+    const ZSTD: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    const GZIP: &[u8] = &[0x1f, 0x8b];
+
+    match archive {
+        _ if archive.starts_with(ZSTD) => Some(Encoding::Zstd),
+        _ if archive.starts_with(GZIP) => Some(Encoding::Gzip),
+        _ if archive.get(257..262) == Some(b"ustar") => Some(Encoding::Tar),
+        // an empty tarball is nothing but zeroed end-of-archive blocks
+        _ if archive.len() >= 1024 && archive[..1024].iter().all(|byte| *byte == 0) => Some(Encoding::Tar),
+        _ => None,
+    }
+}
+
+/// Tars the cache directories with entries in sorted path order, so the same tree always yields the same entry sequence.
+pub fn build(dirs: &[&String], exclude: &[String], settings: &Archive) -> io::Result<Vec<u8>> { write(dirs, exclude, settings, Vec::new()) }
+
+/// What `build` does, into any writer, so the tar can be streamed through a hasher without ever being held in memory.
+pub fn write<W: io::Write>(dirs: &[&String], exclude: &[String], settings: &Archive, out: W) -> io::Result<W> {
+    let mut builder = Builder::new(out);
+
+    // zeroes owners and pins mtimes and modes, so identical trees give byte-identical archives across machines
+    if settings.reproducible {
+        builder.mode(HeaderMode::Deterministic);
+    }
+
+    for dir in dirs {
+        for entry in pattern::walk(WalkDir::new(dir).follow_links(true).sort_by_file_name(), exclude) {
+            let entry = entry?;
+            let path = entry.path();
+
+            match entry.file_type().is_dir() {
+                true => builder.append_dir(path, path)?,
+                false => builder.append_path_with_name(path, path)?,
+            }
+        }
+    }
+
+    builder.into_inner()
+}
+
+/// Rewrites archived paths on extraction, for pulling into a tree laid out differently from the one that pushed.
+#[derive(Clone, Default)]
+pub struct Remap {
+    pub strip: usize,
+    pub prefix: Option<PathBuf>,
+}
+
+impl Remap {
+    pub fn is_identity(&self) -> bool { self.strip == 0 && self.prefix.is_none() }
+
+    /// Drops the first `strip` components and prepends `prefix`, or `None` when nothing is left of the path, like GNU tar.
+    pub fn apply(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let components = path.components().filter(|component| *component != Component::CurDir);
+
+        if components.clone().any(|component| !matches!(component, Component::Normal(_))) {
+            bail!("refusing to extract {}, it points outside the destination", path.display());
+        }
+
+        let rest: PathBuf = components.skip(self.strip).collect();
+
+        if rest.as_os_str().is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(match &self.prefix {
+            Some(prefix) => prefix.join(rest),
+            None => rest,
+        }))
+    }
+}
+
+/// Refuses `target` when a symlink already on disk would carry it out of the destination, checked at the deepest part of it that exists,
+/// and hard links to anything but another relative path. Run before anything touches `target`, since removing it follows the same links.
+fn check_inside(entry: &tar::Entry<'_, &[u8]>, target: &Path, remap: &Remap) -> Result<()> {
+    if entry.header().entry_type().is_hard_link()
+        && entry
+            .link_name()?
+            .is_none_or(|link| link.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)))
+    {
+        bail!("refusing to extract {}, it links outside the destination", target.display());
+    }
+
+    let root = remap.prefix.as_deref().unwrap_or(Path::new("."));
+    fs::create_dir_all(root)?;
+
+    let existing = target
+        .parent()
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .find(|ancestor| !ancestor.as_os_str().is_empty() && ancestor.exists());
+    let resolved = existing.unwrap_or(Path::new(".")).canonicalize()?;
+
+    if !resolved.starts_with(root.canonicalize()?) {
+        bail!("refusing to extract {}, it points outside the destination", target.display());
+    }
+
+    Ok(())
+}
+
+/// Makes sure every restored directory exists afterwards, since an empty cache has no entries to recreate the ones extraction cleared.
+pub fn recreate(dirs: &[String], remap: &Remap) -> Result<()> {
+    for dir in dirs {
+        if let Some(dir) = remap.apply(Path::new(dir))? {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores `dirs`, or just `only`, from the archive, emptying them first unless `incremental`.
+/// Local files that differ from the archive are handled per `on_conflict`, returning how many were kept.
+pub fn restore(tarball: &[u8], dirs: &[String], only: &[String], remap: &Remap, incremental: bool, on_conflict: OnConflict) -> Result<usize> {
+    let restored = match only.is_empty() {
+        true => dirs,
+        false => only,
+    };
+
+    let skip: HashSet<PathBuf> = match on_conflict {
+        OnConflict::Overwrite => HashSet::new(),
+        OnConflict::Skip => conflicts(tarball, only, remap)?.into_iter().collect(),
+        OnConflict::Error => {
+            let conflicts = conflicts(tarball, only, remap)?;
+            if let Some(first) = conflicts.first() {
+                bail!(
+                    "{} local file(s) differ from the cache, starting with {} ([archive] on_conflict = \"error\")",
+                    conflicts.len(),
+                    first.display()
+                );
+            }
+            HashSet::new()
+        }
+    };
+
+    if incremental {
+        sync(tarball, dirs, only, remap, &skip)?;
+        return Ok(skip.len());
+    }
+
+    // clear where each directory will land, a directory stripped away entirely has no root of its own to clear
+    for dir in restored {
+        let Some(root) = remap.apply(Path::new(dir))? else { continue };
+
+        match skip.iter().any(|path| path.starts_with(&root)) {
+            true => clear(&root, &skip)?,
+            false if root.exists() => fs::remove_dir_all(root)?,
+            false => {}
+        }
+    }
+
+    let mut archive = tar::Archive::new(tarball);
+
+    if only.is_empty() && remap.is_identity() && skip.is_empty() {
+        archive.unpack(".")?;
+        recreate(restored, remap)?;
+        return Ok(0);
+    }
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if !only.is_empty() && !only.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+
+        if !skip.is_empty() && remap.apply(&path)?.is_some_and(|target| skip.contains(&target)) {
+            continue;
+        }
+
+        if remap.is_identity() {
+            entry.unpack_in(".")?;
+            continue;
+        }
+
+        let Some(target) = remap.apply(&path)? else { continue };
+        check_inside(&entry, &target, remap)?;
+
+        if let Some(parent) = target.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        entry.unpack(&target).with_context(|| format!("failed to extract {}", target.display()))?;
+    }
+
+    recreate(restored, remap)?;
+    Ok(skip.len())
+}
+
+/// Empties `root` apart from the paths in `skip`, what's under them and the directories leading to them.
+fn clear(root: &Path, skip: &HashSet<PathBuf>) -> Result<()> {
+    for entry in WalkDir::new(root).contents_first(true).into_iter().filter_map(|entry| entry.ok()) {
+        if skip.iter().any(|path| path.starts_with(entry.path()) || entry.path().starts_with(path)) {
+            continue;
+        }
+
+        match entry.file_type().is_dir() {
+            true => fs::remove_dir(entry.path())?,
+            false => fs::remove_file(entry.path())?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Where archived entries would land on something already on disk that differs from them.
+/// A file standing where the entry needs a parent directory counts too.
+pub fn conflicts(tarball: &[u8], only: &[String], remap: &Remap) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+
+    for entry in tar::Archive::new(tarball).entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if entry.header().entry_type().is_dir() || (!only.is_empty() && !only.iter().any(|dir| path.starts_with(dir))) {
+            continue;
+        }
+
+        let Some(target) = remap.apply(&path)? else { continue };
+
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_file() && entry.header().entry_type().is_file() && unchanged(tarball, &entry, &target)? => {}
+            Ok(_) => found.push(target),
+            Err(err) if err.kind() == io::ErrorKind::NotADirectory => found.push(target),
+            Err(_) => {}
+        }
+    }
+
+    Ok(found)
+}
+
+/// Brings `dirs` in line with the archive without clearing them first: only files whose size, mtime or contents differ are written,
+/// and anything on disk the archive doesn't have is removed, so the result matches a full extraction. Paths in `skip` are left as they are.
+pub fn sync(tarball: &[u8], dirs: &[String], only: &[String], remap: &Remap, skip: &HashSet<PathBuf>) -> Result<()> {
+    let mut kept = HashSet::new();
+
+    for entry in tar::Archive::new(tarball).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if !only.is_empty() && !only.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+
+        let Some(target) = remap.apply(&path)? else { continue };
+        check_inside(&entry, &target, remap)?;
+        kept.extend(target.ancestors().filter(|ancestor| !ancestor.as_os_str().is_empty()).map(Path::to_path_buf));
+
+        if skip.contains(&target) {
+            continue;
+        }
+
+        let existing = fs::symlink_metadata(&target).ok();
+        let is_file = entry.header().entry_type().is_file();
+        let is_dir = entry.header().entry_type().is_dir();
+
+        if is_file && existing.as_ref().is_some_and(|metadata| metadata.is_file()) && unchanged(tarball, &entry, &target)? {
+            continue;
+        }
+
+        // a file where a directory was, or the other way round, has to go before the new entry fits
+        match existing {
+            Some(metadata) if metadata.is_dir() && !is_dir => fs::remove_dir_all(&target)?,
+            Some(metadata) if !metadata.is_dir() && is_dir => fs::remove_file(&target)?,
+            Some(metadata) if metadata.is_dir() => continue,
+            _ => {}
+        }
+
+        if let Some(parent) = target.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        entry.unpack(&target).with_context(|| format!("failed to extract {}", target.display()))?;
+    }
+
+    for dir in dirs.iter().filter(|dir| only.is_empty() || only.contains(dir)) {
+        let Some(root) = remap.apply(Path::new(dir))? else { continue };
+
+        // children come before their directory, so a directory is empty by the time its own turn comes
+        for entry in WalkDir::new(&root).contents_first(true).into_iter().filter_map(|entry| entry.ok()) {
+            if kept.contains(entry.path()) || entry.path() == root {
+                continue;
+            }
+
+            match entry.file_type().is_dir() {
+                true => fs::remove_dir(entry.path())?,
+                false => fs::remove_file(entry.path())?,
+            }
+        }
+    }
+
+    recreate(dirs, remap)
+}
+
+/// Whether the file on disk already matches an archived file, by size and mtime, or by contents when the mtime was pinned.
+fn unchanged(tarball: &[u8], entry: &tar::Entry<'_, &[u8]>, target: &Path) -> Result<bool> {
+    let metadata = fs::metadata(target)?;
+    let size = entry.header().size()?;
+
+    if metadata.len() != size {
+        return Ok(false);
+    }
+
+    let mtime = entry.header().mtime()?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
+
+    if mtime == modified && mtime != DETERMINISTIC_MTIME {
+        return Ok(true);
+    }
+
+    let start = entry.raw_file_position() as usize;
+    let archived = tarball.get(start..start + size as usize).unwrap_or_default();
+
+    Ok(fs::read(target)? == archived)
+}
+
+/// Walks the archive's headers before anything on disk is touched, refusing ones past `max_entries` or `max_path_depth`.
+pub fn check_limits(tarball: &[u8], settings: &Archive) -> Result<()> {
+    let max_entries = settings.max_entries.unwrap_or(MAX_ENTRIES);
+    let max_depth = settings.max_path_depth.unwrap_or(MAX_PATH_DEPTH);
+
+    for (count, entry) in tar::Archive::new(tarball).entries()?.enumerate() {
+        if count >= max_entries {
+            bail!("archive has more than {max_entries} entries, refusing to extract it (raise [archive] max_entries if this is expected)");
+        }
+
+        let entry = entry?;
+        let path = entry.path()?;
+        let depth = path.components().filter(|component| *component != Component::CurDir).count();
+
+        if depth > max_depth {
+            bail!(
+                "{} is {depth} levels deep, past the limit of {max_depth}, refusing to extract the archive (raise [archive] max_path_depth if this is expected)",
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tarball() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for (path, content) in [("target/a.txt", "cached"), ("target/b.txt", "other")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    /// Restores into a scratch directory holding a local `target/a.txt` that differs from the cached one.
+    fn restore_over_local(name: &str, incremental: bool, on_conflict: OnConflict) -> (Result<usize>, PathBuf) {
+        let root = std::env::temp_dir().join(format!("volt-restore-{}-{name}-{incremental}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("target/a.txt"), "local edit").unwrap();
+
+        let remap = Remap { strip: 0, prefix: Some(root.clone()) };
+        let result = restore(&tarball(), &["target".to_string()], &[], &remap, incremental, on_conflict);
+
+        (result, root)
+    }
+
+    fn read(root: &Path, path: &str) -> String { fs::read_to_string(root.join(path)).unwrap() }
+
+    #[test]
+    fn overwrite_replaces_differing_files() {
+        for incremental in [false, true] {
+            let (result, root) = restore_over_local("overwrite", incremental, OnConflict::Overwrite);
+
+            assert_eq!(result.unwrap(), 0);
+            assert_eq!(read(&root, "target/a.txt"), "cached");
+            assert_eq!(read(&root, "target/b.txt"), "other");
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn skip_keeps_differing_files() {
+        for incremental in [false, true] {
+            let (result, root) = restore_over_local("skip", incremental, OnConflict::Skip);
+
+            assert_eq!(result.unwrap(), 1);
+            assert_eq!(read(&root, "target/a.txt"), "local edit");
+            assert_eq!(read(&root, "target/b.txt"), "other");
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn error_refuses_before_touching_anything() {
+        for incremental in [false, true] {
+            let (result, root) = restore_over_local("error", incremental, OnConflict::Error);
+
+            assert!(result.unwrap_err().to_string().contains("target/a.txt"));
+            assert_eq!(read(&root, "target/a.txt"), "local edit");
+            assert!(!root.join("target/b.txt").exists());
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn symlinks_cannot_carry_entries_outside() {
+        let root = std::env::temp_dir().join(format!("volt-escape-{}", std::process::id()));
+        let outside = root.join("outside");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&outside).unwrap();
+
+        let mut builder = Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "target/link", &outside).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "target/link/evil", &b"evil"[..]).unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        for incremental in [false, true] {
+            let remap = Remap {
+                strip: 0,
+                prefix: Some(root.join("dest")),
+            };
+            let err = restore(&tarball, &["target".to_string()], &[], &remap, incremental, OnConflict::Overwrite).unwrap_err();
+
+            assert!(err.to_string().contains("outside the destination"));
+            assert!(!outside.join("evil").exists());
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
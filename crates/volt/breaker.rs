@@ -0,0 +1,147 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Times a cooldown doubles at most, however long the server stays down.
+const MAX_DOUBLINGS: u32 = 3;
+
+/// Whether `run` should talk to the server at all, going by the connection failures recorded for it.
+pub enum Gate {
+    Closed,
+    /// The cooldown is over and this run took the probe, so one attempt goes through to find out whether the server is back.
+    HalfOpen,
+    /// Skip the server for this much longer.
+    Open(Duration),
+}
+
+/// Counts connection failures per server in `<home>/state/<server>`, so separate `volt run` processes share them.
+/// After `failures` within `window` the breaker opens for `cooldown`, doubling with every trip that follows and jittered
+/// so a fleet of runners doesn't probe a recovering server in lockstep.
+/// Every read-modify-write holds a lock on the file, and only the run that finds the cooldown over probes, the rest stay out
+/// until it reports back or another cooldown passes without word from it.
+pub struct Breaker {
+    path: PathBuf,
+    failures: u32,
+    window: Duration,
+    cooldown: Duration,
+}
+
+/// `<failures> <window start> <open until> <trips> <probing until>`, all times in unix seconds.
+#[derive(Clone, Default, PartialEq)]
+struct State {
+    failures: u32,
+    since: u64,
+    open_until: u64,
+    trips: u32,
+    probing_until: u64,
+}
+
+impl Breaker {
+    pub fn new(path: PathBuf, failures: u32, window: Duration, cooldown: Duration) -> Self { Breaker { path, failures, window, cooldown } }
+
+    pub fn check(&self) -> Gate {
+        if !self.path.exists() {
+            return Gate::Closed;
+        }
+
+        let gate = self.update(|state, now| match state.open_until {
+            0 => Gate::Closed,
+            until if until > now => Gate::Open(Duration::from_secs(until - now)),
+            // another run already took the probe, this one waits for its answer like the cooldown never ended
+            _ if state.probing_until > now => Gate::Open(Duration::from_secs(state.probing_until - now)),
+            _ => {
+                state.probing_until = now + self.cooldown.as_secs();
+                Gate::HalfOpen
+            }
+        });
+
+        gate.unwrap_or(Gate::Closed)
+    }
+
+    /// Whether the cooldown is running, without taking the probe when it's over.
+    pub fn is_open(&self) -> bool { self.path.exists() && self.update(|state, now| state.open_until > now).unwrap_or(false) }
+
+    pub fn success(&self) {
+        if self.path.exists() {
+            let _ = self.update(|state, _| *state = State::default());
+        }
+    }
+
+    pub fn failure(&self) {
+        let _ = self.update(|state, now| {
+            // failures while open were expected, a failed probe after the cooldown trips again straight away
+            if state.open_until > now {
+                return;
+            }
+            let half_open = state.open_until != 0;
+
+            if !half_open {
+                if state.failures == 0 || now.saturating_sub(state.since) > self.window.as_secs() {
+                    state.failures = 0;
+                    state.since = now;
+                }
+                state.failures += 1;
+            }
+
+            if half_open || state.failures >= self.failures {
+                let backoff = self.cooldown.as_secs() * 2u64.pow(state.trips.min(MAX_DOUBLINGS));
+                state.open_until = now + jitter(backoff);
+                state.trips += 1;
+                state.failures = 0;
+                state.probing_until = 0;
+            }
+        });
+    }
+
+    /// Applies `change` to the state under an exclusive lock on the state file, writing it back when it changed.
+    fn update<T>(&self, change: impl FnOnce(&mut State, u64) -> T) -> std::io::Result<T> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::options().create(true).truncate(false).read(true).write(true).open(&self.path)?;
+        file.lock()?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        let before = parse(&content);
+        let mut state = before.clone();
+        let result = change(&mut state, now());
+
+        if state != before {
+            let line = format!("{} {} {} {} {}", state.failures, state.since, state.open_until, state.trips, state.probing_until);
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(line.as_bytes())?;
+        }
+
+        Ok(result)
+    }
+}
+
+fn parse(content: &str) -> State {
+    let fields: Vec<u64> = content.split_whitespace().filter_map(|field| field.parse().ok()).collect();
+
+    match fields[..] {
+        [failures, since, open_until, trips, probing_until] => State {
+            failures: failures as u32,
+            since,
+            open_until,
+            trips: trips as u32,
+            probing_until,
+        },
+        _ => State::default(),
+    }
+}
+
+fn now() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default() }
+
+/// Spreads `secs` over 75% to 125% of itself.
+fn jitter(secs: u64) -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.subsec_nanos()).unwrap_or_default() as u64;
+    secs * 3 / 4 + nanos % (secs / 2 + 1)
+}
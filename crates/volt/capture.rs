@@ -0,0 +1,59 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+};
+
+/// Lines of combined output kept for the JSON result of a failed run.
+pub const TAIL_LINES: usize = 50;
+
+pub struct Captured {
+    pub status: ExitStatus,
+    pub tail: Vec<String>,
+}
+
+/// Runs `command` with its stdout and stderr piped back through volt a line at a time, prefixed with `prefix`.
+/// With `quiet_stdout` the child's stdout is forwarded to stderr instead, leaving volt's own stdout for machine-readable output.
+pub fn run(command: &mut Command, prefix: &str, quiet_stdout: bool) -> io::Result<Captured> {
+    let mut child = command.stdin(Stdio::inherit()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let tail = Mutex::new(VecDeque::with_capacity(TAIL_LINES));
+
+    thread::scope(|scope| {
+        scope.spawn(|| forward(stdout, prefix, quiet_stdout, &tail));
+        scope.spawn(|| forward(stderr, prefix, true, &tail));
+    });
+
+    Ok(Captured {
+        status: child.wait()?,
+        tail: tail.into_inner().unwrap().into(),
+    })
+}
+
+/// Copies lines from the child to our own stdout or stderr, remembering the last few. Output that isn't UTF-8 is passed through lossily.
+fn forward(source: impl Read, prefix: &str, to_stderr: bool, tail: &Mutex<VecDeque<String>>) {
+    let mut reader = BufReader::new(source);
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\n', '\r']);
+
+        let _ = match to_stderr {
+            true => writeln!(io::stderr().lock(), "{prefix}{text}"),
+            false => writeln!(io::stdout().lock(), "{prefix}{text}"),
+        };
+
+        let mut tail = tail.lock().unwrap();
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(text.to_string());
+
+        line.clear();
+    }
+}
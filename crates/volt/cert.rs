@@ -0,0 +1,177 @@
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+pub struct CertInfo {
+    pub common_name: Option<String>,
+    pub not_after: String,
+    pub days_left: i64,
+}
+
+/// Pulls the subject CN and expiry out of a DER certificate, just enough for `server diag` to report them.
+pub fn inspect(der: &[u8]) -> Option<CertInfo> {
+    let (_, certificate, _) = read(der)?;
+    let (_, tbs, _) = read(certificate)?;
+
+    let mut fields = tbs;
+    let mut next = || {
+        let (tag, contents, rest) = read(fields)?;
+        fields = rest;
+        Some((tag, contents))
+    };
+
+    // the explicit version tag is optional, the serial always follows it
+    let (tag, _) = next()?;
+    if tag == 0xa0 {
+        next()?;
+    }
+
+    let (_signature, _issuer, (_, validity), (_, subject)) = (next()?, next()?, next()?, next()?);
+
+    let (_, _not_before, validity) = read(validity)?;
+    let (tag, not_after, _) = read(validity)?;
+    let (year, month, day, hour, minute) = parse_time(tag, not_after)?;
+
+    let expires = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+
+    Some(CertInfo {
+        common_name: common_name(subject),
+        not_after: format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02} UTC"),
+        days_left: (expires - now).div_euclid(86_400),
+    })
+}
+
+/// Splits one DER element off the front of `data`, as (tag, contents, rest).
+fn read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+
+    let length = match first & 0x80 {
+        0 => first as usize,
+        _ => {
+            let count = (first & 0x7f) as usize;
+            if count > 4 || data.len() < count {
+                return None;
+            }
+            let length = data[..count].iter().fold(0, |length, &byte| length << 8 | byte as usize);
+            data = &data[count..];
+            length
+        }
+    };
+
+    (data.len() >= length).then(|| (tag, &data[..length], &data[length..]))
+}
+
+fn common_name(mut subject: &[u8]) -> Option<String> {
+    while let Some((_, set, rest)) = read(subject) {
+        subject = rest;
+
+        let (_, attribute, _) = read(set)?;
+        let (_, oid, value) = read(attribute)?;
+
+        if oid == COMMON_NAME {
+            let (_, value, _) = read(value)?;
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+
+    None
+}
+
+/// Reads a UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`).
+fn parse_time(tag: u8, time: &[u8]) -> Option<(i64, i64, i64, i64, i64)> {
+    let time = std::str::from_utf8(time).ok()?;
+    let number = |range: std::ops::Range<usize>| time.get(range)?.parse::<i64>().ok();
+
+    let (year, rest) = match tag {
+        0x17 => (number(0..2).map(|year| if year < 50 { 2000 + year } else { 1900 + year })?, 2),
+        0x18 => (number(0..4)?, 4),
+        _ => return None,
+    };
+
+    Some((year, number(rest..rest + 2)?, number(rest + 2..rest + 4)?, number(rest + 4..rest + 6)?, number(rest + 6..rest + 8)?))
+}
+
+/// Days since the unix epoch for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Lowercase hex sha256 of a DER certificate, the form `pinned_cert_sha256` is compared in.
+pub fn fingerprint(der: &[u8]) -> String { Sha256::digest(der).iter().map(|byte| format!("{byte:02x}")).collect() }
+
+/// Accepts `AB:CD:...` or plain hex in any case, returning the canonical lowercase form.
+pub fn normalize_pin(pin: &str) -> Option<String> {
+    let pin: String = pin.trim().chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase();
+    (pin.len() == 64 && pin.chars().all(|c| c.is_ascii_hexdigit())).then_some(pin)
+}
+
+/// Hosts with a pinned leaf certificate are trusted on its fingerprint alone, everything else goes through the system CA store.
+#[derive(Debug)]
+pub struct PinnedVerifier {
+    pins: HashMap<String, Vec<String>>,
+    roots: Arc<WebPkiServerVerifier>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedVerifier {
+    pub fn new(pins: HashMap<String, Vec<String>>, provider: Arc<CryptoProvider>) -> anyhow::Result<Self> {
+        let mut store = RootCertStore::empty();
+        store.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+
+        let roots = WebPkiServerVerifier::builder_with_provider(Arc::new(store), provider.clone()).build()?;
+        Ok(Self { pins, roots, provider })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], server_name: &ServerName<'_>, ocsp: &[u8], now: UnixTime) -> Result<ServerCertVerified, Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_ascii_lowercase(),
+            ServerName::IpAddress(ip) => IpAddr::from(*ip).to_string(),
+            _ => return self.roots.verify_server_cert(end_entity, intermediates, server_name, ocsp, now),
+        };
+
+        let Some(pins) = self.pins.get(&host) else {
+            return self.roots.verify_server_cert(end_entity, intermediates, server_name, ocsp, now);
+        };
+
+        let actual = fingerprint(end_entity);
+        match pins.contains(&actual) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(Error::General(format!("certificate for {host} has sha256 {actual}, which doesn't match its pinned_cert_sha256"))),
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> { self.provider.signature_verification_algorithms.supported_schemes() }
+}
@@ -1,28 +1,238 @@
+mod archive;
+mod breaker;
+mod capture;
+mod cert;
 mod colors;
+mod encryption;
+mod failure;
+mod frames;
 mod hash;
 mod helpers;
+mod local_cache;
+mod pattern;
+mod throttle;
 
 #[path = "config/config.rs"]
 mod config;
 
 use anyhow::{Context, Result, anyhow};
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
-use config::{Route, VoltConfig};
-use indicatif::{ProgressBar, ProgressStyle};
+use breaker::{Breaker, Gate};
+use config::{HashOver, Init, Route, VoltConfig};
+use failure::Failure;
+use local_cache::ArchiveCache;
+use futures::{StreamExt, stream::FuturesUnordered};
+use indicatif::ProgressBar;
 use inquire::{Confirm, CustomType, Password, PasswordDisplayMode, Text, validator::Validation};
-use reqwest::{Client, StatusCode};
+use reqwest::{Body, Client, StatusCode, header::HeaderMap};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{OnceCell, Semaphore},
+};
 
 use std::{
-    fs,
-    path::PathBuf,
-    process::{self, Command, ExitCode},
-    time::{Duration, Instant},
+    collections::BTreeMap,
+    fs::{self, File, TryLockError},
+    io::Read,
+    path::{Path, PathBuf},
+    process::{self, Command, ExitCode, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Must match the server's protocol version for the two to interoperate.
+const PROTOCOL_VERSION: u32 = 1;
+
 struct Services {
     pub config: VoltConfig,
     pub client: Client,
+    version_checked: OnceCell<()>,
+    summary: Mutex<Summary>,
+}
+
+/// What the `[ui] summary_line` after push, pull and run reports, noted down as each step finds out.
+#[derive(Default)]
+struct Summary {
+    status: Option<&'static str>,
+    pull: Option<&'static str>,
+    push: Option<&'static str>,
+    bytes: Option<usize>,
+    key: Option<String>,
+}
+
+/// The loaded config plus what was resolved around it, with server tokens masked.
+#[derive(Serialize)]
+struct ResolvedConfig<'a> {
+    #[serde(flatten)]
+    config: &'a VoltConfig,
+    path: &'a Path,
+    home: PathBuf,
+    profile: Option<&'a str>,
+    servers: BTreeMap<&'a str, config::Server>,
+}
+
+/// Where `volt run` sends the wrapped command's output.
+#[derive(Clone)]
+enum RunOutput {
+    /// The command gets the terminal directly, the interactive default.
+    Inherit,
+    /// Lines pass through volt, prefixed, and `json` ends the run with a `RunReport` on stdout.
+    Captured { prefix: String, json: bool },
+}
+
+/// The `volt run --json` result, correlating the build with what happened to the cache around it.
+#[derive(Serialize)]
+struct RunReport {
+    success: bool,
+    exit_code: Option<i32>,
+    duration_ms: u128,
+    pulled: bool,
+    /// `None` when no push was attempted, or it was handed to a background process.
+    pushed: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    output_tail: Vec<String>,
+}
+
+/// `volt server bench` pushes under this prefix plus random hex, ids no project is ever given.
+const BENCH_ID_PREFIX: &str = "00000000-0000-4000-8000-";
+
+/// Files larger than this are left out of dictionary training, they gain little from a shared dictionary.
+const DICT_MAX_SAMPLE: u64 = 128 * 1024;
+const DICT_SAMPLE_BUDGET: usize = 64 * 1024 * 1024;
+const DICT_MIN_SAMPLES: usize = 8;
+
+/// How many profiles `pull --all` restores at the same time.
+const PARALLEL_PULLS: usize = 4;
+
+/// How many mirrors a push uploads to at the same time, unless `[network] mirror_concurrency` says otherwise.
+const MIRROR_CONCURRENCY: usize = 2;
+
+/// How many servers `server test-all` checks at the same time.
+const PARALLEL_TESTS: usize = 8;
+
+/// Bytes per part when `[network] upload_concurrency` splits a push, archives no larger go up in one request.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// A compressed archive on its way to a server.
+struct Upload<'a> {
+    hash: &'a str,
+    archive: &'a bytes::Bytes,
+    digest: String,
+    uncompressed_size: usize,
+    file_count: usize,
+}
+
+impl Upload<'_> {
+    /// The headers that tell the server what the archive is, sent with the body or with the request that completes a chunked push.
+    fn describe(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request
+            .header("X-Volt-Hash", self.hash)
+            .header("X-Volt-Hash-Algorithm", hash::ALGORITHM)
+            .header("X-Volt-Compression", "zstd")
+            .header("X-Volt-Content-Sha256", &self.digest)
+            .header("Idempotency-Key", self.hash)
+            .header("X-Volt-Uncompressed-Size", self.uncompressed_size)
+            .header("X-Volt-File-Count", self.file_count)
+    }
+}
+
+/// What a pull ended up doing, so callers can report it their own way.
+enum Pulled {
+    UpToDate,
+    Local,
+    /// Bytes transferred, and how long ago the server stored the archive when it said.
+    Downloaded(usize, Option<Duration>),
+    Missing,
+    /// The server's archive is older than `--max-age`.
+    Stale(Duration),
+}
+
+#[derive(Deserialize)]
+struct ServerVersion {
+    version: String,
+    protocol: u32,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    entries: u64,
+    bytes: u64,
+    disk: Option<DiskSpace>,
+    limits: BTreeMap<String, Option<u64>>,
+    namespaces: BTreeMap<String, NamespaceUsage>,
+}
+
+#[derive(Deserialize)]
+struct DiskSpace {
+    free: u64,
+    total: u64,
+}
+
+#[derive(Deserialize)]
+struct NamespaceUsage {
+    used: u64,
+    quota: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct LogLine {
+    timestamp: u64,
+    method: String,
+    uri: String,
+    status: u16,
+    duration_ms: u128,
+    #[serde(default)]
+    client: Option<String>,
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (hours, minutes, seconds) = (self.timestamp / 3600 % 24, self.timestamp / 60 % 60, self.timestamp % 60);
+        let status = match self.status {
+            200..=399 => self.status.to_string().green(),
+            _ => self.status.to_string().red(),
+        };
+
+        write!(f, "{hours:02}:{minutes:02}:{seconds:02} {} {} {status} {}ms", self.method.bright_cyan(), self.uri, self.duration_ms)?;
+
+        match &self.client {
+            Some(client) => write!(f, " {}", client.dimmed()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The token a request was made with, as `/whoami` describes it.
+#[derive(Deserialize)]
+struct Identity {
+    name: Option<String>,
+    admin: bool,
+    namespace: Option<String>,
+}
+
+/// What `/verify` found reading a stored entry back.
+#[derive(Deserialize)]
+struct Verified {
+    ok: bool,
+    bytes: u64,
+    entries: Option<u64>,
+    unpacked: Option<u64>,
+    sha256: String,
+    detail: Option<String>,
+}
+
+/// What `/invalidate` deleted.
+#[derive(Deserialize)]
+struct Invalidated {
+    removed: usize,
+    ids: Vec<String>,
 }
 
 #[derive(Parser)]
@@ -30,22 +240,106 @@ struct Services {
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
-    /// Path to load config
+    /// Path to load config, `-` to read it from stdin or an https URL to fetch it
     #[arg(short, long, default_value = "volt.toml")]
     path: PathBuf,
+    /// Also fetch the config from a plain http URL, where anyone on the way can rewrite it
+    #[arg(long)]
+    allow_insecure_config: bool,
+    /// Override the volt state directory (defaults to ~/.volt, or $XDG_DATA_HOME/volt without a home directory)
+    #[arg(long, env = "VOLT_HOME", global = true)]
+    config_dir: Option<PathBuf>,
+    /// Cap upload bandwidth in bytes per second
+    #[arg(long, global = true)]
+    max_upload_rate: Option<u64>,
+    /// Cap download bandwidth in bytes per second
+    #[arg(long, global = true)]
+    max_download_rate: Option<u64>,
+    /// Leave paths matching this glob out of the hash and archive, on top of `exclude` in volt.toml (repeatable)
+    #[arg(long, global = true)]
+    exclude: Vec<String>,
+    /// Use the cache key and settings of a `[profiles.<name>]` entry
+    #[arg(long, env = "VOLT_PROFILE", global = true)]
+    profile: Option<String>,
+    /// Print extra detail, such as the compression settings a push resolved to
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Create or validate the config and servers, then exit without running a command
+    #[arg(long)]
+    init_only: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Push cache to server
     #[command(visible_alias = "get", visible_alias = "P")]
-    Push,
+    Push {
+        /// Archive this directory instead of the configured cache directories (repeatable)
+        #[arg(long)]
+        from: Vec<String>,
+    },
     /// Pull cache from server
     #[command(visible_alias = "set", visible_alias = "p")]
-    Pull,
+    Pull {
+        /// Exit with code 5 when the server has no cache for this project
+        #[arg(long)]
+        require_hit: bool,
+        /// Only restore these cache directories (repeatable)
+        #[arg(long)]
+        only: Vec<String>,
+        /// Pull every profile's cache concurrently
+        #[arg(long, visible_alias = "parallel", conflicts_with_all = ["only", "download", "strip_components", "prefix", "tag"])]
+        all: bool,
+        /// Save the archive to a file instead of extracting it
+        #[arg(long)]
+        download: Option<PathBuf>,
+        /// Archive format to download
+        #[arg(long, value_enum, default_value_t = ArchiveFormat::Zstd, requires = "download")]
+        format: ArchiveFormat,
+        /// Strip this many leading path components from each entry, like `tar --strip-components`
+        #[arg(long, default_value_t = 0, conflicts_with = "download")]
+        strip_components: usize,
+        /// Extract every entry under this directory
+        #[arg(long, conflicts_with = "download")]
+        prefix: Option<PathBuf>,
+        /// Treat a cache stored longer ago than this as a miss (e.g. 90m, 12h, 7d)
+        #[arg(long, value_parser = helpers::parse_age, conflicts_with = "download")]
+        max_age: Option<Duration>,
+        /// Pull the cache a server-side tag points at instead of this project's
+        #[arg(long, conflicts_with = "all")]
+        tag: Option<String>,
+    },
     /// Run build with caching
     #[command(visible_alias = "start", visible_alias = "r")]
-    Run,
+    Run {
+        /// Skip the build and push when the hash matches the last successful run
+        #[arg(long)]
+        only_if_changed: bool,
+        /// Fail the run when the cache push fails, instead of only warning
+        #[arg(long)]
+        require_push: bool,
+        /// Push the cache even when the build fails, still exiting with the failure (partial outputs get cached too)
+        #[arg(long)]
+        keep_going: bool,
+        /// Pipe the command's output through volt instead of handing it the terminal
+        #[arg(long)]
+        capture: bool,
+        /// Prefix every captured output line with this text (implies --capture)
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Print a JSON result on stdout, with the output tail on failure (implies --capture)
+        #[arg(long)]
+        json: bool,
+        /// Print the server, cache key, directories and command the run would use, without pulling, building or pushing
+        #[arg(long)]
+        dry_run: bool,
+        /// Run every profile's build in turn, only restoring the ones whose cache the server already has
+        #[arg(long, conflicts_with = "dry_run")]
+        all_profiles: bool,
+        /// Restore the cache and stop, without building or pushing, e.g. to warm a workspace or image layer
+        #[arg(long, conflicts_with_all = ["dry_run", "all_profiles", "only_if_changed", "require_push", "keep_going", "capture", "prefix", "json"])]
+        cache_only: bool,
+    },
     /// Check remote cache status
     #[command(visible_alias = "test", visible_alias = "c")]
     Check,
@@ -55,6 +349,131 @@ enum Commands {
         #[command(subcommand)]
         command: Option<Server>,
     },
+    /// Project volt_id management
+    Id {
+        #[command(subcommand)]
+        command: Id,
+    },
+    /// Human-friendly aliases for volt_ids, stored on the server
+    Tag {
+        #[command(subcommand)]
+        command: Tag,
+    },
+    /// Prune the local archive cache to [local_cache] max_bytes and max_age_days (clears it when the cache is disabled)
+    GcLocal,
+    /// Compression dictionary management
+    Dict {
+        #[command(subcommand)]
+        command: Dict,
+    },
+    /// Ask the server to prefetch caches from its upstream
+    Warm {
+        /// volt_ids to prefetch, optionally pinned as `<volt_id>:<hash>` (defaults to this project)
+        ids: Vec<String>,
+    },
+    /// Delete every server entry whose volt_id (or tag, with --tags) matches a glob pattern
+    Invalidate {
+        /// Glob over volt_ids, `*` and `?` are wildcards, e.g. `ci-*`
+        pattern: String,
+        /// Match tag names instead, deleting the tags and the caches they point at
+        #[arg(long)]
+        tags: bool,
+        /// Only delete entries pushed under this namespace
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Confirm deleting the matching entries
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Pull any cache by volt_id or tag into a scratch directory for inspection, leaving the working tree alone
+    Replay {
+        /// volt_id or tag of the cache to fetch
+        id: String,
+        /// Where to extract it (defaults to a volt-replay-<id> directory under the temp dir, cleared first)
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+    },
+    /// Inspect the loaded configuration
+    Config {
+        #[command(subcommand)]
+        command: Config,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ArchiveFormat {
+    /// The stored tar.zst archive, as-is
+    Zstd,
+    /// A standard tar.gz, transcoded by the server
+    Targz,
+}
+
+/// Which requests a server in maintenance turns away.
+#[derive(Clone, Copy, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MaintenanceMode {
+    /// Serve everything again
+    Off,
+    /// Refuse pushes, keep serving pulls
+    Push,
+    /// Refuse pulls as well
+    All,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Maintenance {
+    mode: MaintenanceMode,
+    message: Option<String>,
+    retry_after: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Id {
+    /// Generate a new volt_id, detaching the project from its current cache
+    #[command(visible_alias = "regen")]
+    Regenerate {
+        /// Confirm replacing the existing volt_id
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Tag {
+    /// Point a tag at a volt_id
+    Set {
+        /// Name of the tag, e.g. main-latest or release-1.2
+        name: String,
+        /// volt_id to tag (defaults to this project)
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// List the server's tags
+    #[command(visible_alias = "ls")]
+    List,
+}
+
+#[derive(Subcommand)]
+enum Dict {
+    /// Train a zstd dictionary from the current cache directories
+    Train {
+        /// Where to write the dictionary (defaults to `[compression] dictionary`, then volt.dict)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Maximum dictionary size in bytes
+        #[arg(long, default_value_t = 112_640)]
+        size: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum Config {
+    /// Print the effective configuration after includes, profiles and flags
+    Show {
+        /// Print as JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -73,57 +492,498 @@ enum Server {
     List,
     /// Test connection to the server
     #[command(visible_alias = "status", visible_alias = "t")]
-    Test,
+    Test {
+        /// Seconds to wait for the server before giving up
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+        /// Keep retrying for up to this many seconds while the server is unreachable or still starting
+        #[arg(long, value_name = "SECS")]
+        wait: Option<u64>,
+    },
+    /// Check every configured server at once and report reachability, latency, version and auth
+    TestAll {
+        /// Seconds to wait for each server before marking it unreachable
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
     #[command(visible_alias = "i")]
     /// Display detailed information about a server
     Info {
         /// Name of the server to inspect
         name: String,
     },
+    /// Show recent requests handled by a server
+    Logs {
+        /// Name of the server to read logs from
+        name: String,
+        /// Number of recent lines to show
+        #[arg(long, default_value_t = 50)]
+        tail: usize,
+        /// Keep streaming new lines as they arrive
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Run a throwaway volt-server on a free local port until Ctrl-C
+    Local,
+    /// Set a server's token without prompting, creating the server when --address is given
+    #[command(group(ArgGroup::new("source").required(true).args(["from_stdin", "token_file"])))]
+    SetToken {
+        /// Name of the server to update or create
+        name: String,
+        /// Read the token from stdin
+        #[arg(long)]
+        from_stdin: bool,
+        /// Read the token from this file
+        #[arg(long, value_name = "PATH")]
+        token_file: Option<PathBuf>,
+        /// host:port for a server that doesn't exist yet
+        #[arg(long)]
+        address: Option<String>,
+        /// Connect to the new server over TLS
+        #[arg(long, requires = "address")]
+        tls: bool,
+        /// Test the connection once the token is saved
+        #[arg(long)]
+        test: bool,
+    },
+    /// Add a server from a full `[tls://][token@]host:port[#pin]` line, without prompting
+    Import {
+        /// Name for the new server
+        name: String,
+        /// Connection string, as a server file holds it
+        url: String,
+        /// Replace a server that already has this name
+        #[arg(long)]
+        force: bool,
+        /// Test the connection once the server is saved
+        #[arg(long)]
+        test: bool,
+    },
+    /// Push a random payload to a server and pull it back, reporting throughput both ways
+    Bench {
+        /// Name of the server to benchmark
+        name: String,
+        /// Size of the payload, e.g. 512kb, 100mb or 2gb
+        #[arg(long, default_value = "100mb", value_parser = helpers::parse_size)]
+        size: usize,
+    },
+    /// Replace a server's token with a new one, registered before the old one is revoked
+    RotateToken {
+        /// Name of the server whose token to rotate
+        name: String,
+        /// Admin token to register and revoke with, when the server's own token isn't an admin token
+        #[arg(long)]
+        admin_token_file: Option<PathBuf>,
+    },
+    /// Show or set the server used wherever no project config picks one, kept in ~/.volt/default
+    Default {
+        /// Name of the server to make the default
+        name: Option<String>,
+        /// Forget the default server
+        #[arg(long, conflicts_with = "name")]
+        unset: bool,
+    },
+    /// Make a server the project default by rewriting settings.server
+    SetDefault {
+        /// Name of the server to use by default
+        name: String,
+    },
+    /// Step through DNS, TCP, TLS and HTTP to find where a connection breaks
+    Diag {
+        /// Name of the server to diagnose
+        name: String,
+    },
+    /// Have the server read a stored entry back and check it decompresses cleanly, without downloading it
+    Verify {
+        /// volt_id or tag to verify (defaults to this project)
+        id: Option<String>,
+        /// Also require the stored archive to have this sha256, as printed by an earlier verify
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+    /// Show or switch a server's maintenance mode, which lasts until it restarts (needs an admin token)
+    Maintenance {
+        /// Name of the server
+        name: String,
+        /// Mode to switch to, shows the current one when left out
+        #[arg(value_enum)]
+        mode: Option<MaintenanceMode>,
+        /// Message refused requests get back
+        #[arg(long, requires = "mode")]
+        message: Option<String>,
+        /// Seconds clients are told to wait before trying again
+        #[arg(long, requires = "mode")]
+        retry_after: Option<u64>,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<ExitCode> {
-    let cli = Cli::parse();
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{} {err:#}", colors::FAIL);
+            ExitCode::from(Failure::of(&err).map_or(1, Failure::code))
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<ExitCode> {
+    let mut config = VoltConfig::new(cli.path, cli.config_dir);
+    config.inline = helpers::read_inline_config(&config.path, cli.allow_insecure_config).await.context(Failure::Config)?;
+
+    // server management works anywhere, outside a project it runs on the user-level settings alone
+    let standalone = matches!(cli.command, Some(Commands::Server { .. })) && config.inline.is_none() && !config.path.exists();
+
+    let mut config = match standalone {
+        true => config,
+        false => match config.init().context(Failure::Config)? {
+            Init::Loaded(config) => *config,
+            Init::Created => {
+                println!("{} Created a new config - please fill it out.", colors::BOLT);
+                return Ok(ExitCode::SUCCESS);
+            }
+            Init::NeedsEditing => {
+                eprintln!("😅 Configuration matches default template - please edit it.");
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+    };
+
+    if !standalone {
+        // on stderr, so commands with machine-readable output can be piped
+        eprintln!("📝 Loaded Volt Config\n🚀 Volt is ready!");
+    }
+
+    if config.settings.server.is_empty()
+        && let Some(name) = config.user_default_server()?
+    {
+        config.settings.server = name;
+    }
+
+    if let Some(profile) = &cli.profile {
+        config = config.with_profile(profile)?;
+    }
+
+    config.settings.exclude.extend(cli.exclude.iter().cloned());
+    config.cli_exclude = cli.exclude.clone();
+    config.verbose = cli.verbose;
+
+    if let Some(rate) = cli.max_upload_rate {
+        config.network.max_upload_bytes_per_sec = Some(rate);
+    }
+
+    if let Some(rate) = cli.max_download_rate {
+        config.network.max_download_bytes_per_sec = Some(rate);
+    }
+
+    let client = helpers::create_client(&mut config).context(Failure::Config)?;
+
+    if cli.init_only {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // the server resolves a tag wherever it expects a volt_id
+    if let Some(Commands::Pull { tag: Some(tag), .. }) = &cli.command {
+        config.volt_id = tag.clone();
+    }
 
-    let mut config = VoltConfig::new(cli.path).init()?;
-    let client = helpers::create_client(&mut config)?;
     let mut services = Services::new(config, client);
 
-    match cli.command.unwrap_or(Commands::Run) {
-        Commands::Push => services.push_cache().await?,
-        Commands::Pull => services.pull_cache().await?,
-        Commands::Run => services.run_build().await?,
+    let command = cli.command.unwrap_or(Commands::Run {
+        only_if_changed: false,
+        require_push: false,
+        keep_going: false,
+        capture: false,
+        prefix: None,
+        json: false,
+        dry_run: false,
+        all_profiles: false,
+        cache_only: false,
+    });
+
+    let action = match &command {
+        Commands::Push { .. } => Some("push"),
+        Commands::Pull { all: false, download: None, .. } => Some("pull"),
+        Commands::Run {
+            dry_run: false, all_profiles: false, ..
+        } => Some("run"),
+        _ => None,
+    };
+
+    let start = Instant::now();
+    let result = dispatch(&mut services, command).await;
+
+    if let Some(action) = action
+        && services.config.ui.summary_line
+    {
+        services.print_summary(action, start.elapsed(), &result);
+    }
+
+    result
+}
+
+async fn dispatch(services: &mut Services, command: Commands) -> Result<ExitCode> {
+    let code = match command {
+        Commands::Push { from } => services.push_from(from).await?,
+        Commands::Pull { all: true, require_hit, .. } => services.pull_all(require_hit).await?,
+        Commands::Pull {
+            download: None,
+            require_hit,
+            only,
+            strip_components,
+            prefix,
+            max_age,
+            ..
+        } => services.pull_cache(require_hit, &only, &archive::Remap { strip: strip_components, prefix }, max_age).await?,
+        Commands::Pull { download: Some(output), format, .. } => services.download_cache(&output, format).await?,
+        Commands::Run {
+            only_if_changed,
+            require_push,
+            keep_going,
+            dry_run: true,
+            ..
+        } => {
+            services.config.read_only = true;
+            services.run_plan(only_if_changed, require_push, keep_going)?
+        }
+        Commands::Run { cache_only: true, .. } => services.run_cache_only().await?,
+        Commands::Run {
+            only_if_changed,
+            require_push,
+            keep_going,
+            capture,
+            prefix,
+            json,
+            all_profiles,
+            ..
+        } => {
+            let output = match capture || prefix.is_some() || json {
+                true => RunOutput::Captured {
+                    prefix: prefix.unwrap_or_default(),
+                    json,
+                },
+                false => RunOutput::Inherit,
+            };
+            match all_profiles {
+                true => services.run_all_profiles(only_if_changed, require_push, keep_going, output).await?,
+                false => services.run_build(only_if_changed, require_push, keep_going, output).await?,
+            }
+        }
         Commands::Check => services.check_status().await?,
         Commands::Server { command } => match command.unwrap_or(Server::New) {
             Server::New => services.server_add().await?,
             Server::List => services.server_list().await?,
-            Server::Test => services.server_test().await?,
+            Server::Test { timeout, wait } => services.server_test(Duration::from_secs(timeout), wait.map(Duration::from_secs)).await?,
+            Server::TestAll { timeout } => services.server_test_all(Duration::from_secs(timeout)).await?,
             Server::Remove { name } => services.server_remove(&name).await?,
             Server::Info { name } => services.server_info(&name).await?,
+            Server::Logs { name, tail, follow } => services.server_logs(&name, tail, follow).await?,
+            Server::Diag { name } => services.server_diag(&name).await?,
+            Server::SetDefault { name } => services.server_set_default(&name)?,
+            Server::Default { name, unset } => services.server_user_default(name.as_deref(), unset)?,
+            Server::Import { name, url, force, test } => services.server_import(&name, &url, force, test).await?,
+            Server::Bench { name, size } => services.server_bench(&name, size).await?,
+            Server::RotateToken { name, admin_token_file } => services.server_rotate_token(&name, admin_token_file.as_deref()).await?,
+            Server::Verify { id, sha256 } => services.server_verify(id, sha256.as_deref()).await?,
+            Server::Maintenance { name, mode, message, retry_after } => services.server_maintenance(&name, mode, message, retry_after).await?,
+            Server::SetToken {
+                name,
+                from_stdin,
+                token_file,
+                address,
+                tls,
+                test,
+            } => services.server_set_token(&name, from_stdin, token_file.as_deref(), address, tls, test).await?,
+            Server::Local => services.server_local().await?,
+        },
+        Commands::Id { command } => match command {
+            Id::Regenerate { yes } => services.id_regenerate(yes)?,
+        },
+        Commands::Tag { command } => match command {
+            Tag::Set { name, id } => services.tag_set(&name, id.as_deref()).await?,
+            Tag::List => services.tag_list().await?,
+        },
+        Commands::GcLocal => services.gc_local()?,
+        Commands::Dict { command } => match command {
+            Dict::Train { output, size } => services.dict_train(output.as_deref(), size)?,
+        },
+        Commands::Warm { ids } => services.warm(&ids).await?,
+        Commands::Replay { id, output_dir } => services.replay(&id, output_dir).await?,
+        Commands::Invalidate { pattern, tags, namespace, yes } => services.invalidate(&pattern, tags, namespace.as_deref(), yes).await?,
+        Commands::Config { command } => match command {
+            Config::Show { json } => services.config_show(json)?,
         },
     };
 
-    Ok(ExitCode::SUCCESS)
+    Ok(code)
 }
 
 impl Services {
-    pub fn new(config: VoltConfig, client: Client) -> Self { Self { config, client } }
+    pub fn new(config: VoltConfig, client: Client) -> Self {
+        Self {
+            config,
+            client,
+            version_checked: OnceCell::new(),
+            summary: Mutex::default(),
+        }
+    }
+
+    fn note(&self, update: impl FnOnce(&mut Summary)) { update(&mut self.summary.lock().unwrap()) }
+
+    /// Prints the `volt: key=value ...` line log scrapers pick cache metrics out of, on stderr like the rest of the status output.
+    fn print_summary(&self, action: &str, elapsed: Duration, result: &Result<ExitCode>) {
+        let summary = self.summary.lock().unwrap();
+        let status = match (result, action) {
+            (Err(_), _) => "error",
+            (Ok(_), "pull") => summary.pull.unwrap_or("ok"),
+            (Ok(_), "push") => summary.push.unwrap_or("ok"),
+            (Ok(_), _) => summary.status.unwrap_or("ok"),
+        };
+
+        let mut line = format!("volt: action={action} status={status}");
+
+        if action == "run" {
+            line += &format!(" pull={} push={}", summary.pull.unwrap_or("none"), summary.push.unwrap_or("none"));
+        }
+        if let Some(bytes) = summary.bytes {
+            line += &format!(" bytes={bytes}");
+        }
+        if let Some(key) = &summary.key {
+            line += &format!(" key={}", &key[..key.len().min(12)]);
+        }
+
+        eprintln!("{line} elapsed_ms={}", elapsed.as_millis());
+    }
+
+    /// Compares protocol versions once per invocation, warning (or failing under `strict_version`) on skew.
+    pub async fn check_version(&self) -> Result<()> {
+        self.version_checked
+            .get_or_try_init(|| async {
+                let (url, _) = self.config.get_server(Route::Version)?;
+
+                let response = match self.client.get(&url).send().await {
+                    Ok(next) => next,
+                    Err(_) => return Ok(()),
+                };
+
+                if !self.config.hashing.ignore_mtime {
+                    helpers::warn_clock_skew(response.headers());
+                }
+
+                let problem = match response.status().is_success() {
+                    false => "server does not report a protocol version, it may be outdated".to_string(),
+                    true => match response.json::<ServerVersion>().await {
+                        Ok(server) if server.protocol == PROTOCOL_VERSION => return Ok(()),
+                        Ok(server) => format!("server v{} speaks protocol {}, client expects {PROTOCOL_VERSION}", server.version, server.protocol),
+                        Err(_) => "server returned an unreadable version response".to_string(),
+                    },
+                };
+
+                if self.config.settings.strict_version {
+                    return Err(anyhow!(problem));
+                }
+
+                eprintln!("{} Version mismatch: {problem}", colors::WARN);
+                Ok(())
+            })
+            .await
+            .map(|_| ())
+    }
 
     pub async fn check_hash(&self, hash: &str) -> Result<bool> {
+        let cached = self.config.get_check_cache()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        // a recent answer for the same hash is reused for as long as the server's Cache-Control allows
+        if let Ok(entry) = fs::read_to_string(&cached)
+            && let [cached_hash, exists, expires] = entry.split_whitespace().collect::<Vec<_>>()[..]
+            && cached_hash == hash
+            && expires.parse::<u64>().is_ok_and(|expires| expires > now)
+        {
+            return Ok(exists == "1");
+        }
+
         let (url, header) = self.config.get_server(Route::Check)?;
+        let etag = format!("\"{}:{hash}\"", hash::ALGORITHM);
+
+        let request = self
+            .client
+            .get(&url)
+            .headers(header)
+            .header("X-Volt-Hash", hash)
+            .header("X-Volt-Hash-Algorithm", hash::ALGORITHM)
+            .header("If-None-Match", etag);
 
-        let response = match self.client.get(&url).header("Authorization", header).header("X-Volt-Hash", hash).send().await {
+        let response = match request.send().await {
             Ok(next) => next,
             Err(_) => return Ok(false),
         };
 
-        Ok(response.status() == StatusCode::NOT_MODIFIED)
+        let exists = response.status() == StatusCode::NOT_MODIFIED;
+
+        if let Some(max_age) = helpers::max_age(response.headers()).filter(|max_age| *max_age > 0) {
+            let _ = fs::write(&cached, format!("{hash} {} {}", exists as u8, now + max_age));
+        }
+
+        Ok(exists)
     }
 
-    pub async fn check_status(&self) -> Result<ExitCode> {
+    /// `exclude` plus every file over `[cache] max_file_bytes` in the hashed or cached directories,
+    /// each as its own full-path pattern so the key and the archive leave out the same files.
+    fn exclude(&self) -> (Vec<String>, Vec<(PathBuf, u64)>) {
+        let mut exclude = self.config.settings.exclude.clone();
+        let Some(max_bytes) = self.config.cache.max_file_bytes else { return (exclude, Vec::new()) };
+
+        let mut dirs = self.config.settings.cache.clone();
+        dirs.extend(self.config.settings.hash.iter().flatten().filter(|dir| !self.config.settings.cache.contains(dir)).cloned());
+
+        let oversized = hash::oversized(&dirs, &exclude, max_bytes);
+        // a leading `./` keeps a top-level file's pattern from matching that name anywhere in the tree
+        exclude.extend(oversized.iter().map(|(path, _)| format!("./{}", path.to_string_lossy().trim_start_matches("./"))));
+
+        (exclude, oversized)
+    }
+
+    /// Hashes the `hash` directories, or the cache directories when none are set, into this project's cache key.
+    fn compute_hash(&self) -> Result<String> {
         let hash_dirs = self.config.settings.hash.as_ref().unwrap_or(&self.config.settings.cache);
-        let hash = hash::compute_cache(hash_dirs)?;
+        let ignore_mtime = self.config.hashing.ignore_mtime;
+
+        // per-file hashes only carry over between runs hashing the same way
+        let sidecar = match (self.config.settings.hash_cache, ignore_mtime) {
+            (true, false) => Some(self.config.get_hash_cache()?),
+            (true, true) => Some(self.config.get_hash_cache()?.with_extension("nomtime")),
+            (false, _) => None,
+        };
+
+        let budget = self.config.hashing.merkle_time_budget();
+        let max_open = self.config.hashing.max_open_files;
+        let (exclude, _) = self.exclude();
+
+        // built the way push builds its archive, only always reproducible, so mtimes and owners don't leak into the key
+        if self.config.hashing.over == HashOver::Archive {
+            let present: Vec<&String> = hash_dirs.iter().filter(|dir| Path::new(dir).exists()).collect();
+            let settings = config::Archive {
+                reproducible: true,
+                ..self.config.archive.clone()
+            };
+            return Ok(hash::bytes_to_hex(archive::write(&present, &exclude, &settings, Sha256::new())?.finalize()));
+        }
+
+        if self.config.hashing.git {
+            return Ok(hash::compute_git(hash_dirs, |dir, submodules| {
+                let exclude: Vec<String> = exclude.iter().chain(submodules).cloned().collect();
+                hash::compute_cache(std::slice::from_ref(dir), &exclude, None, false, ignore_mtime, budget, max_open)
+            })?);
+        }
+
+        Ok(hash::compute_cache(hash_dirs, &exclude, sidecar.as_deref(), !self.config.read_only, ignore_mtime, budget, max_open)?)
+    }
+
+    pub async fn check_status(&self) -> Result<ExitCode> {
+        self.check_version().await?;
+        let hash = self.compute_hash()?;
 
         if self.check_hash(&hash).await? {
             println!("{} Cache exists on server", colors::OK);
@@ -131,151 +991,1674 @@ impl Services {
         }
 
         println!("{} Files currently uncached", colors::WARN);
-        return Ok(ExitCode::FAILURE);
+        Ok(ExitCode::from(Failure::CacheMiss.code()))
     }
 
-    pub async fn pull_cache(&self) -> Result<ExitCode> {
+    pub async fn pull_cache(&self, require_hit: bool, only: &[String], remap: &archive::Remap, max_age: Option<Duration>) -> Result<ExitCode> {
         let start = Instant::now();
-        let (url, header) = self.config.get_server(Route::Pull)?;
 
-        let hash_dirs = self.config.settings.hash.as_ref().unwrap_or(&self.config.settings.cache);
-        let hash = hash::compute_cache(hash_dirs)?;
+        if let Some(dir) = only.iter().find(|dir| !self.config.settings.cache.iter().any(|cache| Path::new(cache) == Path::new(dir))) {
+            return Err(anyhow!("'{dir}' is not one of the configured cache directories").context(Failure::Config));
+        }
 
-        let pb = ProgressBar::new_spinner();
-        let style = ProgressStyle::with_template("\n{spinner:.green} {msg}")
-            .unwrap()
-            .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏", "✓"]);
+        self.check_version().await?;
 
-        pb.set_style(style);
-        pb.enable_steady_tick(std::time::Duration::from_millis(80));
+        let hash = self.compute_hash()?;
+        self.note(|summary| summary.key = Some(hash.clone()));
 
-        let response = match self.client.get(&url).header("Authorization", header).header("X-Volt-Hash", hash).send().await {
-            Ok(next) => next,
-            Err(_) => {
+        let pb = helpers::spinner();
+
+        let pulled = match self.restore(&hash, only, remap, max_age, &pb).await {
+            Ok(pulled) => pulled,
+            Err(err) => {
                 pb.finish_and_clear();
-                return Err(anyhow!("unable to connect, is the server up?"));
+                let status = if helpers::is_maintenance(&err) { "maintenance" } else { "error" };
+                self.note(|summary| summary.pull = Some(status));
+                return Err(err);
             }
         };
 
-        if response.status() == StatusCode::NOT_MODIFIED {
-            pb.finish_with_message("Cache is up to date");
-            return Ok(ExitCode::SUCCESS);
+        self.note(|summary| match pulled {
+            Pulled::UpToDate => summary.pull = Some("up-to-date"),
+            Pulled::Local => summary.pull = Some("local"),
+            Pulled::Downloaded(size, _) => {
+                summary.pull = Some("hit");
+                summary.bytes = Some(size);
+            }
+            Pulled::Missing => summary.pull = Some("miss"),
+            Pulled::Stale(_) => summary.pull = Some("stale"),
+        });
+
+        let elapsed = format!("{:.2?}", start.elapsed()).green();
+
+        match pulled {
+            Pulled::UpToDate => pb.finish_with_message("Cache is up to date"),
+            Pulled::Local => pb.finish_with_message(format!("Cache restored from local archive in {elapsed}")),
+            Pulled::Downloaded(_, None) => pb.finish_with_message(format!("Cache restored in {elapsed}")),
+            Pulled::Downloaded(_, Some(age)) => pb.finish_with_message(format!("Cache restored in {elapsed}, stored {} ago", helpers::format_age(age).bright_cyan())),
+            Pulled::Stale(age) => {
+                pb.finish_and_clear();
+
+                return match require_hit {
+                    true => Err(anyhow!("the stored cache is {} old, past --max-age", helpers::format_age(age)).context(Failure::CacheMiss)),
+                    false => {
+                        eprintln!("{} Stored cache is {} old, past --max-age, skipping it", colors::WARN, helpers::format_age(age));
+                        Ok(ExitCode::SUCCESS)
+                    }
+                };
+            }
+            Pulled::Missing => {
+                pb.finish_and_clear();
+
+                return match require_hit {
+                    true => Err(anyhow!("no cache stored on the server").context(Failure::CacheMiss)),
+                    false => {
+                        eprintln!("{} No cache stored on the server yet", colors::WARN);
+                        Ok(ExitCode::SUCCESS)
+                    }
+                };
+            }
         }
 
-        if !response.status().is_success() {
-            pb.finish_and_clear();
-            return Err(anyhow!(response.status()));
+        // best effort, a cache that can't be pruned shouldn't fail the pull that used it
+        if let Ok(Some(local)) = self.local_cache()
+            && let Ok(pruned) = local.prune()
+            && pruned.archives > 0
+        {
+            eprintln!("{} Pruned {} old archive(s) from the local cache", colors::OK, pruned.archives);
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    fn gc_local(&self) -> Result<ExitCode> {
+        let local_cache = &self.config.local_cache;
+        let max_bytes = local_cache.max_bytes.unwrap_or_default();
+        let cache = ArchiveCache::new(&self.config.get_home()?, &self.config.volt_id, max_bytes, local_cache.max_age());
+
+        let pruned = cache.prune()?;
+
+        match pruned.archives {
+            0 => println!("{} Local cache is within its limits", colors::OK),
+            archives => println!("{} Removed {archives} archive(s), reclaiming {}", colors::OK, helpers::format_size(pruned.bytes as usize).bright_cyan()),
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Pulls every profile's cache at once, at most `PARALLEL_PULLS` at a time, and prints one combined summary.
+    pub async fn pull_all(&self, require_hit: bool) -> Result<ExitCode> {
+        let start = Instant::now();
+
+        if self.config.profiles.is_empty() {
+            return Err(anyhow!("no profiles are configured").context(Failure::Config));
+        }
+
+        self.check_version().await?;
+
+        let pb = helpers::spinner();
+        pb.set_message(format!("Pulling {} profiles...", self.config.profiles.len()));
+
+        let pulls = self.config.profiles.keys().map(|name| async move {
+            let pulled = async {
+                let services = Services::new(self.config.with_profile(name)?, self.client.clone());
+                let hash = services.compute_hash()?;
+
+                services.restore(&hash, &[], &archive::Remap::default(), None, &ProgressBar::hidden()).await
+            };
+
+            (name, pulled.await)
+        });
+
+        let results: Vec<_> = futures::stream::iter(pulls).buffer_unordered(PARALLEL_PULLS).collect().await;
+        pb.finish_and_clear();
+
+        let (mut hits, mut misses, mut bytes) = (0, 0, 0);
+        let mut failure = None;
+
+        for (name, result) in results {
+            match result {
+                Ok(Pulled::Missing) => {
+                    misses += 1;
+                    println!("{} {} no cache stored on the server yet", colors::WARN, name.bright_magenta());
+                }
+                Ok(Pulled::UpToDate) => {
+                    hits += 1;
+                    println!("{} {} is up to date", colors::OK, name.bright_magenta());
+                }
+                Ok(pulled) => {
+                    hits += 1;
+                    if let Pulled::Downloaded(size, _) = pulled {
+                        bytes += size;
+                    }
+                    println!("{} {} restored", colors::OK, name.bright_magenta());
+                }
+                Err(err) => {
+                    eprintln!("{} {} {err:#}", colors::FAIL, name.bright_magenta());
+                    failure = failure.or(Some(err));
+                }
+            }
         }
 
-        pb.set_message("Downloading archive...");
+        println!(
+            "\n{hits} hits, {misses} misses, {} downloaded in {}",
+            helpers::format_size(bytes).bright_cyan(),
+            format!("{:.2?}", start.elapsed()).green()
+        );
+
+        match failure {
+            Some(err) => Ok(ExitCode::from(Failure::of(&err).map_or(1, Failure::code))),
+            None if require_hit && misses > 0 => Ok(ExitCode::from(Failure::CacheMiss.code())),
+            None => Ok(ExitCode::SUCCESS),
+        }
+    }
+
+    /// Restores the cache for `hash` from the local archive cache or the server, reporting progress on `pb`.
+    async fn restore(&self, hash: &str, only: &[String], remap: &archive::Remap, max_age: Option<Duration>, pb: &ProgressBar) -> Result<Pulled> {
+        let (url, header) = self.config.get_server(Route::Pull)?;
+        let local = self.local_cache()?;
+
+        // a remapped pull lands somewhere the local hash doesn't describe, so it never counts as up to date
+        let hash = if remap.is_identity() { hash } else { "" };
+
+        // only the server knows when an archive was stored, so --max-age skips the local cache
+        if let Some(local) = local.as_ref().filter(|_| max_age.is_none())
+            && let Some(server_hash) = self.server_hash(hash).await
+            && let Some(archive) = local.get(&server_hash)
+        {
+            pb.set_message("Extracting from local cache...");
+            self.extract(&self.decompress(&archive)?, only, remap).await?;
+            return Ok(Pulled::Local);
+        }
+
+        let mut request = self.client.get(&url).headers(header).header("X-Volt-Hash", hash).header("X-Volt-Hash-Algorithm", hash::ALGORITHM);
+
+        // the server can't decode dictionary-compressed or encrypted archives, so those always come back as zstd
+        if self.config.network.prefer_uncompressed && self.config.compression.dictionary.is_none() && self.config.security.encryption_key.is_none() {
+            request = request.header("X-Volt-Prefer-Uncompressed", "1");
+        }
+
+        let response = request.send().await.map_err(|_| anyhow!("unable to connect, is the server up?").context(Failure::Network))?;
+
+        match response.status() {
+            StatusCode::NOT_MODIFIED => return Ok(Pulled::UpToDate),
+            StatusCode::NOT_FOUND => return Ok(Pulled::Missing),
+            status if !status.is_success() => return Err(helpers::response_error(response).await),
+            _ => {}
+        }
+
+        let age = response
+            .headers()
+            .get("Last-Modified")
+            .and_then(|modified| httpdate::parse_http_date(modified.to_str().ok()?).ok())
+            .map(|modified| modified.elapsed().unwrap_or_default());
+
+        if let (Some(age), Some(max_age)) = (age, max_age)
+            && age > max_age
+        {
+            return Ok(Pulled::Stale(age));
+        }
+
+        pb.set_message("Downloading archive...");
+
+        let server_hash = response.headers().get("X-Volt-Hash").and_then(|h| h.to_str().ok()).map(ToString::to_string);
+        let total = response.content_length();
+        let mut archive = Vec::with_capacity(total.unwrap_or_default() as usize);
+        let mut stream = Box::pin(throttle::throttle(response.bytes_stream(), self.config.network.max_download_bytes_per_sec));
+
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk?);
+
+            if let Some(total) = total {
+                pb.set_message(format!("Downloading archive... {}/{}", helpers::format_size(archive.len()), helpers::format_size(total as usize)));
+            }
+        }
+
+        let size = archive.len();
+        let archive = self.unseal(archive)?;
+        let encoding = archive::sniff(&archive);
+
+        // the local cache only holds zstd archives, so plain tar responses aren't kept
+        if encoding == Some(archive::Encoding::Zstd)
+            && let (Some(local), Some(server_hash)) = (&local, &server_hash)
+            && let Err(err) = local.put(server_hash, &archive)
+        {
+            pb.suspend(|| eprintln!("{} Failed to store archive locally: {err}", colors::WARN));
+        }
+
+        pb.set_message("Extracting...");
+
+        let tarball = match encoding {
+            Some(archive::Encoding::Zstd) => self.decompress(&archive)?,
+            Some(archive::Encoding::Gzip) => {
+                let mut tarball = Vec::new();
+                flate2::read::GzDecoder::new(archive.as_slice())
+                    .read_to_end(&mut tarball)
+                    .context("the archive is gzip but failed to decompress")?;
+                tarball
+            }
+            Some(archive::Encoding::Tar) => archive,
+            None => {
+                let magic = archive.iter().take(4).map(|byte| format!("{byte:02x}")).collect::<String>();
+                return Err(anyhow!("the server sent an archive in an unrecognized format (starting {magic}), expected zstd, gzip or tar"));
+            }
+        };
+
+        self.extract(&tarball, only, remap).await?;
+        Ok(Pulled::Downloaded(size, age))
+    }
+
+    /// Restores another cache entry into a scratch directory, every entry landing under it whatever directories it was pushed from.
+    async fn replay(&mut self, id: &str, output: Option<PathBuf>) -> Result<ExitCode> {
+        let start = Instant::now();
+
+        // the id names the default scratch directory and a path segment, so it's held to what the server accepts for either
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) || id.starts_with('.') {
+            return Err(anyhow!("invalid volt_id or tag '{id}'").context(Failure::Config));
+        }
+
+        let output = match output {
+            Some(output) if output.read_dir().is_ok_and(|mut entries| entries.next().is_some()) => {
+                return Err(anyhow!("{} isn't empty, replay into a new directory", output.display()).context(Failure::Config));
+            }
+            Some(output) => output,
+            None => {
+                let output = self.config.get_temp_dir()?.join(format!("volt-replay-{id}"));
+                if output.exists() {
+                    fs::remove_dir_all(&output)?;
+                }
+                output
+            }
+        };
+
+        self.check_version().await?;
+
+        // extract only what the archive holds, nothing about this project's own cache directories applies to it
+        self.config.volt_id = id.to_string();
+        self.config.settings.cache.clear();
+        self.config.archive.incremental = false;
+
+        let remap = archive::Remap {
+            strip: 0,
+            prefix: Some(output.clone()),
+        };
+
+        fs::create_dir_all(&output)?;
+
+        let pb = helpers::spinner();
+        let pulled = self.restore("", &[], &remap, None, &pb).await;
+        pb.finish_and_clear();
+
+        match pulled? {
+            Pulled::Missing => Err(anyhow!("no cache stored on the server for {id}").context(Failure::CacheMiss)),
+            _ => {
+                let elapsed = format!("{:.2?}", start.elapsed()).green();
+                println!("{} Replayed {} into {} in {elapsed}", colors::OK, id.bright_cyan(), output.display().to_string().bright_blue());
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    }
+
+    /// Decrypts a downloaded archive when `[security] encryption_key` is set, and refuses encrypted archives without one.
+    fn unseal(&self, archive: Vec<u8>) -> Result<Vec<u8>> {
+        match self.config.security.key()? {
+            Some(key) => encryption::open(&key, &archive),
+            None if encryption::is_sealed(&archive) => Err(anyhow!("the archive is encrypted, set [security] encryption_key to restore it").context(Failure::Config)),
+            None => Ok(archive),
+        }
+    }
+
+    fn dictionary(&self) -> Result<Option<Vec<u8>>> {
+        let Some(path) = &self.config.compression.dictionary else {
+            return Ok(None);
+        };
+
+        let dictionary = fs::read(path).with_context(|| format!("failed to read dictionary {}", path.display())).context(Failure::Config)?;
+        Ok(Some(dictionary))
+    }
+
+    /// Decompresses an archive, loading the configured dictionary when its frame says it was written with one.
+    fn decompress(&self, archive: &[u8]) -> Result<Vec<u8>> {
+        let Some(expected) = zstd::zstd_safe::get_dict_id_from_frame(archive) else {
+            return Ok(frames::decompress(archive, &[])?);
+        };
+
+        let dictionary = self
+            .dictionary()?
+            .ok_or_else(|| anyhow!("the archive was compressed with dictionary {expected}, set `[compression] dictionary` to restore it").context(Failure::Config))?;
+
+        if let Some(actual) = zstd::zstd_safe::get_dict_id_from_dict(&dictionary)
+            && actual != expected
+        {
+            return Err(anyhow!("the archive was compressed with dictionary {expected}, but the configured one is {actual}").context(Failure::Config));
+        }
+
+        Ok(frames::decompress(archive, &dictionary)?)
+    }
+
+    /// Trains a zstd dictionary on small files sampled from the cache directories.
+    fn dict_train(&self, output: Option<&Path>, size: usize) -> Result<ExitCode> {
+        let output = output.or(self.config.compression.dictionary.as_deref()).unwrap_or(Path::new("volt.dict"));
+        let pb = helpers::spinner();
+        pb.set_message("Sampling cache files...");
+
+        let mut samples = Vec::new();
+        let mut sampled = 0;
+
+        'dirs: for dir in &self.config.settings.cache {
+            for entry in pattern::walk(walkdir::WalkDir::new(dir).sort_by_file_name(), &self.config.settings.exclude).filter_map(|e| e.ok()) {
+                if sampled >= DICT_SAMPLE_BUDGET {
+                    break 'dirs;
+                }
+
+                if entry.file_type().is_file() && entry.metadata().is_ok_and(|metadata| metadata.len() <= DICT_MAX_SAMPLE) {
+                    let sample = fs::read(entry.path())?;
+                    sampled += sample.len();
+                    samples.push(sample);
+                }
+            }
+        }
+
+        if samples.len() < DICT_MIN_SAMPLES {
+            pb.finish_and_clear();
+            return Err(anyhow!("found only {} small files to sample, a dictionary needs at least {DICT_MIN_SAMPLES}", samples.len()));
+        }
+
+        pb.set_message(format!("Training on {} files...", samples.len()));
+        let dictionary = zstd::dict::from_samples(&samples, size)?;
+        fs::write(output, &dictionary)?;
+
+        pb.finish_with_message(format!(
+            "Wrote {} dictionary to {}, point `[compression] dictionary` at it to use it",
+            helpers::format_size(dictionary.len()).bright_cyan(),
+            output.display()
+        ));
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    fn local_cache(&self) -> Result<Option<ArchiveCache>> {
+        match self.config.local_cache.max_bytes.filter(|max| *max > 0) {
+            Some(max_bytes) => Ok(Some(ArchiveCache::new(&self.config.get_home()?, &self.config.volt_id, max_bytes, self.config.local_cache.max_age()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Asks the server which hash its stored archive has, without downloading it.
+    async fn server_hash(&self, hash: &str) -> Option<String> {
+        let (url, header) = self.config.get_server(Route::Check).ok()?;
+        let response = self
+            .client
+            .get(&url)
+            .headers(header)
+            .header("X-Volt-Hash", hash)
+            .header("X-Volt-Hash-Algorithm", hash::ALGORITHM)
+            .send()
+            .await
+            .ok()?;
+
+        match response.status() {
+            StatusCode::OK => response.headers().get("X-Volt-Hash")?.to_str().ok().map(ToString::to_string),
+            _ => None,
+        }
+    }
+
+    /// Replaces the cache directories with the archive contents, limited to `only` when it isn't empty and rewritten by `remap`.
+    async fn extract(&self, tarball: &[u8], only: &[String], remap: &archive::Remap) -> Result<()> {
+        archive::check_limits(tarball, &self.config.archive)?;
+
+        let settings = &self.config.archive;
+        let kept = archive::restore(tarball, &self.config.settings.cache, only, remap, settings.incremental, settings.on_conflict)?;
+
+        if kept > 0 {
+            eprintln!("{} Kept {kept} local file(s) that differ from the cache", colors::WARN);
+        }
+
+        Ok(())
+    }
+
+    pub async fn download_cache(&self, output: &Path, format: ArchiveFormat) -> Result<ExitCode> {
+        let start = Instant::now();
+        self.check_version().await?;
+
+        let (url, header) = self.config.get_server(Route::Pull)?;
+        let format = match format {
+            ArchiveFormat::Zstd => "zstd",
+            ArchiveFormat::Targz if self.config.security.encryption_key.is_some() => {
+                return Err(anyhow!("the server can't convert encrypted archives, download with --format zstd instead").context(Failure::Config));
+            }
+            ArchiveFormat::Targz => "targz",
+        };
+
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("format", format)])
+            .headers(header)
+            .send()
+            .await
+            .map_err(|_| anyhow!("unable to connect, is the server up?").context(Failure::Network))?;
+
+        if !response.status().is_success() {
+            return Err(helpers::response_error(response).await);
+        }
+
+        // staged in temp_dir, so a failed download never leaves a truncated archive at `output`
+        let staging = self.config.get_temp_dir()?.join(format!("volt-{}.download", uuid::Uuid::new_v4().simple()));
+        let mut stream = Box::pin(throttle::throttle(response.bytes_stream(), self.config.network.max_download_bytes_per_sec));
+
+        let download = async {
+            let mut file = tokio::fs::File::create(&staging).await.with_context(|| format!("Failed to create {staging:?}"))?;
+            let mut written = 0;
+
+            // a sealed archive has to arrive whole before it can be checked and opened
+            if self.config.security.encryption_key.is_some() {
+                let mut archive = Vec::new();
+                while let Some(chunk) = stream.next().await {
+                    archive.extend_from_slice(&chunk?);
+                }
+
+                let archive = self.unseal(archive)?;
+                written = archive.len();
+                file.write_all(&archive).await?;
+            } else {
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk?;
+                    written += chunk.len();
+                    file.write_all(&chunk).await?;
+                }
+            }
+
+            file.flush().await?;
+            anyhow::Ok(written)
+        };
+
+        let written = match download.await {
+            Ok(written) => written,
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&staging).await;
+                return Err(err);
+            }
+        };
+
+        // temp_dir may be on another filesystem, where a rename can't reach
+        if tokio::fs::rename(&staging, output).await.is_err() {
+            tokio::fs::copy(&staging, output).await.with_context(|| format!("Failed to write {output:?}"))?;
+            tokio::fs::remove_file(&staging).await?;
+        }
+
+        println!(
+            "{} Saved {} to {} in {}",
+            colors::OK,
+            helpers::format_size(written).bright_cyan(),
+            output.display(),
+            format!("{:.2?}", start.elapsed()).green()
+        );
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Pushes `dirs` in place of the configured cache directories, overlaps resolved per `cache_overlap`, or a plain push when empty.
+    /// The key is hashed from `dirs` too unless `[settings] hash` names other directories, in which case it still comes from those.
+    pub async fn push_from(&mut self, dirs: Vec<String>) -> Result<ExitCode> {
+        if let Some(dir) = dirs.iter().find(|dir| !Path::new(dir).is_dir()) {
+            return Err(anyhow!("'{dir}' is not a directory").context(Failure::Config));
+        }
+
+        if !dirs.is_empty() {
+            self.config.settings.cache = config::resolve_overlap(dirs, self.config.settings.cache_overlap).context(Failure::Config)?;
+        }
+
+        self.push_cache().await
+    }
+
+    pub async fn push_cache(&self) -> Result<ExitCode> {
+        let start = Instant::now();
+        let _lock = self.push_lock(true)?;
+        #[cfg(unix)]
+        helpers::signal_ready();
+
+        self.check_version().await?;
+        let (url, header) = self.config.get_server(Route::Push)?;
+
+        let hash = self.compute_hash()?;
+        self.note(|summary| {
+            summary.key = Some(hash.clone());
+            summary.push = Some("error");
+        });
+
+        let pb = helpers::spinner();
+
+        if self.check_hash(&hash).await? {
+            self.note(|summary| summary.push = Some("skipped"));
+            pb.finish_with_message("Skipping cache push");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        pb.set_message("Creating archive...");
+
+        let (present, missing): (Vec<_>, Vec<_>) = self.config.settings.cache.iter().partition(|dir| Path::new(dir).exists());
+
+        for dir in &missing {
+            pb.suspend(|| eprintln!("{} Skipping missing cache directory {}", colors::WARN, dir.yellow()));
+        }
+
+        let (exclude, oversized) = self.exclude();
+
+        for (path, size) in &oversized {
+            pb.suspend(|| {
+                eprintln!(
+                    "{} Skipping {} ({}), it is over [cache] max_file_bytes",
+                    colors::WARN,
+                    path.display().to_string().yellow(),
+                    helpers::format_size(*size as usize)
+                )
+            });
+        }
+
+        let buffer = archive::build(&present, &exclude, &self.config.archive)?;
+
+        let file_count: usize = present.iter().map(|dir| hash::count_files_in_dir(dir, &exclude)).sum();
+        let uncompressed_size = buffer.len();
+
+        // a build that legitimately produced nothing is still recorded, so the next identical build is a hit rather than a miss
+        if file_count == 0 {
+            pb.suspend(|| eprintln!("{} No files to cache, pushing an empty cache for this hash", colors::WARN));
+        }
+
+        pb.set_message("Compressing...");
+
+        let dictionary = self.dictionary()?;
+        let tuning = self.config.compression.tuning();
+
+        if self.config.verbose {
+            let threads = match tuning.threads {
+                0 => format!("{} (one per core)", rayon::current_num_threads()),
+                threads => threads.to_string(),
+            };
+            pb.suspend(|| {
+                eprintln!(
+                    "{} Compressing with preset {}: level {}, {threads} threads, long-distance matching {}",
+                    colors::BOLT,
+                    self.config.compression.preset.name(),
+                    tuning.level,
+                    if tuning.long_distance { "on" } else { "off" }
+                )
+            });
+        }
+
+        let compressed = bytes::Bytes::from(frames::compress(&buffer, &tuning, dictionary.as_deref().unwrap_or_default())?);
+
+        if self.config.compression.verify {
+            pb.set_message("Verifying archive...");
+
+            let matches = self.decompress(&compressed).is_ok_and(|decoded| decoded == buffer);
+            if !matches {
+                pb.finish_and_clear();
+                return Err(anyhow!(
+                    "the compressed archive doesn't decompress back to the original, refusing to push it (try again, and check the machine's memory if it repeats)"
+                ));
+            }
+        }
+
+        // the local cache stays plaintext, only what leaves the machine is sealed
+        let sealed = match self.config.security.key()? {
+            Some(key) => bytes::Bytes::from(encryption::seal(&key, &compressed)?),
+            None => compressed.clone(),
+        };
+        let length = helpers::format_size(sealed.len());
+
+        pb.set_message("Uploading...");
+
+        let upload = Upload {
+            hash: &hash,
+            archive: &sealed,
+            digest: hash::content_digest(&sealed),
+            uncompressed_size,
+            file_count,
+        };
+
+        let sent = match self.config.network.upload_concurrency.filter(|concurrency| *concurrency > 1 && sealed.len() > PART_SIZE) {
+            Some(concurrency) => self.upload_parts(&url, header.clone(), &upload, concurrency, &pb).await,
+            None => self.upload(&url, header.clone(), &upload).await,
+        };
+
+        let response = match sent {
+            Ok(next) => next,
+            Err(_) => {
+                pb.finish_and_clear();
+
+                // a server in maintenance turns the push away without reading it, which cuts the upload off, so ask it why
+                if let Ok(response) = self.client.get(&url).headers(header).send().await
+                    && response.headers().contains_key("X-Volt-Maintenance")
+                {
+                    self.note(|summary| summary.push = Some("maintenance"));
+                    return Err(helpers::response_error(response).await);
+                }
+
+                return Err(anyhow!("unable to connect, is the server up?").context(Failure::Network));
+            }
+        };
+
+        if response.status() == StatusCode::UNPROCESSABLE_ENTITY {
+            pb.finish_and_clear();
+            let reason = response.text().await.unwrap_or_default();
+            return Err(anyhow!("server rejected push: {reason}").context(Failure::Config));
+        }
+
+        if matches!(response.status(), StatusCode::INSUFFICIENT_STORAGE | StatusCode::CONFLICT) {
+            pb.finish_and_clear();
+            let reason = response.text().await.unwrap_or_default();
+            return Err(anyhow!("server rejected push: {reason}"));
+        }
+
+        if !response.status().is_success() {
+            pb.finish_and_clear();
+            let err = helpers::response_error(response).await;
+            if helpers::is_maintenance(&err) {
+                self.note(|summary| summary.push = Some("maintenance"));
+            }
+            return Err(err);
+        }
+
+        // the server's answer changed, so a remembered check no longer holds
+        let _ = fs::remove_file(self.config.get_check_cache()?);
+
+        if let Some(local) = self.local_cache()?
+            && let Err(err) = local.put(&hash, &compressed)
+        {
+            pb.suspend(|| eprintln!("{} Failed to store archive locally: {err}", colors::WARN));
+        }
+
+        let skipped = match missing.is_empty() {
+            true => String::new(),
+            false => format!(" (skipped {})", missing.iter().map(|dir| dir.as_str()).collect::<Vec<_>>().join(", ").yellow()),
+        };
+
+        pb.finish_with_message(format!("Cached {} in {}{skipped}", length.bright_cyan(), format!("{:.2?}", start.elapsed()).green()));
+        self.note(|summary| {
+            summary.push = Some("pushed");
+            summary.bytes = Some(sealed.len());
+        });
+
+        self.push_mirrors(&upload).await;
+        Ok(ExitCode::SUCCESS)
+    }
+
+    async fn upload(&self, url: &str, header: HeaderMap, upload: &Upload<'_>) -> reqwest::Result<reqwest::Response> {
+        let body = Body::wrap_stream(throttle::throttle(throttle::chunked(upload.archive.clone()), self.config.network.max_upload_bytes_per_sec));
+
+        upload
+            .describe(self.client.post(url).headers(header))
+            .header("Content-Length", upload.archive.len())
+            .body(body)
+            .send()
+            .await
+    }
+
+    /// Uploads the archive in `PART_SIZE` parts, `concurrency` at a time, then has the server join them in order.
+    /// Servers that predate chunked pushes get the archive in a single request instead.
+    async fn upload_parts(&self, url: &str, header: HeaderMap, upload: &Upload<'_>, concurrency: usize, pb: &ProgressBar) -> reqwest::Result<reqwest::Response> {
+        let upload_id = uuid::Uuid::new_v4().simple().to_string();
+        let total = upload.archive.len();
+        let parts: Vec<_> = (0..total).step_by(PART_SIZE).map(|start| upload.archive.slice(start..(start + PART_SIZE).min(total))).collect();
+
+        // an empty first part tells a server without chunked pushes apart before any real part is cut off mid-body by its 404
+        let part = |index: usize| self.client.put(format!("{url}/parts/{index}")).headers(header.clone()).header("X-Volt-Upload-Id", &upload_id);
+        let probe = part(0).header("Content-Length", 0).send().await?;
+
+        match probe.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => return self.upload(url, header, upload).await,
+            status if !status.is_success() => return Ok(probe),
+            _ => {}
+        }
+
+        // the cap is shared between the parts in flight, rather than applying to each
+        let rate = self.config.network.max_upload_bytes_per_sec.map(|rate| (rate / concurrency as u64).max(1));
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let requests = parts.iter().enumerate().map(|(index, data)| {
+            let (sent, pb) = (sent.clone(), pb.clone());
+            let progress = throttle::throttle(throttle::chunked(data.clone()), rate).inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    let sent = sent.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+                    pb.set_message(format!("Uploading... {}/{}", helpers::format_size(sent), helpers::format_size(total)));
+                }
+            });
+
+            part(index).header("Content-Length", data.len()).body(Body::wrap_stream(progress)).send()
+        });
+
+        let mut responses = futures::stream::iter(requests).buffer_unordered(concurrency);
+
+        while let Some(response) = responses.next().await {
+            let response = response?;
+
+            if !response.status().is_success() {
+                return Ok(response);
+            }
+        }
+
+        drop(responses);
+
+        upload
+            .describe(self.client.post(format!("{url}/complete")).headers(header))
+            .header("X-Volt-Upload-Id", &upload_id)
+            .header("X-Volt-Parts", parts.len())
+            .send()
+            .await
+    }
+
+    /// Copies a pushed archive to every `[network] mirrors` server, a few at a time, reporting each as it finishes.
+    /// Mirrors are best effort, so their failures are only reported.
+    async fn push_mirrors(&self, upload: &Upload<'_>) {
+        let mirrors = &self.config.network.mirrors;
+
+        if mirrors.is_empty() {
+            return;
+        }
+
+        let permits = Semaphore::new(self.config.network.mirror_concurrency.unwrap_or(MIRROR_CONCURRENCY).max(1));
+
+        let uploads = mirrors.iter().map(|name| {
+            let permits = &permits;
+
+            async move {
+                let _permit = permits.acquire().await;
+                let start = Instant::now();
+
+                let result = match self.config.get_named_server(name, Route::Push) {
+                    Err(err) => Err(format!("{err:#}")),
+                    Ok((url, header)) => match self.upload(&url, header, upload).await {
+                        Err(err) => Err(helpers::explain_request_error(&err)),
+                        Ok(response) if !response.status().is_success() => Err(format!("server responded {}", response.status())),
+                        Ok(_) => Ok(()),
+                    },
+                };
+
+                (name, result, start.elapsed())
+            }
+        });
+
+        let mut finished: FuturesUnordered<_> = uploads.collect();
+
+        while let Some((name, result, elapsed)) = finished.next().await {
+            match result {
+                Ok(()) => eprintln!("{} Mirrored to {} in {}", colors::OK, name.bright_magenta(), format!("{elapsed:.2?}").green()),
+                Err(err) => eprintln!("{} Mirror {} failed: {err}", colors::WARN, name.bright_magenta()),
+            }
+        }
+    }
+
+    /// Lays out what `run` would do, reading the tree to compute the key but leaving the cache, the server and the build alone.
+    pub fn run_plan(&self, only_if_changed: bool, require_push: bool, keep_going: bool) -> Result<ExitCode> {
+        let settings = &self.config.settings;
+        let list = |dirs: &[String]| match dirs.is_empty() {
+            true => "none".yellow().to_string(),
+            false => dirs.iter().map(|dir| dir.bright_cyan().to_string()).collect::<Vec<_>>().join(", "),
+        };
+
+        println!("\nRun plan {}", "(dry run, nothing is pulled, built or pushed)".dimmed());
+
+        match self.config.servers.get(&settings.server) {
+            Some(server) => {
+                let scheme = if server.tls { "https" } else { "http" };
+                let token = if server.token.is_some() { "token configured" } else { "no token" };
+                println!("  Server: {} ({scheme}://{}, {token})", settings.server.bright_magenta(), server.address);
+            }
+            None => println!("  Server: {} {}", settings.server.bright_magenta(), "(not configured)".red()),
+        }
+
+        println!("  volt_id: {}", self.config.volt_id.bright_cyan());
+        if let Some(profile) = &self.config.profile {
+            println!("  Profile: {}", profile.bright_magenta());
+        }
+
+        let hash = self.compute_hash();
+        match &hash {
+            Ok(hash) => println!("  Cache key: {}", hash.bright_cyan()),
+            Err(err) => println!("  Cache key: {}", format!("unavailable, {err}").red()),
+        }
+
+        println!("  Hashed: {}", list(settings.hash.as_ref().unwrap_or(&settings.cache)));
+        println!("  Restored and archived: {}", list(&settings.cache));
+        if !settings.exclude.is_empty() {
+            println!("  Excluded: {}", list(&settings.exclude));
+        }
+
+        println!("  Command: sh -c {:?}", settings.wrap);
+
+        let require_push = require_push || self.config.run.require_push;
+        let push = match (self.config.run.background_push && !require_push, require_push) {
+            (true, _) => "in the background after the build",
+            (false, true) => "after the build, failing the run if it fails",
+            (false, false) => "after the build",
+        };
+        let failed = match keep_going || self.config.run.push_on_failure {
+            true => ", even a failed one",
+            false => "",
+        };
+        println!("  Push: {push}{failed}");
+
+        if !self.config.network.mirrors.is_empty() {
+            println!("  Mirrors: {}", list(&self.config.network.mirrors));
+        }
+
+        if only_if_changed && let Ok(hash) = &hash {
+            let unchanged = fs::read_to_string(self.config.get_last_run()?).is_ok_and(|last| last == *hash);
+            println!(
+                "  Only if changed: {}",
+                if unchanged {
+                    "unchanged since the last run, it would be skipped".yellow()
+                } else {
+                    "changed, it would run".green()
+                }
+            );
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    pub async fn run_build(&self, only_if_changed: bool, require_push: bool, keep_going: bool, output: RunOutput) -> Result<ExitCode> {
+        let start = Instant::now();
+        let require_push = require_push || self.config.run.require_push;
+        let keep_going = keep_going || self.config.run.push_on_failure;
+        let name = self.config.settings.wrap.split_whitespace().next().unwrap_or_default();
+
+        // with a JSON result on stdout, everything else has to stay off it
+        let json = matches!(output, RunOutput::Captured { json: true, .. });
+        let say = |line: String| match json {
+            true => eprintln!("{line}"),
+            false => println!("{line}"),
+        };
+
+        if only_if_changed {
+            let hash = self.compute_hash()?;
+
+            if fs::read_to_string(self.config.get_last_run()?).is_ok_and(|last| last == hash) {
+                self.note(|summary| summary.status = Some("unchanged"));
+                say(format!("{} No changes since last run", colors::OK));
+                return Ok(ExitCode::SUCCESS);
+            }
+        }
+
+        say(format!("🔥 Starting {}", self.config.settings.wrap));
+
+        // don't restore or build on top of a background push that is still archiving
+        drop(self.push_lock(true)?);
+
+        let breaker = self.breaker()?;
+        let unhealthy = match breaker.as_ref().map(Breaker::check) {
+            Some(Gate::Open(remaining)) => {
+                eprintln!("\n{} Cache server marked unhealthy, skipping it for another {}s", colors::WARN, remaining.as_secs());
+                self.note(|summary| summary.pull = Some("unhealthy"));
+                true
+            }
+            _ => false,
+        };
+
+        let pull = match unhealthy {
+            true => None,
+            false => Some(self.pull_cache(false, &[], &archive::Remap::default(), None).await),
+        };
+
+        if let (Some(breaker), Some(pull)) = (&breaker, &pull) {
+            Self::trip(breaker, pull);
+        }
+
+        let pulled = match pull {
+            None => false,
+            Some(Ok(_)) => true,
+            Some(Err(err)) => {
+                match helpers::is_maintenance(&err) {
+                    true => eprintln!("\n{} Building without the cache, {}", colors::WARN, err.root_cause()),
+                    false => eprintln!("\n{} Cache pull failed: {err}", colors::FAIL),
+                }
+                false
+            }
+        };
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&self.config.settings.wrap);
+
+        let (status, tail) = match &output {
+            RunOutput::Inherit => (command.status(), Vec::new()),
+            RunOutput::Captured { prefix, json } => match capture::run(&mut command, prefix, *json) {
+                Ok(captured) => (Ok(captured.status), captured.tail),
+                Err(err) => (Err(err), Vec::new()),
+            },
+        };
+
+        let status = status.with_context(|| format!("{} Failed to execute {name}", colors::FAIL))?;
+        let code = status.code().unwrap_or_default();
+
+        let report = |success: bool, pushed: Option<bool>, output_tail: Vec<String>| -> Result<()> {
+            if json {
+                let report = RunReport {
+                    success,
+                    exit_code: status.code(),
+                    duration_ms: start.elapsed().as_millis(),
+                    pulled,
+                    pushed,
+                    output_tail,
+                };
+                println!("{}", serde_json::to_string(&report)?);
+            }
+            Ok(())
+        };
+
+        if !status.success() {
+            self.note(|summary| summary.status = Some("build-failed"));
+            eprintln!("{} Failed with exit code {code} in {}", colors::FAIL, format!("{:.2?}", start.elapsed()).yellow());
+
+            if !keep_going {
+                report(false, None, tail)?;
+                return Ok(ExitCode::from(Failure::Build.code()));
+            }
+
+            eprintln!("{} Pushing the cache anyway, it may hold partial outputs of the failed build", colors::WARN);
+        }
+
+        // a detached push can't report back, so a required push always runs in the foreground
+        // the pull may just have tripped the breaker, in which case the push would only fail the same way
+        let unhealthy = unhealthy || breaker.as_ref().is_some_and(Breaker::is_open);
+
+        let pushed = if unhealthy && !require_push {
+            self.note(|summary| summary.push = Some("unhealthy"));
+            Some(false)
+        } else if self.config.run.background_push && !require_push {
+            self.spawn_push().await?;
+            self.note(|summary| summary.push = Some("background"));
+            None
+        } else {
+            let push = self.push_cache().await;
+            if let Some(breaker) = &breaker {
+                Self::trip(breaker, &push);
+            }
+
+            match push {
+                Ok(_) => Some(true),
+                // the operator turned pushes away on purpose, which isn't worth failing a build over even when the push is required
+                Err(err) if helpers::is_maintenance(&err) => {
+                    eprintln!("\n{} Skipped the cache push, {}", colors::WARN, err.root_cause());
+                    Some(false)
+                }
+                Err(err) if require_push => {
+                    report(false, Some(false), Vec::new())?;
+                    return Err(err.context("cache push failed and is required"));
+                }
+                Err(err) => {
+                    eprintln!("\n{} Cache push failed: {err}", colors::FAIL);
+                    Some(false)
+                }
+            }
+        };
+
+        if !status.success() {
+            report(false, pushed, tail)?;
+            return Ok(ExitCode::from(Failure::Build.code()));
+        }
+
+        // hashed after the build, so outputs it wrote don't count as changes next time
+        if only_if_changed {
+            fs::write(self.config.get_last_run()?, self.compute_hash()?)?;
+        }
+
+        say(format!("{} Finished successfully in {}", colors::OK, format!("{:.2?}", start.elapsed()).yellow()));
+        report(true, pushed, Vec::new())?;
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Tracks connection failures to the selected server for `run`, unless `[network] breaker_failures` is 0.
+    fn breaker(&self) -> Result<Option<Breaker>> {
+        let network = &self.config.network;
+        let failures = network.breaker_failures.unwrap_or(3);
+
+        if failures == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Breaker::new(
+            self.config.get_breaker_state()?,
+            failures,
+            Duration::from_secs(network.breaker_window_secs.unwrap_or(300)),
+            Duration::from_secs(network.breaker_cooldown_secs.unwrap_or(60)),
+        )))
+    }
+
+    /// Counts a request that never reached the server against it, and clears the count once one gets an answer.
+    /// A server in maintenance answered, it just refused, so that counts as reachable.
+    fn trip(breaker: &Breaker, result: &Result<ExitCode>) {
+        match result {
+            Ok(_) => breaker.success(),
+            Err(err) if helpers::is_maintenance(err) => breaker.success(),
+            Err(err) if matches!(Failure::of(err), Some(Failure::Network)) => breaker.failure(),
+            Err(_) => {}
+        }
+    }
+
+    /// The restore `run` starts with, on its own, exiting with the pull's result instead of carrying on to build.
+    pub async fn run_cache_only(&self) -> Result<ExitCode> {
+        // like run, don't restore on top of a background push that is still archiving
+        drop(self.push_lock(true)?);
+        self.pull_cache(false, &[], &archive::Remap::default(), None).await
+    }
+
+    /// Runs every profile's build one after another, each pulling and pushing its own cache, then prints one combined summary.
+    /// A profile whose key the server already holds is only restored, without running its build.
+    pub async fn run_all_profiles(&self, only_if_changed: bool, require_push: bool, keep_going: bool, output: RunOutput) -> Result<ExitCode> {
+        let start = Instant::now();
+
+        if self.config.profiles.is_empty() {
+            return Err(anyhow!("no profiles are configured").context(Failure::Config));
+        }
+
+        self.check_version().await?;
+
+        let mut rows = Vec::with_capacity(self.config.profiles.len());
+        let mut failed = None;
+
+        for name in self.config.profiles.keys() {
+            let profile_start = Instant::now();
+            println!("\n{} Profile {}", colors::BOLT, name.bright_magenta());
+
+            let services = Services::new(self.config.with_profile(name)?, self.client.clone());
+            let result = async {
+                match services.check_hash(&services.compute_hash()?).await? {
+                    true => services.pull_cache(false, &[], &archive::Remap::default(), None).await.map(|code| (code, "cached")),
+                    false => services.run_build(only_if_changed, require_push, keep_going, output.clone()).await.map(|code| (code, "built")),
+                }
+            };
+
+            let outcome = match result.await {
+                Ok((code, _)) if code != ExitCode::SUCCESS => {
+                    failed = failed.or(Some(code));
+                    "failed"
+                }
+                Ok((_, outcome)) => services.summary.lock().unwrap().status.unwrap_or(outcome),
+                Err(err) => {
+                    eprintln!("{} {} {err:#}", colors::FAIL, name.bright_magenta());
+                    failed = failed.or(Some(ExitCode::from(Failure::of(&err).map_or(1, Failure::code))));
+                    "error"
+                }
+            };
+
+            let cache = services.summary.lock().unwrap().pull.unwrap_or("none");
+            rows.push((name, outcome, cache, profile_start.elapsed()));
+        }
+
+        let width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or_default();
+        println!();
+
+        for (name, outcome, cache, elapsed) in &rows {
+            let outcome = match *outcome {
+                "failed" | "error" => outcome.red(),
+                "cached" => outcome.green(),
+                _ => outcome.normal(),
+            };
+            println!("  {:width$}  {outcome:<9} {cache:<11} {}", name.bright_magenta(), format!("{elapsed:.2?}").yellow());
+        }
+
+        let count = |outcome: &str| rows.iter().filter(|row| row.1 == outcome).count();
+        println!(
+            "\n{} built, {} cached, {} failed in {}",
+            count("built"),
+            count("cached"),
+            count("failed") + count("error"),
+            format!("{:.2?}", start.elapsed()).green()
+        );
+
+        Ok(failed.unwrap_or(ExitCode::SUCCESS))
+    }
+
+    /// Opens the per-project push lock, waiting for any in-flight push when `wait` is set.
+    fn push_lock(&self, wait: bool) -> Result<File> {
+        let file = File::options().create(true).truncate(false).write(true).open(self.config.get_lock()?)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) if wait => {
+                eprintln!("{} Waiting for a background push to finish...", colors::BOLT);
+                file.lock()?;
+                Ok(file)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn spawn_push(&self) -> Result<()> {
+        let log = self.config.get_home()?.join("push.log");
+        let output = File::create(&log)?;
+
+        let mut command = Command::new(std::env::current_exe()?);
+        match self.config.inline {
+            Some(_) => command.arg("--path").arg("-"),
+            None => command.arg("--path").arg(&self.config.path),
+        };
+
+        if let Some(home) = &self.config.home {
+            command.arg("--config-dir").arg(home);
+        }
+
+        if let Some(rate) = self.config.network.max_upload_bytes_per_sec {
+            command.arg("--max-upload-rate").arg(rate.to_string());
+        }
+
+        if let Some(profile) = &self.config.profile {
+            command.arg("--profile").arg(profile);
+        }
+
+        for glob in &self.config.cli_exclude {
+            command.arg("--exclude").arg(glob);
+        }
+
+        // an inline config can't be read again, so the child gets the same content on its stdin
+        let stdin = match self.config.inline {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+
+        #[cfg(unix)]
+        let (ready, writer) = helpers::ready_pipe(&mut command)?;
+
+        let mut child = command.arg("push").stdin(stdin).stdout(output.try_clone()?).stderr(output).spawn()?;
+
+        if let (Some(content), Some(mut stdin)) = (&self.config.inline, child.stdin.take()) {
+            std::io::Write::write_all(&mut stdin, content.as_bytes())?;
+        }
+
+        // wait for the child to take the lock, so the next run can't slip in ahead of it
+        #[cfg(unix)]
+        {
+            drop(writer);
+            helpers::wait_ready(ready, Duration::from_secs(5)).await;
+        }
+
+        eprintln!("{} Pushing cache in the background, logging to {}", colors::BOLT, log.display());
+        Ok(())
+    }
+
+    fn id_regenerate(&mut self, yes: bool) -> Result<ExitCode> {
+        if !yes {
+            return Err(anyhow!("regenerating the volt_id orphans the existing cache, pass --yes to confirm"));
+        }
+
+        let previous = self.config.regenerate_id()?;
+
+        println!("\n{} Regenerated volt_id: {}", colors::OK, self.config.volt_id.bright_cyan());
+        println!("{} Cache stored under {} will no longer be referenced", colors::WARN, previous.yellow());
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    async fn tag_set(&self, name: &str, id: Option<&str>) -> Result<ExitCode> {
+        let (url, header) = self.config.get_server(Route::Tag)?;
+        let volt_id = id.unwrap_or(&self.config.volt_id);
+
+        // the name becomes a path segment, so catch what would route elsewhere before the server sees it
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) {
+            return Err(anyhow!("invalid tag '{name}', tags use letters, digits, '.', '-' and '_'").context(Failure::Config));
+        }
+
+        let response = self
+            .client
+            .put(format!("{url}/{name}"))
+            .headers(header)
+            .json(&serde_json::json!({ "volt_id": volt_id }))
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(anyhow!("the server doesn't support tags, upgrade it").context(Failure::Config)),
+            StatusCode::BAD_REQUEST => Err(anyhow!("invalid tag '{name}' or volt_id '{volt_id}', tags use letters, digits, '.', '-' and '_'").context(Failure::Config)),
+            status if !status.is_success() => Err(helpers::status_error(status)),
+            _ => {
+                println!("{} Tagged {} as {}", colors::OK, volt_id.bright_cyan(), name.bright_magenta());
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    }
+
+    async fn tag_list(&self) -> Result<ExitCode> {
+        let (url, header) = self.config.get_server(Route::Tags)?;
+
+        let response = self
+            .client
+            .get(&url)
+            .headers(header)
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        if !response.status().is_success() {
+            return Err(helpers::status_error(response.status()));
+        }
+
+        let tags: BTreeMap<String, String> = response.json().await?;
+
+        if tags.is_empty() {
+            println!("No tags on {}", self.config.settings.server.bright_cyan());
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        for (name, volt_id) in &tags {
+            let this = if *volt_id == self.config.volt_id { " (this project)".dimmed().to_string() } else { String::new() };
+            println!("  {} → {}{this}", name.bright_magenta(), volt_id.bright_cyan());
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    async fn warm(&self, ids: &[String]) -> Result<ExitCode> {
+        let (url, header) = self.config.get_server(Route::Warm)?;
+
+        let targets: Vec<_> = match ids.is_empty() {
+            true => vec![serde_json::json!({ "volt_id": self.config.volt_id })],
+            false => ids
+                .iter()
+                .map(|id| match id.split_once(':') {
+                    Some((volt_id, hash)) => serde_json::json!({ "volt_id": volt_id, "hash": hash }),
+                    None => serde_json::json!({ "volt_id": id }),
+                })
+                .collect(),
+        };
+
+        let response = self
+            .client
+            .post(&url)
+            .headers(header)
+            .json(&serde_json::json!({ "ids": targets }))
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        match response.status() {
+            StatusCode::NOT_IMPLEMENTED => Err(anyhow!("the server has no upstream to warm from").context(Failure::Config)),
+            status if !status.is_success() => Err(helpers::status_error(status)),
+            _ => {
+                println!("{} Prefetching {} cache(s) in the background", colors::OK, targets.len());
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    }
+
+    /// Pushes `size` random bytes to a server under a throwaway id and pulls them back, timing each direction.
+    /// The entry is deleted afterwards, which any token that pushed it may do, a failed cleanup only warns.
+    async fn server_bench(&self, name: &str, size: usize) -> Result<ExitCode> {
+        let mut config = self.config.clone();
+        config.settings.server = name.to_string();
+        config.volt_id = format!("{BENCH_ID_PREFIX}{}", &uuid::Uuid::new_v4().simple().to_string()[..12]);
+
+        let bench = Services::new(config, self.client.clone());
+        let send = |request: reqwest::RequestBuilder| async move { request.send().await.map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network)) };
+        let rate = |elapsed: Duration| format!("{}/s", helpers::format_size((size as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as usize));
+
+        let pb = helpers::spinner();
+        pb.set_message(format!("Generating {} of random data...", helpers::format_size(size)));
+
+        let mut payload = vec![0u8; size];
+        SystemRandom::new().fill(&mut payload).map_err(|_| anyhow!("no randomness available for the payload"))?;
+        let payload = bytes::Bytes::from(payload);
+        let digest = hash::content_digest(&payload);
+
+        pb.set_message("Measuring round trip...");
+        let (url, header) = bench.config.get_server(Route::Health)?;
+        let start = Instant::now();
+        let response = send(self.client.get(&url).headers(header)).await?;
+        if !response.status().is_success() {
+            pb.finish_and_clear();
+            return Err(helpers::status_error(response.status()).context(format!("server '{name}' isn't answering health checks")));
+        }
+        let round_trip = start.elapsed();
+
+        pb.set_message(format!("Uploading {}...", helpers::format_size(size)));
+        let (url, header) = bench.config.get_server(Route::Push)?;
+        let upload = Upload {
+            hash: &digest,
+            archive: &payload,
+            digest: digest.clone(),
+            uncompressed_size: size,
+            file_count: 1,
+        };
+
+        let start = Instant::now();
+        let sent = match bench.config.network.upload_concurrency.filter(|concurrency| *concurrency > 1 && size > PART_SIZE) {
+            Some(concurrency) => bench.upload_parts(&url, header, &upload, concurrency, &ProgressBar::hidden()).await,
+            None => bench.upload(&url, header, &upload).await,
+        };
+        let response = sent.map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        if !response.status().is_success() {
+            pb.finish_and_clear();
+            let status = response.status();
+            let reason = response.text().await.unwrap_or_default();
+            return Err(helpers::status_error(status).context(format!("the server refused the benchmark push {reason}").trim_end().to_string()));
+        }
+        let uploaded = start.elapsed();
+
+        pb.set_message(format!("Downloading {}...", helpers::format_size(size)));
+        let (url, header) = bench.config.get_server(Route::Pull)?;
+        let start = Instant::now();
+        let response = send(self.client.get(&url).headers(header)).await?;
+        let first_byte = start.elapsed();
+
+        if !response.status().is_success() {
+            pb.finish_and_clear();
+            return Err(helpers::status_error(response.status()).context("failed to pull the benchmark payload back"));
+        }
+
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+            received += chunk.len();
+            hasher.update(&chunk);
+        }
+        let downloaded = start.elapsed();
+        let intact = received == size && hash::bytes_to_hex(hasher.finalize()) == digest;
+
+        pb.set_message("Cleaning up...");
+        let (url, header) = bench.config.get_server(Route::Delete)?;
+        let cleaned = send(self.client.delete(&url).headers(header)).await;
+        pb.finish_and_clear();
+
+        println!("\nBenchmark of {} with {}", name.bright_magenta(), helpers::format_size(size).bright_cyan());
+        println!("  Round trip: {}", format!("{round_trip:.2?}").green());
+        println!("  Upload:     {} in {}", rate(uploaded).bright_cyan(), format!("{uploaded:.2?}").green());
+        println!(
+            "  Download:   {} in {}, first byte after {}",
+            rate(downloaded).bright_cyan(),
+            format!("{downloaded:.2?}").green(),
+            format!("{first_byte:.2?}").green()
+        );
+
+        let cleanup = match cleaned {
+            Ok(response) if response.status().is_success() => None,
+            Ok(response) => Some(helpers::status_error(response.status()).to_string()),
+            Err(err) => Some(err.to_string()),
+        };
+
+        if let Some(reason) = cleanup {
+            eprintln!("{} Couldn't remove the benchmark entry {}: {reason}", colors::WARN, bench.config.volt_id.yellow());
+        }
+
+        if !intact {
+            return Err(anyhow!("the payload came back different from what was pushed, {received} of {size} bytes"));
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Swaps a server's token without a window where it's locked out: the new token is registered with the same rights and saved,
+    /// and the old one is revoked only once the new one has been seen to work. Any failure puts the old token back.
+    async fn server_rotate_token(&self, name: &str, admin_token_file: Option<&Path>) -> Result<ExitCode> {
+        let Some(server) = self.config.servers.get(name).cloned() else {
+            return Err(anyhow!("server '{name}' does not exist").context(Failure::Config));
+        };
+
+        let old_token = server.token.clone().ok_or_else(|| anyhow!("server '{name}' has no token to rotate").context(Failure::Config))?;
+        let headers = |token: &str| {
+            self.config.auth_headers(&config::Server {
+                token: Some(token.to_string()),
+                ..server.clone()
+            })
+        };
+
+        let (whoami_url, _) = self.config.get_named_server(name, Route::Whoami)?;
+        let (tokens_url, _) = self.config.get_named_server(name, Route::Tokens)?;
+        let send = |request: reqwest::RequestBuilder| async move { request.send().await.map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network)) };
+
+        let response = send(self.client.get(&whoami_url).headers(headers(&old_token)?)).await?;
+        let identity: Identity = match response.status() {
+            StatusCode::NOT_FOUND => return Err(anyhow!("the server doesn't support token rotation, upgrade it").context(Failure::Config)),
+            status if !status.is_success() => return Err(helpers::status_error(status).context("the current token doesn't work")),
+            _ => response.json().await?,
+        };
+
+        let Some(old_name) = identity.name else {
+            return Err(anyhow!("'{name}' uses the server's auth_token, which can only be changed in the server's config").context(Failure::Config));
+        };
+
+        let admin_token = match admin_token_file {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+                .context(Failure::Config)?
+                .trim()
+                .to_string(),
+            None if identity.admin => old_token.clone(),
+            None => return Err(anyhow!("token '{old_name}' isn't an admin token, pass --admin-token-file to rotate it").context(Failure::Auth)),
+        };
+
+        let mut secret = [0u8; 32];
+        SystemRandom::new().fill(&mut secret).map_err(|_| anyhow!("no randomness available for the token"))?;
+        let new_token: String = secret.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        // later rotations replace the suffix rather than stacking another one
+        let base = old_name
+            .rsplit_once("-r")
+            .filter(|(_, stamp)| !stamp.is_empty() && stamp.chars().all(|c| c.is_ascii_digit()))
+            .map_or(old_name.as_str(), |(base, _)| base);
+        let new_name = format!("{base}-r{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+
+        let registration = serde_json::json!({ "name": new_name, "token": new_token, "admin": identity.admin, "namespace": identity.namespace });
+        let response = send(self.client.post(&tokens_url).headers(headers(&admin_token)?).json(&registration)).await?;
+
+        match response.status() {
+            StatusCode::CREATED => println!("{} Registered token {}", colors::OK, new_name.bright_cyan()),
+            StatusCode::CONFLICT => return Err(anyhow!("the server already has a token named '{new_name}', try again in a second")),
+            status => return Err(helpers::status_error(status).context("failed to register the new token")),
+        }
+
+        let path = self.config.get_servers()?.join(name);
+        let previous = fs::read_to_string(&path)?;
+        let rotated = config::Server {
+            token: Some(new_token.clone()),
+            ..server.clone()
+        };
+
+        let saved = fs::write(&path, helpers::format_server(&rotated)).map_err(anyhow::Error::from);
+        let verified = async {
+            saved?;
+            let response = send(self.client.get(&whoami_url).headers(headers(&new_token)?)).await?;
+            if !response.status().is_success() {
+                return Err(helpers::status_error(response.status()).context("the new token doesn't work"));
+            }
+
+            match response.json::<Identity>().await?.name {
+                Some(seen) if seen == new_name => Ok(()),
+                seen => Err(anyhow!("the server took the new token for {seen:?} instead of '{new_name}'")),
+            }
+        };
+
+        // the old token keeps working until here, so a failure only has to undo what was added
+        let revoked = match verified.await {
+            Ok(()) => {
+                // an admin token being rotated revokes itself with its replacement
+                let revoker = if admin_token == old_token { &new_token } else { &admin_token };
+                let response = send(self.client.delete(format!("{tokens_url}/{old_name}")).headers(headers(revoker)?)).await?;
 
-        let compressed = response.bytes().await?;
-        let decoder = zstd::stream::decode_all(&*compressed)?;
+                match response.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(helpers::status_error(status).context(format!("failed to revoke the old token '{old_name}'"))),
+                }
+            }
+            Err(err) => Err(err),
+        };
 
-        pb.set_message("Extracting...");
+        if let Err(err) = revoked {
+            fs::write(&path, previous)?;
 
-        for dir in &self.config.settings.cache {
-            if std::path::Path::new(dir).exists() {
-                tokio::fs::remove_dir_all(dir).await?;
+            let response = send(self.client.delete(format!("{tokens_url}/{new_name}")).headers(headers(&admin_token)?)).await;
+            if !response.is_ok_and(|response| response.status().is_success()) {
+                eprintln!("{} Couldn't revoke {} again, remove it on the server", colors::WARN, new_name.yellow());
             }
+
+            return Err(err.context(format!("rotation rolled back, '{name}' still uses token '{old_name}'")));
         }
 
-        let mut archive = tar::Archive::new(&*decoder);
-        archive.unpack(".")?;
+        println!("{} Verified the new token and revoked {}", colors::OK, old_name.yellow());
+        println!("\n{} Rotated the token for {}", colors::OK, name.bright_cyan());
 
-        pb.finish_with_message(format!("Cache restored in {}", format!("{:.2?}", start.elapsed()).green()));
         Ok(ExitCode::SUCCESS)
     }
 
-    pub async fn push_cache(&self) -> Result<ExitCode> {
-        let start = Instant::now();
-        let (url, header) = self.config.get_server(Route::Push)?;
+    async fn server_verify(&self, id: Option<String>, sha256: Option<&str>) -> Result<ExitCode> {
+        let mut config = self.config.clone();
+        if let Some(id) = id {
+            config.volt_id = id;
+        }
 
-        let hash_dirs = self.config.settings.hash.as_ref().unwrap_or(&self.config.settings.cache);
-        let hash = hash::compute_cache(hash_dirs)?;
+        let (url, header) = config.get_server(Route::Verify)?;
+        let pb = helpers::spinner();
+        pb.set_message(format!("Verifying {} on {}...", config.volt_id.bright_cyan(), config.settings.server.bright_magenta()));
 
-        let pb = ProgressBar::new_spinner();
-        let style = ProgressStyle::with_template("\n{spinner:.green} {msg}")
-            .unwrap()
-            .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏", "✓"]);
+        let mut request = self.client.get(&url).headers(header);
+        if let Some(sha256) = sha256 {
+            request = request.query(&[("sha256", sha256)]);
+        }
 
-        pb.set_style(style);
-        pb.enable_steady_tick(Duration::from_millis(80));
+        let response = request.send().await.map_err(|err| {
+            pb.finish_and_clear();
+            anyhow!(helpers::explain_request_error(&err)).context(Failure::Network)
+        })?;
 
-        if self.check_hash(&hash).await? {
-            pb.finish_with_message("Skipping cache push");
-            return Ok(ExitCode::SUCCESS);
-        }
+        pb.finish_and_clear();
 
-        pb.set_message("Creating archive...");
+        let verified: Verified = match response.status() {
+            StatusCode::NOT_FOUND => return Err(anyhow!("nothing is stored under {}, or the server is too old to verify", config.volt_id).context(Failure::CacheMiss)),
+            StatusCode::OK | StatusCode::UNPROCESSABLE_ENTITY => response.json().await?,
+            status => return Err(helpers::status_error(status)),
+        };
 
-        let mut buffer = Vec::new();
-        {
-            let mut ar = tar::Builder::new(&mut buffer);
-            for dir in &self.config.settings.cache {
-                ar.append_dir_all(dir, dir)?;
-            }
-            ar.finish()?;
+        let size = helpers::format_size(verified.bytes as usize);
+        if !verified.ok {
+            let detail = verified.detail.unwrap_or_default();
+            return Err(anyhow!("{} failed verification after {size}: {detail}", config.volt_id));
         }
 
-        pb.set_message("Compressing...");
+        match (verified.entries, verified.unpacked) {
+            (Some(entries), Some(unpacked)) => println!(
+                "{} {} is intact, {entries} entries, {} stored, {} unpacked",
+                colors::OK,
+                config.volt_id.bright_cyan(),
+                size.bright_cyan(),
+                helpers::format_size(unpacked as usize).bright_cyan()
+            ),
+            _ => println!("{} {} read back in full, {} stored", colors::OK, config.volt_id.bright_cyan(), size.bright_cyan()),
+        }
 
-        let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3)?;
-        {
-            encoder.multithread(4)?;
-            std::io::copy(&mut &buffer[..], &mut encoder)?;
+        if let Some(detail) = verified.detail {
+            println!("  {}", detail.dimmed());
         }
 
-        let compressed = encoder.finish()?;
-        let length = helpers::format_size(compressed.len());
+        println!("  sha256 {}", verified.sha256.dimmed());
+        Ok(ExitCode::SUCCESS)
+    }
 
-        let response = match self.client.post(&url).header("Authorization", header).header("X-Volt-Hash", hash).body(compressed).send().await {
-            Ok(next) => next,
-            Err(_) => {
-                pb.finish_and_clear();
-                return Err(anyhow!("unable to connect, is the server up?"));
-            }
+    async fn server_maintenance(&self, name: &str, mode: Option<MaintenanceMode>, message: Option<String>, retry_after: Option<u64>) -> Result<ExitCode> {
+        let (url, header) = self.config.get_named_server(name, Route::Maintenance)?;
+
+        let request = match mode {
+            Some(mode) => self.client.put(&url).json(&Maintenance { mode, message, retry_after }),
+            None => self.client.get(&url),
         };
 
-        pb.set_message("Uploading...");
+        let response = request
+            .headers(header)
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
 
-        if !response.status().is_success() {
-            pb.finish_and_clear();
-            return Err(anyhow!(response.status()));
+        let maintenance: Maintenance = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => return Err(anyhow!("the server doesn't support maintenance mode, upgrade it").context(Failure::Config)),
+            status if !status.is_success() => return Err(helpers::status_error(status)),
+            _ => response.json().await?,
+        };
+
+        let refusing = match maintenance.mode {
+            MaintenanceMode::Off => {
+                println!("{} {} is serving everything", colors::OK, name.bright_cyan());
+                return Ok(ExitCode::SUCCESS);
+            }
+            MaintenanceMode::Push => "pushes",
+            MaintenanceMode::All => "pushes and pulls",
+        };
+
+        println!("{} {} is in maintenance, refusing {refusing}", colors::WARN, name.bright_cyan());
+        if let Some(message) = maintenance.message {
+            println!("  message: {message}");
+        }
+        if let Some(retry_after) = maintenance.retry_after {
+            println!("  retry after: {retry_after}s");
         }
 
-        pb.finish_with_message(format!("Cached {} in {}", length.bright_cyan(), format!("{:.2?}", start.elapsed()).green()));
         Ok(ExitCode::SUCCESS)
     }
 
-    pub async fn run_build(&self) -> Result<ExitCode> {
-        let start = Instant::now();
-        let name = self.config.settings.wrap.split_whitespace().next().unwrap_or_default();
-
-        println!("🔥 Starting {}", self.config.settings.wrap);
-
-        if let Err(err) = self.pull_cache().await {
-            eprintln!("\n{} Cache pull failed: {err}", colors::FAIL);
+    async fn invalidate(&self, pattern: &str, tags: bool, namespace: Option<&str>, yes: bool) -> Result<ExitCode> {
+        if !yes {
+            return Err(anyhow!("invalidating deletes every matching cache on the server, pass --yes to confirm"));
         }
 
-        let status = Command::new("sh")
-            .arg("-c")
-            .arg(&self.config.settings.wrap)
-            .status()
-            .with_context(|| format!("{} Failed to execute {name}", colors::FAIL))?;
-
-        let code = status.code().unwrap_or_default();
+        let (url, header) = self.config.get_server(Route::Invalidate)?;
+
+        let response = self
+            .client
+            .post(&url)
+            .headers(header)
+            .json(&serde_json::json!({ "pattern": pattern, "tags": tags, "namespace": namespace }))
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(anyhow!("the server doesn't support invalidation, upgrade it").context(Failure::Config)),
+            StatusCode::BAD_REQUEST => Err(anyhow!("invalid pattern '{pattern}' or namespace").context(Failure::Config)),
+            status if !status.is_success() => Err(helpers::status_error(status)),
+            _ => {
+                let invalidated: Invalidated = response.json().await?;
+
+                for volt_id in &invalidated.ids {
+                    let this = if *volt_id == self.config.volt_id { " (this project)".dimmed().to_string() } else { String::new() };
+                    println!("  {} {}{this}", "-".red(), volt_id.bright_cyan());
+                }
 
-        if !status.success() {
-            eprintln!("{} Failed with exit code {code} in {}", colors::FAIL, format!("{:.2?}", start.elapsed()).yellow());
-            return Ok(ExitCode::FAILURE);
+                println!("{} Removed {} cache(s) matching {}", colors::OK, invalidated.removed, pattern.bright_magenta());
+                Ok(ExitCode::SUCCESS)
+            }
         }
+    }
+
+    fn config_show(&self, json: bool) -> Result<ExitCode> {
+        let servers = self
+            .config
+            .servers
+            .iter()
+            .map(|(name, server)| {
+                let redacted = config::Server {
+                    token: server.token.as_ref().map(|_| "********".to_string()),
+                    ..server.clone()
+                };
+                (name.as_str(), redacted)
+            })
+            .collect();
+
+        let resolved = ResolvedConfig {
+            config: &self.config,
+            path: &self.config.path,
+            home: self.config.get_home()?,
+            profile: self.config.profile.as_deref(),
+            servers,
+        };
 
-        if let Err(err) = self.push_cache().await {
-            eprintln!("\n{} Cache push failed: {err}", colors::FAIL);
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&resolved)?),
+            false => print!("{}", toml::to_string_pretty(&resolved)?),
         }
 
-        println!("{} Finished successfully in {}", colors::OK, format!("{:.2?}", start.elapsed()).yellow());
         Ok(ExitCode::SUCCESS)
     }
 
@@ -356,9 +2739,22 @@ impl Services {
             );
         }
 
+        let mut pin = None;
+        if tls {
+            let answer = Text::new("Pin the server's certificate sha256 (optional):")
+                .with_help_message("Trust only this leaf certificate instead of the system CA store, leave empty to skip")
+                .with_validator(|input: &str| match input.trim().is_empty() || cert::normalize_pin(input).is_some() {
+                    true => Ok(Validation::Valid),
+                    false => Ok(Validation::Invalid("Expected 64 hex characters, colons allowed".into())),
+                })
+                .prompt()?;
+            pin = cert::normalize_pin(&answer);
+        }
+
         let protocol = if tls { "tls://" } else { "" };
         let auth_part = token.as_ref().map_or(String::new(), |t| format!("{}@", t));
-        let url = format!("{}{}{}:{}", protocol, auth_part, address, port);
+        let pin_part = pin.as_ref().map_or(String::new(), |p| format!("#{}", p));
+        let url = format!("{}{}{}:{}{}", protocol, auth_part, address, port, pin_part);
 
         helpers::parse_server(&url).context("Invalid server configuration")?;
 
@@ -369,7 +2765,10 @@ impl Services {
             let url_str = url.as_str();
 
             if url_str.contains('@') {
-                let (protocol, rest) = if url_str.starts_with("tls://") { ("tls://", &url_str["tls://".len()..]) } else { ("", url_str) };
+                let (protocol, rest) = match url_str.strip_prefix("tls://") {
+                    Some(rest) => ("tls://", rest),
+                    None => ("", url_str),
+                };
 
                 if let Some(at_pos) = rest.find('@') {
                     let (token_part, host_port) = rest.split_at(at_pos);
@@ -389,6 +2788,101 @@ impl Services {
         Ok(ExitCode::SUCCESS)
     }
 
+    /// Writes a server file from a token read off stdin or a file, for provisioning scripts that have no TTY to prompt on.
+    async fn server_set_token(&mut self, name: &str, from_stdin: bool, token_file: Option<&Path>, address: Option<String>, tls: bool, test: bool) -> Result<ExitCode> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') {
+            return Err(anyhow!("invalid server name '{name}'").context(Failure::Config));
+        }
+
+        let token = match (from_stdin, token_file) {
+            (true, _) => std::io::read_to_string(std::io::stdin()).context("Failed to read the token from stdin")?,
+            (false, Some(path)) => fs::read_to_string(path).with_context(|| format!("Failed to read token file {path:?}")).context(Failure::Config)?,
+            (false, None) => unreachable!("clap requires a token source"),
+        };
+
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(anyhow!("the token is empty").context(Failure::Config));
+        }
+
+        let mut server = match (address, self.config.servers.get(name)) {
+            (Some(address), _) => config::Server {
+                tls,
+                address: address.trim().to_string(),
+                token: None,
+                pinned_cert_sha256: None,
+            },
+            (None, Some(server)) => server.clone(),
+            (None, None) => return Err(anyhow!("server '{name}' does not exist, pass --address to create it").context(Failure::Config)),
+        };
+
+        let created = !self.config.servers.contains_key(name);
+        server.token = Some(token.to_string());
+
+        let line = helpers::format_server(&server);
+        let parsed = helpers::parse_server(&line).context("Invalid server configuration").context(Failure::Config)?;
+
+        // `@` and `#` delimit the token in a server file, so one inside it would be read back as a different server
+        if parsed.token.as_deref() != Some(token) || parsed.address != server.address {
+            return Err(anyhow!("the token can't contain '@' or '#'").context(Failure::Config));
+        }
+
+        fs::write(self.config.get_servers()?.join(name), &line)?;
+        self.config.servers_stamp = None;
+
+        match created {
+            true => println!("\n{} Created server {} at {}", colors::OK, name.bright_cyan(), server.address.bright_blue()),
+            false => println!("\n{} Updated the token for {}", colors::OK, name.bright_cyan()),
+        }
+
+        if !test {
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        self.config.settings.server = name.to_string();
+        self.client = helpers::create_client(&mut self.config).context(Failure::Config)?;
+        self.server_test(Duration::from_secs(5), None).await
+    }
+
+    /// The non-interactive `server add`, for pasting a connection string copied from another machine.
+    async fn server_import(&mut self, name: &str, url: &str, force: bool, test: bool) -> Result<ExitCode> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') {
+            return Err(anyhow!("invalid server name '{name}'").context(Failure::Config));
+        }
+
+        let path = self.config.get_servers()?.join(name);
+        if path.exists() && !force {
+            return Err(anyhow!("server '{name}' already exists, pass --force to replace it").context(Failure::Config));
+        }
+
+        let server = helpers::parse_server(url).context("Invalid server connection string").context(Failure::Config)?;
+        if server.address.is_empty() {
+            return Err(anyhow!("the connection string has no address").context(Failure::Config));
+        }
+
+        fs::write(&path, helpers::format_server(&server))?;
+        self.config.servers_stamp = None;
+
+        let scheme = if server.tls { "tls://" } else { "" };
+        let token = if server.token.is_some() { ", with a token" } else { "" };
+        let pinned = if server.pinned_cert_sha256.is_some() { ", certificate pinned" } else { "" };
+        println!(
+            "\n{} Imported server {}: {}{}{token}{pinned}",
+            colors::OK,
+            name.bright_cyan(),
+            scheme.bright_blue(),
+            server.address.bright_blue()
+        );
+
+        if !test {
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        self.config.settings.server = name.to_string();
+        self.client = helpers::create_client(&mut self.config).context(Failure::Config)?;
+        self.server_test(Duration::from_secs(5), None).await
+    }
+
     async fn server_remove(&self, name: &str) -> Result<ExitCode> {
         let servers_dir = self.config.get_servers()?;
         let server_path = servers_dir.join(name);
@@ -422,29 +2916,432 @@ impl Services {
         Ok(ExitCode::SUCCESS)
     }
 
+    /// Starts `volt-server` against a temporary cache with a one-off token, and removes both again on Ctrl-C.
+    async fn server_local(&self) -> Result<ExitCode> {
+        let binary = std::env::current_exe()?.with_file_name(format!("volt-server{}", std::env::consts::EXE_SUFFIX));
+        let binary = if binary.exists() { binary } else { PathBuf::from("volt-server") };
+
+        // the server binds whatever its config says, so reserve a free port to hand it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let address = format!("127.0.0.1:{port}");
+        let token = uuid::Uuid::new_v4().simple().to_string();
+
+        let dir = self.config.get_temp_dir()?.join(format!("volt-local-{}", uuid::Uuid::new_v4().simple()));
+        fs::create_dir_all(dir.join("cache"))?;
+
+        let mut config = toml::Table::new();
+        config.insert("auth_token".into(), token.clone().into());
+        config.insert("cache_dir".into(), dir.join("cache").to_string_lossy().into_owned().into());
+        config.insert("address".into(), address.clone().into());
+        fs::write(dir.join("config.toml"), toml::to_string(&config)?)?;
+
+        let log = File::create(dir.join("server.log"))?;
+        let spawned = tokio::process::Command::new(&binary)
+            .current_dir(&dir)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn();
+
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(anyhow!("couldn't start {}: {err}, is volt-server installed next to volt or on PATH?", binary.display()).context(Failure::Config));
+            }
+        };
+
+        let version = format!("http://{address}/version");
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while self.client.get(&version).send().await.is_err() {
+            if Instant::now() > deadline || child.try_wait()?.is_some() {
+                let output = fs::read_to_string(dir.join("server.log")).unwrap_or_default();
+                let _ = child.kill().await;
+                let _ = fs::remove_dir_all(&dir);
+                return Err(anyhow!("the local server didn't come up:\n{}", output.trim()));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let line = format!("{token}@{address}");
+
+        println!("\n{} Local server listening on {}", colors::OK, address.bright_cyan());
+        println!("  Cache: {}", dir.join("cache").display());
+        println!("  Server string: {}", line.bright_green());
+        println!("  Add it with: echo -n '{line}' > {}", self.config.get_servers()?.join("local").display());
+        println!("\nPress Ctrl-C to stop, the cache is deleted on exit");
+
+        let exited = tokio::select! {
+            _ = tokio::signal::ctrl_c() => None,
+            status = child.wait() => Some(status?),
+        };
+
+        let _ = child.kill().await;
+        let _ = fs::remove_dir_all(&dir);
+
+        match exited {
+            None => {
+                println!("\n{} Stopped the local server", colors::OK);
+                Ok(ExitCode::SUCCESS)
+            }
+            Some(status) => Err(anyhow!("the local server exited unexpectedly ({status})")),
+        }
+    }
+
+    fn server_user_default(&self, name: Option<&str>, unset: bool) -> Result<ExitCode> {
+        let path = self.config.get_user_default()?;
+
+        if unset {
+            match fs::remove_file(&path) {
+                Ok(()) => println!("\n{} Removed the default server", colors::OK),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => println!("\n{} No default server was set", colors::OK),
+                Err(err) => return Err(err.into()),
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        let Some(name) = name else {
+            let current = self.config.user_default_server()?;
+            match &current {
+                Some(name) => println!("\nDefault server: {}", name.bright_cyan()),
+                None => println!("\n{} No default server, set one with `volt server default <name>`", colors::WARN),
+            }
+
+            if !self.config.settings.server.is_empty() && current.as_ref() != Some(&self.config.settings.server) {
+                println!("  this project uses {} instead, from settings.server", self.config.settings.server.bright_magenta());
+            }
+            return Ok(ExitCode::SUCCESS);
+        };
+
+        if !self.config.servers.contains_key(name) {
+            eprintln!("\n{} Server '{name}' not found, see `volt server list`", colors::FAIL);
+            return Ok(ExitCode::from(Failure::Config.code()));
+        }
+
+        fs::write(&path, name)?;
+        println!("\n{} {} is now the default server outside projects that set their own", colors::OK, name.bright_cyan());
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    fn server_set_default(&mut self, name: &str) -> Result<ExitCode> {
+        if !self.config.servers.contains_key(name) {
+            eprintln!("\n{} Server '{name}' not found, see `volt server list`", colors::FAIL);
+            return Ok(ExitCode::from(Failure::Config.code()));
+        }
+
+        if self.config.settings.server == name {
+            println!("\n{} {} is already the default server", colors::OK, name.bright_cyan());
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        let previous = self.config.set_server(name)?;
+        println!("\n{} Default server changed from {} to {}", colors::OK, previous.yellow(), name.bright_cyan());
+
+        Ok(ExitCode::SUCCESS)
+    }
+
     async fn server_info(&mut self, name: &str) -> Result<ExitCode> {
-        let servers_dir = self.config.get_servers()?;
-        let server_path = servers_dir.join(name);
+        self.config.load_servers()?;
 
-        let content = fs::read_to_string(&server_path).unwrap_or_else(|_| {
+        let Some(server) = self.config.servers.get(name).cloned() else {
             eprintln!("\n{} Server '{name}' not found", colors::FAIL);
-            process::exit(1)
-        });
-
-        let server = helpers::parse_server(&content)?;
+            return Ok(ExitCode::FAILURE);
+        };
 
         println!("\nServer information for {}", name.bright_magenta());
         println!("  Address: {}", server.address.bright_cyan());
         println!("  TLS: {}", if server.tls { "Enabled".green() } else { "Disabled".yellow() });
         println!("  Authentication: {}", if server.token.is_some() { "Token configured".green() } else { "No token".red() });
+        if let Some(pin) = &server.pinned_cert_sha256 {
+            println!("  Pinned certificate: {}", pin.bright_cyan());
+        }
+
+        self.config.settings.server = name.to_string();
+        self.server_test(Duration::from_secs(5), None).await?;
+        self.server_usage().await;
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Prints what the server reports on `/usage`, staying quiet for servers that predate it.
+    async fn server_usage(&self) {
+        let Ok((url, header)) = self.config.get_server(Route::Usage) else { return };
+
+        let usage = match self.client.get(&url).headers(header).timeout(Duration::from_secs(5)).send().await {
+            Ok(response) if response.status().is_success() => response.json::<Usage>().await.ok(),
+            _ => None,
+        };
+
+        let Some(usage) = usage else { return };
+        let size = |bytes: u64| helpers::format_size(bytes as usize);
+
+        println!("  Cache: {} entries, {}", usage.entries.to_string().bright_cyan(), size(usage.bytes).bright_cyan());
+
+        if let Some(disk) = usage.disk {
+            let free = match disk.free < disk.total / 10 {
+                true => size(disk.free).red(),
+                false => size(disk.free).green(),
+            };
+            println!("  Disk: {free} free of {}", size(disk.total));
+        }
+
+        let limits: Vec<_> = usage.limits.iter().filter_map(|(name, limit)| Some(format!("{name} {}", limit.as_ref()?))).collect();
+        println!("  Limits: {}", if limits.is_empty() { "none".dimmed().to_string() } else { limits.join(", ") });
+
+        for (name, namespace) in usage.namespaces {
+            let quota = namespace.quota.map_or("no quota".to_string(), |quota| format!("of {}", size(quota)));
+            println!("  Namespace {}: {} used {quota}", name.bright_magenta(), size(namespace.used));
+        }
+    }
+
+    async fn server_logs(&mut self, name: &str, tail: usize, follow: bool) -> Result<ExitCode> {
+        self.config.settings.server = name.to_string();
+        let (url, header) = self.config.get_server(Route::Logs)?;
+
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("tail", tail.to_string()), ("follow", follow.to_string())])
+            .headers(header)
+            .send()
+            .await
+            .map_err(|err| anyhow!(helpers::explain_request_error(&err)).context(Failure::Network))?;
+
+        if !response.status().is_success() {
+            return Err(helpers::status_error(response.status()));
+        }
+
+        if !follow {
+            for line in response.json::<Vec<LogLine>>().await? {
+                println!("{line}");
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        // server-sent events arrive as `data: {json}` lines, possibly split across chunks
+        let mut stream = response.bytes_stream();
+        let mut pending = String::new();
+
+        while let Some(chunk) = stream.next().await {
+            pending.push_str(&String::from_utf8_lossy(&chunk?));
+
+            while let Some(end) = pending.find('\n') {
+                let event = pending.drain(..=end).collect::<String>();
+
+                if let Some(data) = event.trim_end().strip_prefix("data:")
+                    && let Ok(line) = serde_json::from_str::<LogLine>(data.trim())
+                {
+                    println!("{line}");
+                }
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+
+    async fn server_diag(&mut self, name: &str) -> Result<ExitCode> {
+        const STAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+        let Some(server) = self.config.servers.get(name).cloned() else {
+            eprintln!("\n{} Server '{name}' not found", colors::FAIL);
+            return Ok(ExitCode::from(Failure::Config.code()));
+        };
 
         self.config.settings.server = name.to_string();
-        self.server_test().await?;
+        let network = ExitCode::from(Failure::Network.code());
+
+        let scheme = if server.tls { "https" } else { "http" };
+        let url = reqwest::Url::parse(&format!("{scheme}://{}", server.address)).map_err(|err| anyhow!("invalid server address '{}': {err}", server.address).context(Failure::Config))?;
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        println!("\nDiagnosing {} ({})", name.bright_magenta(), server.address.bright_cyan());
+
+        let started = Instant::now();
+        let outcome = helpers::within(tokio::time::timeout(STAGE_TIMEOUT, tokio::net::lookup_host((host.as_str(), port))).await).and_then(|addrs| {
+            let addrs: Vec<_> = addrs.collect();
+            match addrs.is_empty() {
+                true => Err("no addresses returned".to_string()),
+                false => Ok((addrs.iter().map(|addr| addr.ip().to_string()).collect::<Vec<_>>().join(", "), addrs)),
+            }
+        });
 
+        let Some(addrs) = helpers::diag_stage("DNS", started, outcome) else {
+            return Ok(network);
+        };
+
+        let started = Instant::now();
+        let outcome = helpers::within(tokio::time::timeout(STAGE_TIMEOUT, tokio::net::TcpStream::connect(&addrs[..])).await)
+            .and_then(|stream| stream.peer_addr().map_err(|err| err.to_string()))
+            .map(|addr| (format!("connected to {addr}"), addr));
+
+        let Some(addr) = helpers::diag_stage("TCP", started, outcome) else {
+            return Ok(network);
+        };
+
+        if server.tls {
+            let started = Instant::now();
+            let pin = server.pinned_cert_sha256.clone();
+
+            let outcome = tokio::task::spawn_blocking(move || {
+                let stream = std::net::TcpStream::connect_timeout(&addr, STAGE_TIMEOUT).map_err(|err| err.to_string())?;
+                stream.set_read_timeout(Some(STAGE_TIMEOUT)).map_err(|err| err.to_string())?;
+
+                // a pinned certificate is checked on its fingerprint below, so it may be one no CA would vouch for
+                let connector = native_tls::TlsConnector::builder().danger_accept_invalid_certs(pin.is_some()).build().map_err(|err| err.to_string())?;
+                let stream = connector.connect(&host, stream).map_err(|err| err.to_string())?;
+                let der = stream.peer_certificate().ok().flatten().and_then(|cert| cert.to_der().ok());
+
+                if let Some(pin) = &pin {
+                    let actual = der.as_deref().map(cert::fingerprint).ok_or("no peer certificate to compare with the pin")?;
+                    if &actual != pin {
+                        return Err(format!("certificate sha256 {actual} doesn't match the pinned {pin}"));
+                    }
+                }
+
+                let detail = match der.as_deref().and_then(cert::inspect) {
+                    None => "handshake ok, certificate details unavailable".to_string(),
+                    Some(cert) => {
+                        let remaining = format!("{} days left", cert.days_left);
+                        let remaining = if cert.days_left < 14 { remaining.yellow() } else { remaining.normal() };
+                        format!("CN={}, expires {} ({remaining})", cert.common_name.as_deref().unwrap_or("?"), cert.not_after)
+                    }
+                };
+                let detail = if pin.is_some() { format!("{detail}, pin matched") } else { detail };
+
+                Ok((detail, ()))
+            })
+            .await?;
+
+            if helpers::diag_stage("TLS", started, outcome).is_none() {
+                return Ok(network);
+            }
+        }
+
+        let (url, _) = self.config.get_server(Route::Version)?;
+        let started = Instant::now();
+
+        let outcome = match self.client.get(&url).timeout(STAGE_TIMEOUT).send().await {
+            Err(err) => Err(helpers::explain_request_error(&err)),
+            Ok(response) if !response.status().is_success() => Err(format!("/version returned {}", response.status())),
+            Ok(response) => match response.json::<ServerVersion>().await {
+                Ok(server) => Ok((format!("volt-server v{}, protocol {}", server.version, server.protocol), ())),
+                Err(_) => Err("unreadable /version response".to_string()),
+            },
+        };
+
+        if helpers::diag_stage("HTTP", started, outcome).is_none() {
+            return Ok(network);
+        }
+
+        let (url, header) = self.config.get_server(Route::Health)?;
+        let started = Instant::now();
+
+        let outcome = match self.client.get(&url).headers(header).timeout(STAGE_TIMEOUT).send().await {
+            Err(err) => Err(helpers::explain_request_error(&err)),
+            Ok(response) => match response.status() {
+                status if status.is_success() => Ok(("token accepted".to_string(), ())),
+                status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                    helpers::diag_stage::<()>("Auth", started, Err(format!("token rejected ({status})")));
+                    return Ok(ExitCode::from(Failure::Auth.code()));
+                }
+                status => Err(format!("/health returned {status}")),
+            },
+        };
+
+        if helpers::diag_stage("Auth", started, outcome).is_none() {
+            return Ok(network);
+        }
+
+        println!("\n{} All checks passed for {name}", colors::OK);
         Ok(ExitCode::SUCCESS)
     }
 
-    async fn server_test(&self) -> Result<ExitCode> {
+    /// Checks every server concurrently, each bounded by `timeout`, and prints one row per server.
+    async fn server_test_all(&self, timeout: Duration) -> Result<ExitCode> {
+        if self.config.servers.is_empty() {
+            eprintln!("\n{} No servers configured", colors::WARN);
+            return Ok(ExitCode::FAILURE);
+        }
+
+        let pb = helpers::spinner();
+        pb.set_message(format!("Testing {} servers...", self.config.servers.len()));
+
+        let tests = self.config.servers.keys().map(|name| async move {
+            let probe = async {
+                let (url, header) = self.config.get_named_server(name, Route::Health)?;
+                let start = Instant::now();
+                let response = self.client.get(&url).headers(header).timeout(timeout).send().await?;
+                let latency = start.elapsed();
+
+                let (url, _) = self.config.get_named_server(name, Route::Version)?;
+                let version = match self.client.get(&url).timeout(timeout).send().await {
+                    Ok(response) if response.status().is_success() => response.json::<ServerVersion>().await.ok(),
+                    _ => None,
+                };
+
+                anyhow::Ok((response.status(), latency, version))
+            };
+
+            (name, probe.await)
+        });
+
+        let mut results: Vec<_> = futures::stream::iter(tests).buffer_unordered(PARALLEL_TESTS).collect().await;
+        results.sort_by_key(|(name, _)| *name);
+        pb.finish_and_clear();
+
+        let width = results.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+        let (mut unreachable, mut rejected) = (0, 0);
+
+        println!();
+        for (name, result) in results {
+            let name = format!("{name:<width$}").bright_magenta();
+
+            let (status, latency, version) = match result {
+                Err(err) => {
+                    unreachable += 1;
+                    let reason = err.downcast_ref::<reqwest::Error>().map_or_else(|| err.to_string(), helpers::explain_request_error);
+                    println!("{} {name}  {}", colors::FAIL, reason.red());
+                    continue;
+                }
+                Ok(result) => result,
+            };
+
+            let auth = match status {
+                status if status.is_success() => "auth ok".green(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    rejected += 1;
+                    "auth rejected".red()
+                }
+                status => {
+                    unreachable += 1;
+                    status.to_string().red()
+                }
+            };
+
+            let version = match version {
+                Some(server) if server.protocol == PROTOCOL_VERSION => format!("v{}", server.version).normal(),
+                Some(server) => format!("v{} (protocol {})", server.version, server.protocol).yellow(),
+                None => "unknown version".yellow(),
+            };
+
+            let mark = if status.is_success() { &colors::OK } else { &colors::FAIL };
+            println!("{mark} {name}  {:>8}  {version}  {auth}", format!("{:.0?}", latency).bright_cyan());
+        }
+
+        match (unreachable, rejected) {
+            (0, 0) => Ok(ExitCode::SUCCESS),
+            (0, _) => Ok(ExitCode::from(Failure::Auth.code())),
+            _ => Ok(ExitCode::from(Failure::Network.code())),
+        }
+    }
+
+    /// Checks the health route once, or with `wait` keeps retrying while the server can't be reached or answers 5xx,
+    /// so a server that is still starting isn't reported as down. An auth rejection is final either way.
+    async fn server_test(&self, timeout: Duration, wait: Option<Duration>) -> Result<ExitCode> {
         let name = &self.config.settings.server;
 
         let (url, header) = self.config.get_server(Route::Health).unwrap_or_else(|_| {
@@ -452,14 +3349,55 @@ impl Services {
             process::exit(1)
         });
 
-        let response = self.client.get(&url).header("Authorization", header).send().await.context("Connection failed")?;
+        let start = Instant::now();
+        let pb = match wait {
+            Some(_) => helpers::spinner(),
+            None => ProgressBar::hidden(),
+        };
 
-        if response.status().is_success() {
-            println!("\n{} Successfully connected to {name}", colors::OK);
-        } else {
-            println!("\n{} Connection failed: {}", colors::FAIL, response.status());
+        let response = loop {
+            let attempt = self.client.get(&url).headers(header.clone()).timeout(timeout).send().await;
+            let retryable = match &attempt {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+
+            match wait {
+                Some(wait) if retryable && start.elapsed() < wait => {
+                    pb.set_message(format!("Waiting for {name} to come up... {}s", start.elapsed().as_secs()));
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+                _ => break attempt,
+            }
+        };
+
+        pb.finish_and_clear();
+        if wait.is_some() {
+            println!("\nWaited {:.1?} for {name}", start.elapsed());
         }
 
-        Ok(ExitCode::SUCCESS)
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                println!("\n{} {}", colors::FAIL, helpers::explain_request_error(&err));
+                return Ok(ExitCode::from(Failure::Network.code()));
+            }
+        };
+
+        match response.status() {
+            status if status.is_success() => {
+                println!("\n{} Successfully connected to {name}", colors::OK);
+                self.check_version().await?;
+                Ok(ExitCode::SUCCESS)
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                println!("\n{} Authentication rejected ({}), check the server token", colors::FAIL, response.status());
+                Ok(ExitCode::from(Failure::Auth.code()))
+            }
+            status => {
+                println!("\n{} Connection failed: {status}", colors::FAIL);
+                Ok(ExitCode::FAILURE)
+            }
+        }
     }
 }
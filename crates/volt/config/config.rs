@@ -1,8 +1,15 @@
-use crate::{colors, helpers::parse_server};
+use crate::{colors, failure::Failure, helpers::parse_server};
 use anyhow::{Context, Result, anyhow};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, path::PathBuf, process};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 use toml::Value;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use toml_edit::{DocumentMut, Item};
 use uuid::Uuid;
 
 const DEFAULT_CONFIG: &str = include_str!("default.toml");
@@ -15,6 +22,27 @@ pub enum Route {
     Pull,
     Health,
     Check,
+    Version,
+    Logs,
+    Warm,
+    Usage,
+    Tag,
+    Tags,
+    Invalidate,
+    Verify,
+    Whoami,
+    Tokens,
+    Maintenance,
+    Delete,
+}
+
+/// What `VoltConfig::init` found at the config path, leaving the reporting to the caller.
+pub enum Init {
+    /// There was no config, so the default template was written for the user to fill in.
+    Created,
+    /// The config still matches the default template.
+    NeedsEditing,
+    Loaded(Box<VoltConfig>),
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
@@ -22,11 +50,67 @@ pub struct VoltConfig {
     pub volt_id: String,
     pub settings: Config,
 
+    #[serde(default)]
+    pub network: Network,
+
+    #[serde(default)]
+    pub local_cache: LocalCache,
+
+    #[serde(default)]
+    pub cache: CacheLimits,
+
+    #[serde(default)]
+    pub run: Run,
+
+    #[serde(default)]
+    pub archive: Archive,
+
+    #[serde(default)]
+    pub compression: Compression,
+
+    #[serde(default)]
+    pub security: Security,
+
+    #[serde(default, rename = "hash")]
+    pub hashing: Hashing,
+
+    #[serde(default)]
+    pub ui: Ui,
+
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
     #[serde(skip)]
     pub path: PathBuf,
 
+    /// The config as read from stdin or a URL when `path` is `-` or `http(s)://...`, there being no file behind it.
+    #[serde(skip)]
+    pub inline: Option<String>,
+
+    #[serde(skip)]
+    pub home: Option<PathBuf>,
+
+    #[serde(skip)]
+    pub profile: Option<String>,
+
+    /// Globs from `--exclude`, already part of `settings.exclude` but kept apart for the processes volt spawns.
+    #[serde(skip)]
+    pub cli_exclude: Vec<String>,
+
+    /// Set by `--verbose`, for details most runs don't need.
+    #[serde(skip)]
+    pub verbose: bool,
+
+    /// Set by `run --dry-run`, which mustn't write anything: state paths come back without their directories being created.
+    #[serde(skip)]
+    pub read_only: bool,
+
     #[serde(skip)]
     pub servers: Servers,
+
+    /// Modification time of the servers dir when `servers` was read, so an unchanged dir isn't parsed again.
+    #[serde(skip)]
+    pub servers_stamp: Option<SystemTime>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
@@ -35,6 +119,238 @@ pub struct Config {
     pub cache: Vec<String>,
     pub wrap: String,
     pub hash: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    #[serde(default)]
+    pub strict_version: bool,
+
+    /// Remember per-file hashes between runs, so only files whose size or mtime changed are read again.
+    #[serde(default)]
+    pub hash_cache: bool,
+
+    /// Where staged downloads and other scratch files go, the system temp dir when unset.
+    pub temp_dir: Option<PathBuf>,
+
+    /// What to do when one `cache` entry is the same as, or nested inside, another.
+    #[serde(default)]
+    pub cache_overlap: CacheOverlap,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheOverlap {
+    #[default]
+    Dedupe,
+    Error,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Network {
+    pub max_upload_bytes_per_sec: Option<u64>,
+    pub max_download_bytes_per_sec: Option<u64>,
+
+    #[serde(default)]
+    pub prefer_uncompressed: bool,
+
+    pub auth_header: Option<String>,
+    pub auth_scheme: Option<String>,
+
+    /// Replaces the `volt/<version>` user agent outright, `user_agent_suffix` appends to it instead.
+    pub user_agent: Option<String>,
+    pub user_agent_suffix: Option<String>,
+
+    /// Extra servers each successful push is copied to.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    pub mirror_concurrency: Option<usize>,
+
+    /// Splits pushes into parts uploaded this many at a time, for links where one stream can't fill the bandwidth.
+    pub upload_concurrency: Option<usize>,
+
+    /// Connection failures within `breaker_window_secs` after which `run` stops trying the server for a while, 0 turns that off.
+    pub breaker_failures: Option<u32>,
+    pub breaker_window_secs: Option<u64>,
+    pub breaker_cooldown_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct LocalCache {
+    pub max_bytes: Option<u64>,
+    pub max_age_days: Option<u64>,
+}
+
+impl LocalCache {
+    pub fn max_age(&self) -> Option<Duration> { self.max_age_days.map(|days| Duration::from_secs(days * 86_400)) }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct CacheLimits {
+    /// Files above this size are left out of both the key and the archive, so one stray core dump can't bloat every push.
+    pub max_file_bytes: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Run {
+    #[serde(default)]
+    pub background_push: bool,
+    #[serde(default)]
+    pub require_push: bool,
+    /// Push even when the build fails, for caches whose intermediate outputs stay valid, the run still fails.
+    #[serde(default)]
+    pub push_on_failure: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Archive {
+    #[serde(default)]
+    pub reproducible: bool,
+    pub max_entries: Option<usize>,
+    pub max_path_depth: Option<usize>,
+
+    /// Restore over the existing tree, rewriting only changed files, instead of clearing it and extracting everything.
+    #[serde(default)]
+    pub incremental: bool,
+
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+/// What a pull does with an archived file that would replace a different one on disk.
+/// A full restore counts them before clearing the cache directories, and keeps skipped files through the clearing.
+#[derive(Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    #[default]
+    Overwrite,
+    /// Keep the local file.
+    Skip,
+    /// Abort the pull before anything on disk is touched.
+    Error,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Compression {
+    pub dictionary: Option<PathBuf>,
+
+    /// Starting point for the settings below, each of which overrides it when set.
+    #[serde(default)]
+    pub preset: Preset,
+    pub level: Option<i32>,
+    /// Threads compressing frames in parallel, 0 for one per core.
+    pub threads: Option<usize>,
+    pub long_distance: Option<bool>,
+
+    /// Decompress every archive right after compressing it and compare it to the original before it's uploaded.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Quickest turnaround, for CI next to the server.
+    Fast,
+    #[default]
+    Balanced,
+    /// Smallest archives, for slow or metered links.
+    Max,
+}
+
+/// The zstd settings a push compresses with, once the preset and any explicit fields are resolved.
+pub struct Tuning {
+    pub level: i32,
+    pub threads: usize,
+    pub long_distance: bool,
+}
+
+impl Preset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Fast => "fast",
+            Preset::Balanced => "balanced",
+            Preset::Max => "max",
+        }
+    }
+}
+
+impl Compression {
+    pub fn tuning(&self) -> Tuning {
+        let (level, long_distance) = match self.preset {
+            Preset::Fast => (1, false),
+            Preset::Balanced => (3, false),
+            Preset::Max => (19, true),
+        };
+
+        Tuning {
+            level: self.level.unwrap_or(level),
+            threads: self.threads.unwrap_or(0),
+            long_distance: self.long_distance.unwrap_or(long_distance),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Security {
+    /// Hex key archives are sealed with before upload, the server only ever stores ciphertext.
+    pub encryption_key: Option<String>,
+}
+
+impl Security {
+    pub fn key(&self) -> Result<Option<[u8; 32]>> { self.encryption_key.as_deref().map(crate::encryption::parse_key).transpose() }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Hashing {
+    /// Milliseconds a Merkle build may take before the cache key is computed by sampling instead.
+    pub merkle_time_budget_ms: Option<u64>,
+
+    /// Key tracked directories by their git tree ids instead of their files, see `hash::compute_git`.
+    #[serde(default)]
+    pub git: bool,
+
+    /// Leave modification times out of the cache key, for fleets whose clocks or checkouts disagree on them.
+    #[serde(default)]
+    pub ignore_mtime: bool,
+
+    /// Threads hashing may run on, which bounds the files it holds open at once, half the soft `RLIMIT_NOFILE` when unset.
+    pub max_open_files: Option<usize>,
+
+    #[serde(default)]
+    pub over: HashOver,
+}
+
+/// What the cache key is a digest of.
+#[derive(Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashOver {
+    /// The files in the hashed directories, walked or Merkle-hashed.
+    #[default]
+    Tree,
+    /// The reproducible tar of those directories, byte for byte what a push archives only when they're the cache directories,
+    /// which a separate `[settings] hash` list breaks.
+    Archive,
+}
+
+impl Hashing {
+    pub fn merkle_time_budget(&self) -> Option<Duration> { self.merkle_time_budget_ms.map(Duration::from_millis) }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Ui {
+    /// End push, pull and run with one `volt: action=... status=...` line for log-based monitoring.
+    #[serde(default)]
+    pub summary_line: bool,
+}
+
+/// A separately keyed cache within the same project, overriding the `[settings]` it names.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub volt_id: String,
+    pub cache: Vec<String>,
+    pub hash: Option<Vec<String>>,
+    pub wrap: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -42,69 +358,231 @@ pub struct Server {
     pub tls: bool,
     pub address: String,
     pub token: Option<String>,
+    /// Leaf certificate sha256 this server must present, trusted instead of the system CA store.
+    pub pinned_cert_sha256: Option<String>,
 }
 
 impl VoltConfig {
-    pub fn new(path: PathBuf) -> Self {
-        let mut config = Self::default();
-        config.path = path;
-        return config;
-    }
+    pub fn new(path: PathBuf, home: Option<PathBuf>) -> Self { Self { path, home, ..Default::default() } }
 
-    pub fn init(&self) -> Result<VoltConfig> {
-        if self.path.exists() {
+    pub fn init(&self) -> Result<Init> {
+        if self.inline.is_some() || self.path.exists() {
             return self.load();
         }
 
         let config = DEFAULT_CONFIG.replace("{volt_id}", &Uuid::new_v4().to_string());
-
         fs::write(&self.path, config)?;
-        println!("{} Created a new config - please fill it out.", crate::colors::BOLT);
 
-        process::exit(0);
+        Ok(Init::Created)
+    }
+
+    /// Returns this config with a profile's key, cache and build settings applied.
+    pub fn with_profile(&self, name: &str) -> Result<VoltConfig> {
+        let profile = self.profiles.get(name).ok_or_else(|| anyhow!("profile '{name}' does not exist").context(Failure::Config))?;
+        let mut config = self.clone();
+
+        config.profile = Some(name.to_string());
+        config.volt_id = profile.volt_id.clone();
+        config.settings.cache = resolve_overlap(profile.cache.clone(), self.settings.cache_overlap).context(Failure::Config)?;
+        config.settings.hash = profile.hash.clone();
+
+        if let Some(wrap) = &profile.wrap {
+            config.settings.wrap = wrap.clone();
+        }
+
+        Ok(config)
+    }
+
+    pub fn regenerate_id(&mut self) -> Result<String> {
+        let volt_id = Uuid::new_v4().to_string();
+        self.edit(|document| replace_value(&mut document["volt_id"], volt_id.as_str().into()))?;
+
+        Ok(std::mem::replace(&mut self.volt_id, volt_id))
+    }
+
+    /// Points `settings.server` at `name`, returning the server it replaced.
+    pub fn set_server(&mut self, name: &str) -> Result<String> {
+        self.edit(|document| replace_value(&mut document["settings"]["server"], name.into()))?;
+
+        Ok(std::mem::replace(&mut self.settings.server, name.to_string()))
     }
 
-    pub fn get_server(&self, route: Route) -> Result<(String, String)> {
-        let server = self.servers.get(&self.settings.server).ok_or_else(|| {
-            let name = &self.settings.server;
-            anyhow!("server '{name}' does not exist")
-        })?;
+    /// Rewrites the config file in place through `toml_edit`, so comments and layout survive.
+    pub fn edit(&self, apply: impl FnOnce(&mut DocumentMut)) -> Result<()> {
+        if self.inline.is_some() {
+            return Err(anyhow!("the config was read from {:?}, change it at its source instead", self.path).context(Failure::Config));
+        }
+
+        let content = fs::read_to_string(&self.path)?;
+        let mut document = content.parse::<DocumentMut>().with_context(|| format!("Failed to parse {:?}", self.path))?;
+
+        apply(&mut document);
+
+        let staging = self.path.with_extension("toml.tmp");
+        fs::write(&staging, document.to_string())?;
+        fs::rename(&staging, &self.path).with_context(|| format!("Failed to write {:?}", self.path))?;
+
+        Ok(())
+    }
+
+    pub fn get_server(&self, route: Route) -> Result<(String, HeaderMap)> { self.get_named_server(&self.settings.server, route) }
+
+    pub fn get_named_server(&self, name: &str, route: Route) -> Result<(String, HeaderMap)> {
+        let server = self.servers.get(name).ok_or_else(|| anyhow!("server '{name}' does not exist").context(Failure::Config))?;
 
         let route = match route {
             Route::Push => "push",
             Route::Pull => "pull",
             Route::Health => "health",
             Route::Check => "check",
+            Route::Version => "version",
+            Route::Logs => "logs",
+            Route::Warm => "warm",
+            Route::Usage => "usage",
+            Route::Tag => "tag",
+            Route::Tags => "tags",
+            Route::Invalidate => "invalidate",
+            Route::Verify => "verify",
+            Route::Whoami => "whoami",
+            Route::Tokens => "admin/tokens",
+            Route::Maintenance => "admin/maintenance",
+            Route::Delete => "cache",
         };
 
         let tls = if server.tls { "https" } else { "http" };
-        let url = format!("{tls}://{}/{route}/{}", server.address, self.volt_id);
-        let header = server.token.as_ref().map_or_else(|| String::new(), |t| format!("Bearer {}", t));
+        let url = match route {
+            "version" | "logs" | "warm" | "usage" | "tag" | "tags" | "invalidate" | "whoami" | "admin/tokens" | "admin/maintenance" => format!("{tls}://{}/{route}", server.address),
+            // the health route only echoes the id back, so server commands run outside a project still have one to send
+            "health" if self.volt_id.is_empty() => format!("{tls}://{}/{route}/-", server.address),
+            _ => format!("{tls}://{}/{route}/{}", server.address, self.volt_id),
+        };
+        Ok((url, self.auth_headers(server)?))
+    }
+
+    /// Builds the auth header the server expects, `Authorization: Bearer <token>` unless `[network]` overrides it.
+    pub fn auth_headers(&self, server: &Server) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+
+        if let Some(token) = &server.token {
+            let name = self.network.auth_header.as_deref().unwrap_or("Authorization");
+            let value = match self.network.auth_scheme.as_deref().unwrap_or("Bearer") {
+                "" => token.to_owned(),
+                scheme => format!("{scheme} {token}"),
+            };
+
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow!("invalid auth_header '{name}'").context(Failure::Config))?;
+            headers.insert(name, HeaderValue::from_str(&value).context("server token is not a valid header value")?);
+        }
+
+        Ok(headers)
+    }
+
+    /// `--config-dir` or `VOLT_HOME`, then `~/.volt`, then `$XDG_DATA_HOME/volt` for containers without a home directory.
+    pub fn get_home(&self) -> Result<PathBuf> {
+        let xdg_data = || std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()).map(|dir| PathBuf::from(dir).join("volt"));
+
+        let path = match &self.home {
+            Some(path) => path.to_owned(),
+            None => match home::home_dir().map(|home| home.join(".volt")).or_else(xdg_data) {
+                Some(path) => path,
+                None => return Err(anyhow!("no home directory to keep volt's state in, set VOLT_HOME, --config-dir or XDG_DATA_HOME").context(Failure::Config)),
+            },
+        };
+
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
 
-        Ok((url, header))
+        Ok(path)
     }
 
     pub fn get_servers(&self) -> Result<PathBuf> {
-        match home::home_dir() {
-            Some(mut path) => {
-                path.push(".volt");
-                path.push("servers");
+        let path = self.get_home()?.join("servers");
 
-                if !path.exists() {
-                    fs::create_dir_all(&path)?;
-                }
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
 
-                return Ok(path);
-            }
-            None => {
-                eprintln!("{} Impossible to get your home directory", colors::FAIL);
-                process::exit(0);
-            }
+        Ok(path)
+    }
+
+    /// The file naming the server used when no project config sets `settings.server`.
+    pub fn get_user_default(&self) -> Result<PathBuf> { Ok(self.get_home()?.join("default")) }
+
+    pub fn user_default_server(&self) -> Result<Option<String>> {
+        match fs::read_to_string(self.get_user_default()?) {
+            Ok(name) => Ok(Some(name.trim().to_string()).filter(|name| !name.is_empty())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
         }
     }
 
+    pub fn get_lock(&self) -> Result<PathBuf> {
+        let path = self.get_home()?.join("locks");
+
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+
+        Ok(path.join(format!("{}.lock", self.volt_id)))
+    }
+
+    /// `[settings] temp_dir`, created on first use, or the system temp dir.
+    pub fn get_temp_dir(&self) -> Result<PathBuf> {
+        let Some(path) = &self.settings.temp_dir else {
+            return Ok(std::env::temp_dir());
+        };
+
+        fs::create_dir_all(path).with_context(|| format!("Failed to create temp_dir {path:?}")).context(Failure::Config)?;
+        Ok(path.to_owned())
+    }
+
+    /// Where `run --only-if-changed` remembers the hash of the last successful build.
+    pub fn get_last_run(&self) -> Result<PathBuf> {
+        let path = self.get_home()?.join("last-run");
+
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+
+        Ok(path.join(&self.volt_id))
+    }
+
+    /// Where `[settings] hash_cache` keeps per-file hashes for this project, safe to delete at any time.
+    pub fn get_hash_cache(&self) -> Result<PathBuf> {
+        let path = self.get_home()?.join("hash-cache");
+
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+
+        Ok(path.join(&self.volt_id))
+    }
+
+    /// Where the last `/check` answer for this project and server is kept while the server allows reusing it.
+    pub fn get_check_cache(&self) -> Result<PathBuf> {
+        let path = self.get_home()?.join("checks");
+
+        if !self.read_only && !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+
+        Ok(path.join(format!("{}@{}", self.volt_id, self.settings.server)))
+    }
+
+    /// Where the connection failures `run` has seen for the selected server are kept, shared by every project using it.
+    pub fn get_breaker_state(&self) -> Result<PathBuf> { Ok(self.get_home()?.join("state").join(&self.settings.server)) }
+
+    /// Reads every server file, unless the servers dir is unchanged since the last read.
+    /// Adding, removing or renaming a file moves the dir's mtime, overwriting one in place does not, so writers clear `servers_stamp`.
     pub fn load_servers(&mut self) -> Result<()> {
         let path = self.get_servers()?;
+        let stamp = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+
+        if stamp.is_some() && stamp == self.servers_stamp {
+            return Ok(());
+        }
+
         let mut servers = BTreeMap::new();
 
         for entry in fs::read_dir(path)? {
@@ -127,11 +605,16 @@ impl VoltConfig {
             servers.insert(file_name, server);
         }
 
-        Ok(self.servers = servers)
+        self.servers = servers;
+        self.servers_stamp = stamp;
+        Ok(())
     }
 
-    fn load(&self) -> Result<VoltConfig> {
-        let content = fs::read_to_string(&self.path)?;
+    fn load(&self) -> Result<Init> {
+        let content = match &self.inline {
+            Some(content) => content.clone(),
+            None => fs::read_to_string(&self.path)?,
+        };
         let default_toml: Value = toml::from_str(DEFAULT_CONFIG)?;
         let current_toml: Value = toml::from_str(&content)?;
 
@@ -142,11 +625,161 @@ impl VoltConfig {
         };
 
         if filter_volt_id(&default_toml) == filter_volt_id(&current_toml) {
-            eprintln!("😅 Configuration matches default template - please edit it.");
-            process::exit(1);
+            return Ok(Init::NeedsEditing);
+        }
+
+        // includes of an inline config resolve against the working directory
+        let merged = match self.inline {
+            Some(_) => expand_includes(current_toml, Path::new("."), &mut Vec::new())?,
+            None => resolve_includes(&self.path, &mut Vec::new())?,
+        };
+
+        let mut config: VoltConfig = merged.try_into()?;
+        config.path = self.path.to_owned();
+        config.inline = self.inline.clone();
+        config.home = self.home.to_owned();
+        config.settings.cache = resolve_overlap(std::mem::take(&mut config.settings.cache), config.settings.cache_overlap)?;
+
+        Ok(Init::Loaded(Box::new(config)))
+    }
+}
+
+/// Finds `cache` entries that repeat or sit inside another entry, which would otherwise be archived twice.
+/// `dedupe` keeps only the shallowest path of each overlapping group, `error` refuses the list outright.
+pub fn resolve_overlap(dirs: Vec<String>, mode: CacheOverlap) -> Result<Vec<String>> {
+    let normalize = |dir: &str| -> PathBuf { Path::new(dir).components().filter(|c| !matches!(c, std::path::Component::CurDir)).collect() };
+    let paths: Vec<PathBuf> = dirs.iter().map(|dir| normalize(dir)).collect();
+
+    let mut kept: Vec<String> = Vec::with_capacity(dirs.len());
+    let mut overlaps = Vec::new();
+
+    for (index, path) in paths.iter().enumerate() {
+        // an earlier duplicate, or any other entry this one is nested in, covers it already
+        let covered_by = paths
+            .iter()
+            .enumerate()
+            .find(|(other, parent)| *other != index && path.starts_with(parent) && (parent.as_path() != path.as_path() || *other < index));
+
+        match covered_by {
+            Some((other, _)) => overlaps.push((dirs[index].clone(), dirs[other].clone())),
+            None => kept.push(dirs[index].clone()),
+        }
+    }
+
+    if overlaps.is_empty() {
+        return Ok(dirs);
+    }
+
+    let described = overlaps.iter().map(|(dir, parent)| format!("'{dir}' is covered by '{parent}'")).collect::<Vec<_>>().join(", ");
+
+    match mode {
+        CacheOverlap::Error => Err(anyhow!("cache entries overlap: {described}, set cache_overlap = \"dedupe\" to keep only the outermost")),
+        CacheOverlap::Dedupe => {
+            eprintln!("{} Overlapping cache entries, {described}, caching {}", colors::WARN, kept.join(", "));
+            Ok(kept)
+        }
+    }
+}
+
+/// Loads a config file with its `include` list merged underneath it, includes resolved relative to the including file.
+fn resolve_includes(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+    let canonical = path.canonicalize().with_context(|| format!("Failed to read config {:?}", path))?;
+
+    if stack.contains(&canonical) {
+        return Err(anyhow!("include cycle detected at {:?}", path));
+    }
+
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read config {:?}", path))?;
+    let current: Value = toml::from_str(&content).with_context(|| format!("Failed to parse config {:?}", path))?;
+
+    stack.push(canonical);
+    let merged = expand_includes(current, path.parent().unwrap_or(Path::new(".")), stack);
+    stack.pop();
+
+    merged
+}
+
+/// Merges the files listed in `include`, relative to `base`, underneath the config that lists them.
+fn expand_includes(mut current: Value, base: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+    let includes = current.as_table_mut().and_then(|t| t.remove("include"));
+    let mut merged = Value::Table(Default::default());
+
+    if let Some(includes) = includes {
+        let includes: Vec<String> = includes.try_into().with_context(|| format!("include in {:?} must be a list of paths", base))?;
+
+        for include in includes {
+            merge_toml(&mut merged, resolve_includes(&base.join(include), stack)?);
         }
+    }
+
+    merge_toml(&mut merged, current);
+    Ok(merged)
+}
+
+fn merge_toml(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn replace_value(item: &mut Item, mut value: toml_edit::Value) {
+    if let Some(previous) = item.as_value() {
+        *value.decor_mut() = previous.decor().clone();
+    }
+
+    *item = Item::Value(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs(list: &[&str]) -> Vec<String> { list.iter().map(|dir| dir.to_string()).collect() }
+
+    #[test]
+    fn disjoint_dirs_pass_through() {
+        for mode in [CacheOverlap::Dedupe, CacheOverlap::Error] {
+            assert_eq!(resolve_overlap(dirs(&["target", "node_modules", "dist"]), mode).unwrap(), dirs(&["target", "node_modules", "dist"]));
+        }
+    }
+
+    #[test]
+    fn siblings_sharing_a_prefix_are_disjoint() {
+        for mode in [CacheOverlap::Dedupe, CacheOverlap::Error] {
+            assert_eq!(resolve_overlap(dirs(&["target", "target-wasm"]), mode).unwrap(), dirs(&["target", "target-wasm"]));
+        }
+    }
+
+    #[test]
+    fn nested_dirs_keep_the_outermost() {
+        assert_eq!(resolve_overlap(dirs(&["target/debug", "target"]), CacheOverlap::Dedupe).unwrap(), dirs(&["target"]));
+        assert_eq!(resolve_overlap(dirs(&["./target", "target/release/deps"]), CacheOverlap::Dedupe).unwrap(), dirs(&["./target"]));
+    }
+
+    #[test]
+    fn nested_dirs_are_refused() {
+        let err = resolve_overlap(dirs(&["target", "target/debug"]), CacheOverlap::Error).unwrap_err();
+        assert!(err.to_string().contains("'target/debug' is covered by 'target'"));
+    }
+
+    #[test]
+    fn identical_dirs_keep_the_first() {
+        assert_eq!(resolve_overlap(dirs(&["target", "./target", "dist"]), CacheOverlap::Dedupe).unwrap(), dirs(&["target", "dist"]));
+    }
 
-        println!("📝 Loaded Volt Config\n🚀 Volt is ready!");
-        current_toml.try_into().map_err(Into::into)
+    #[test]
+    fn identical_dirs_are_refused() {
+        let err = resolve_overlap(dirs(&["target", "./target"]), CacheOverlap::Error).unwrap_err();
+        assert!(err.to_string().contains("'./target' is covered by 'target'"));
     }
 }
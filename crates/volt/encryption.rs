@@ -0,0 +1,78 @@
+use crate::failure::Failure;
+use anyhow::{Result, anyhow};
+use ring::{
+    aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+
+/// Starts every archive sealed on the client, followed by the key id and nonce it was sealed with.
+const MAGIC: &[u8; 8] = b"VOLTE2E1";
+const KEY_ID_LEN: usize = 8;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+/// Parses `[security] encryption_key`, 64 hex characters.
+pub fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("encryption_key must be 64 hex characters, generate one with `openssl rand -hex 32`").context(Failure::Config));
+    }
+
+    let mut key = [0u8; 32];
+    for (n, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[n * 2..n * 2 + 2], 16)?;
+    }
+
+    Ok(key)
+}
+
+/// The first bytes of the key's sha256, recorded with each archive so a rotated key is reported as such instead of as corruption.
+fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] { Sha256::digest(key)[..KEY_ID_LEN].try_into().unwrap() }
+
+fn cipher(key: &[u8; 32]) -> LessSafeKey { LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("chacha20-poly1305 takes a 32 byte key")) }
+
+pub fn is_sealed(archive: &[u8]) -> bool { archive.starts_with(MAGIC) }
+
+/// Seals a compressed archive with ChaCha20-Poly1305 under a fresh random nonce, the header doubling as associated data.
+pub fn seal(key: &[u8; 32], archive: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| anyhow!("no randomness available for the nonce"))?;
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + archive.len() + CHACHA20_POLY1305.tag_len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&key_id(key));
+    sealed.extend_from_slice(&nonce);
+
+    let mut body = archive.to_vec();
+    cipher(key)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(&sealed[..HEADER_LEN]), &mut body)
+        .map_err(|_| anyhow!("failed to encrypt archive"))?;
+
+    sealed.extend_from_slice(&body);
+    Ok(sealed)
+}
+
+/// Opens an archive sealed by [`seal`], failing on a different key or on any modified byte.
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < HEADER_LEN || !is_sealed(sealed) {
+        return Err(anyhow!("the archive isn't encrypted, it was pushed without [security] encryption_key or replaced on the server"));
+    }
+
+    let (header, body) = sealed.split_at(HEADER_LEN);
+    let stored_id = &header[MAGIC.len()..MAGIC.len() + KEY_ID_LEN];
+
+    if stored_id != key_id(key) {
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        return Err(anyhow!("the archive was encrypted with key {}, but the configured key is {}", hex(stored_id), hex(&key_id(key))).context(Failure::Config));
+    }
+
+    let nonce = Nonce::try_assume_unique_for_key(&header[MAGIC.len() + KEY_ID_LEN..]).map_err(|_| anyhow!("malformed archive header"))?;
+    let mut body = body.to_vec();
+    let length = cipher(key)
+        .open_in_place(nonce, Aad::from(header), &mut body)
+        .map_err(|_| anyhow!("the archive failed to decrypt, it was modified after it was pushed"))?
+        .len();
+
+    body.truncate(length);
+    Ok(body)
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// Failure categories with stable process exit codes, so scripts can branch on the cause.
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    Build,
+    Network,
+    Auth,
+    Config,
+    CacheMiss,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Build => 1,
+            Failure::Network => 2,
+            Failure::Auth => 3,
+            Failure::Config => 4,
+            Failure::CacheMiss => 5,
+        }
+    }
+
+    pub fn of(err: &anyhow::Error) -> Option<Failure> { err.downcast_ref::<Failure>().copied() }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Failure::Build => "build failed",
+            Failure::Network => "network error",
+            Failure::Auth => "authentication failed",
+            Failure::Config => "invalid configuration",
+            Failure::CacheMiss => "cache miss",
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Failure {}
@@ -0,0 +1,89 @@
+use crate::config::Tuning;
+use rayon::prelude::*;
+use std::io::{self, Read};
+use zstd::{bulk::Compressor, stream::raw::CParameter};
+
+/// Uncompressed bytes per zstd frame. Frames are compressed and decompressed independently, so large restores use every core.
+const FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+/// Compresses `data` as a run of independent frames, which any zstd decoder still reads as one stream.
+pub fn compress(data: &[u8], tuning: &Tuning, dictionary: &[u8]) -> io::Result<Vec<u8>> {
+    let compressor = || -> io::Result<Compressor<'static>> {
+        let mut compressor = Compressor::with_dictionary(tuning.level, dictionary)?;
+        compressor.set_parameter(CParameter::EnableLongDistanceMatching(tuning.long_distance))?;
+        Ok(compressor)
+    };
+
+    if data.is_empty() {
+        return compressor()?.compress(data);
+    }
+
+    let compress = || data.par_chunks(FRAME_SIZE).map(|chunk| compressor()?.compress(chunk)).collect::<io::Result<Vec<_>>>();
+
+    let frames = match tuning.threads {
+        0 => compress()?,
+        threads => rayon::ThreadPoolBuilder::new().num_threads(threads).build().map_err(io::Error::other)?.install(compress)?,
+    };
+
+    Ok(frames.concat())
+}
+
+/// Decompresses every frame in parallel straight into one buffer.
+/// Single-frame archives from older clients, or frames that don't record their size, are decoded as one stream instead.
+pub fn decompress(archive: &[u8], dictionary: &[u8]) -> io::Result<Vec<u8>> {
+    let frames = match split(archive) {
+        Some(frames) if frames.len() > 1 && rayon::current_num_threads() > 1 => frames,
+        _ => return decompress_stream(archive, dictionary),
+    };
+
+    // frames larger than we'd ever write weren't written by us, so their sizes aren't trusted with an allocation
+    let sizes: Option<Vec<usize>> = frames
+        .iter()
+        .map(|frame| zstd::zstd_safe::get_frame_content_size(frame).ok()?.filter(|size| *size <= FRAME_SIZE as u64).map(|size| size as usize))
+        .collect();
+    let Some(sizes) = sizes else {
+        return decompress_stream(archive, dictionary);
+    };
+
+    let mut output = vec![0; sizes.iter().sum()];
+    let mut slots = Vec::with_capacity(frames.len());
+    let mut rest = &mut output[..];
+
+    for size in &sizes {
+        let (slot, tail) = rest.split_at_mut(*size);
+        slots.push(slot);
+        rest = tail;
+    }
+
+    frames.into_par_iter().zip(slots).try_for_each(|(frame, slot)| {
+        let written = zstd::bulk::Decompressor::with_dictionary(dictionary)?.decompress_to_buffer(frame, slot)?;
+
+        match written == slot.len() {
+            true => Ok(()),
+            false => Err(io::Error::new(io::ErrorKind::InvalidData, "zstd frame is shorter than its recorded size")),
+        }
+    })?;
+
+    Ok(output)
+}
+
+fn decompress_stream(archive: &[u8], dictionary: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    zstd::stream::read::Decoder::with_dictionary(archive, dictionary)?.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Cuts an archive at its frame boundaries, or `None` when they can't be found.
+fn split(mut archive: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+
+    while !archive.is_empty() {
+        let size = zstd::zstd_safe::find_frame_compressed_size(archive).ok()?;
+        let (frame, rest) = archive.split_at(size);
+
+        frames.push(frame);
+        archive = rest;
+    }
+
+    Some(frames)
+}
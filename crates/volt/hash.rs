@@ -1,14 +1,34 @@
+use crate::{colors, helpers, pattern};
 use merkle_hash::{Algorithm, MerkleTree};
 use rayon::prelude::*;
-use std::{collections::hash_map::DefaultHasher, hash::Hasher, path::Path, time::UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::Hasher,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
 
 const SAMPLE_RATE: f32 = 0.1;
 const CHUNK_SIZE: usize = 64 * 1024;
+/// Files past this also have their last chunk sampled, catching appended data and footers the head never shows.
+const TAIL_THRESHOLD: u64 = 4 * CHUNK_SIZE as u64;
 
 const MERKLE_TREE_THRESHOLD: usize = 1000;
+const PROGRESS_THRESHOLD: usize = 20_000;
+/// Names the key scheme below, sent with every hash so the server never matches keys across schemes.
+/// Bump it whenever a change here makes the same tree hash differently.
+pub const ALGORITHM: &str = "merkle-blake3-v2";
+
 const DEFAULT_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
 
-fn bytes_to_hex(bytes: impl AsRef<[u8]>) -> String {
+pub fn bytes_to_hex(bytes: impl AsRef<[u8]>) -> String {
     const TABLE: &[u8; 16] = b"0123456789abcdef";
 
     let bytes = bytes.as_ref();
@@ -23,6 +43,9 @@ fn bytes_to_hex(bytes: impl AsRef<[u8]>) -> String {
     hex_string
 }
 
+/// Sha256 of an archive exactly as uploaded, so the server can tell a push corrupted in transit from the one that was sent.
+pub fn content_digest(archive: &[u8]) -> String { bytes_to_hex(Sha256::digest(archive)) }
+
 #[inline]
 fn should_sample(path: &Path) -> bool {
     let mut hasher = DefaultHasher::new();
@@ -31,28 +54,75 @@ fn should_sample(path: &Path) -> bool {
 }
 
 #[inline]
-fn hash_metadata(hasher: &mut DefaultHasher, path: &Path) {
+fn hash_metadata(hasher: &mut DefaultHasher, path: &Path, ignore_mtime: bool) {
     hasher.write(path.as_os_str().as_encoded_bytes());
 
     if let Ok(metadata) = std::fs::metadata(path) {
         hasher.write_u64(metadata.len());
 
+        if ignore_mtime {
+            return;
+        }
+
         let modified_secs = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
         hasher.write_u64(modified_secs);
     }
 }
 
+/// Runs `work` on a rayon pool of at most `max_open` threads, half the soft `RLIMIT_NOFILE` when unset, so a wide machine can't
+/// run the process out of descriptors. Each sampling thread holds one file open at a time, a Merkle build also one directory
+/// handle per level it's inside, so deep trees want a lower cap there.
+fn bounded<T: Send>(max_open: Option<usize>, work: impl FnOnce() -> T + Send) -> T {
+    let threads = max_open.unwrap_or_else(default_open_limit).max(1);
+
+    if threads >= rayon::current_num_threads() {
+        return work();
+    }
+
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool.install(work),
+        Err(_) => work(),
+    }
+}
+
+/// Half the soft descriptor limit, leaving the rest for the sockets and pipes volt holds meanwhile.
+#[cfg(unix)]
+fn default_open_limit() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 if limit.rlim_cur == libc::RLIM_INFINITY => usize::MAX,
+        0 => (limit.rlim_cur as usize / 2).max(16),
+        _ => 256,
+    }
+}
+
+#[cfg(not(unix))]
+fn default_open_limit() -> usize { 256 }
+
 #[inline]
 fn hash_file_sample(hasher: &mut DefaultHasher, path: &Path) {
-    if let Ok(mut file) = std::fs::File::open(path) {
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        if let Ok(bytes_read) = std::io::Read::read(&mut file, &mut buffer) {
-            hasher.write(&buffer[..bytes_read]);
-        }
+    use std::io::{Read, Seek, SeekFrom};
+
+    let Ok(mut file) = std::fs::File::open(path) else { return };
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+
+    if let Ok(bytes_read) = file.read(&mut buffer) {
+        hasher.write(&buffer[..bytes_read]);
+    }
+
+    let Ok(len) = file.metadata().map(|metadata| metadata.len()) else { return };
+    if len <= TAIL_THRESHOLD {
+        return;
+    }
+
+    hasher.write_u64(len);
+    if file.seek(SeekFrom::Start(len - CHUNK_SIZE as u64)).is_ok() && file.read_exact(&mut buffer).is_ok() {
+        hasher.write(&buffer);
     }
 }
 
-fn compute_cache_merkle(dir: &str) -> Result<String, std::io::Error> {
+fn compute_cache_merkle(dir: &str, ignore_mtime: bool, max_open: Option<usize>) -> Result<String, std::io::Error> {
     let path = Path::new(dir);
     if !path.exists() {
         return Ok(DEFAULT_HASH.to_string());
@@ -65,20 +135,20 @@ fn compute_cache_merkle(dir: &str) -> Result<String, std::io::Error> {
         }
     };
 
-    match MerkleTree::builder(path_str).algorithm(Algorithm::Blake3).hash_names(false).build() {
+    match bounded(max_open, || MerkleTree::builder(path_str).algorithm(Algorithm::Blake3).hash_names(false).build()) {
         Ok(tree) => {
             let hash = bytes_to_hex(tree.root.item.hash);
             Ok(hash)
         }
-        Err(_) => compute_cache_sampling(&[dir.to_string()]),
+        Err(_) => compute_cache_sampling(&[dir.to_string()], &[], None, false, ignore_mtime, max_open),
     }
 }
 
-fn compute_cache_merkle_multi(dirs: &[String]) -> Result<String, std::io::Error> {
+fn compute_cache_merkle_multi(dirs: &[String], ignore_mtime: bool, max_open: Option<usize>) -> Result<String, std::io::Error> {
     let mut merkle_hashes = Vec::new();
 
     for dir in dirs {
-        let hash = compute_cache_merkle(dir)?;
+        let hash = compute_cache_merkle(dir, ignore_mtime, max_open)?;
         merkle_hashes.push(hash);
     }
 
@@ -101,12 +171,74 @@ fn compute_cache_merkle_multi(dirs: &[String]) -> Result<String, std::io::Error>
     Ok(result)
 }
 
-fn compute_cache_sampling(dirs: &[String]) -> Result<String, std::io::Error> {
+/// Runs the Merkle build on a worker thread and hashes with the sampling walk instead once `budget` runs out.
+/// The tree builder can't be interrupted, so an abandoned build runs to completion in the background and its result is dropped.
+fn compute_cache_merkle_within(dirs: &[String], ignore_mtime: bool, budget: Option<Duration>, max_open: Option<usize>) -> Result<String, std::io::Error> {
+    fn build(dirs: &[String], ignore_mtime: bool, max_open: Option<usize>) -> Result<String, std::io::Error> {
+        match dirs {
+            [dir] => compute_cache_merkle(dir, ignore_mtime, max_open),
+            dirs => compute_cache_merkle_multi(dirs, ignore_mtime, max_open),
+        }
+    }
+
+    let Some(budget) = budget else {
+        return build(dirs, ignore_mtime, max_open);
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let owned = dirs.to_vec();
+    std::thread::spawn(move || sender.send(build(&owned, ignore_mtime, max_open)));
+
+    match receiver.recv_timeout(budget) {
+        Ok(result) => result,
+        Err(_) => {
+            eprintln!("{} Merkle hashing took longer than {}ms, falling back to sampling", colors::WARN, budget.as_millis());
+            compute_cache_sampling(dirs, &[], None, false, ignore_mtime, max_open)
+        }
+    }
+}
+
+/// A file's size and modification time in nanoseconds, the part of its metadata a cached hash is trusted against.
+type Stamp = (u64, u128);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos()))
+}
+
+/// Reads the `size mtime hash path` lines written by `save_sidecar`, treating a missing or damaged file as empty.
+fn load_sidecar(path: &Path) -> HashMap<PathBuf, (Stamp, u64)> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, ' ');
+            let (size, modified, hash, file) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+            Some((PathBuf::from(file), ((size.parse().ok()?, modified.parse().ok()?), u64::from_str_radix(hash, 16).ok()?)))
+        })
+        .collect()
+}
+
+fn save_sidecar(path: &Path, entries: &[(&PathBuf, Stamp, u64)]) -> Result<(), std::io::Error> {
+    let content: String = entries
+        .iter()
+        .map(|(file, (size, modified), hash)| format!("{size} {modified} {hash:x} {}\n", file.display()))
+        .collect();
+    let staging = path.with_extension("tmp");
+
+    std::fs::write(&staging, content)?;
+    std::fs::rename(&staging, path)
+}
+
+fn compute_cache_sampling(dirs: &[String], exclude: &[String], sidecar: Option<&Path>, save: bool, ignore_mtime: bool, max_open: Option<usize>) -> Result<String, std::io::Error> {
     let mut all_files = Vec::new();
 
     for dir in dirs {
-        let files: Vec<_> = walkdir::WalkDir::new(dir)
-            .into_iter()
+        let files: Vec<_> = pattern::walk(walkdir::WalkDir::new(dir), exclude)
             .filter_map(|e| e.ok())
             .filter(|e| e.file_type().is_file())
             .map(|e| e.path().to_owned())
@@ -116,41 +248,234 @@ fn compute_cache_sampling(dirs: &[String]) -> Result<String, std::io::Error> {
 
     all_files.sort();
 
-    let hashes: Vec<u64> = all_files
-        .par_iter()
-        .map(|path| {
-            let mut hasher = DefaultHasher::new();
+    let progress = (all_files.len() > PROGRESS_THRESHOLD && std::io::stderr().is_terminal()).then(|| helpers::progress(all_files.len() as u64, "Hashing files"));
+    let hashed = AtomicU64::new(0);
+
+    let cached = sidecar.map(load_sidecar).unwrap_or_default();
+    let reused = AtomicU64::new(0);
+
+    let hashes: Vec<(&PathBuf, Option<Stamp>, u64)> = bounded(max_open, || {
+        all_files
+            .par_iter()
+            .map(|path| {
+                // an unchanged size and mtime means the file can't have been rewritten, so its earlier hash still holds
+                let stamp = sidecar.and_then(|_| stamp(path));
+
+                if let Some(stamp) = stamp
+                    && let Some((cached_stamp, hash)) = cached.get(path)
+                    && *cached_stamp == stamp
+                {
+                    reused.fetch_add(1, Ordering::Relaxed);
+                    return (path, Some(stamp), *hash);
+                }
+
+                let mut hasher = DefaultHasher::new();
+
+                hash_metadata(&mut hasher, path, ignore_mtime);
+
+                if should_sample(path) {
+                    hash_file_sample(&mut hasher, path);
+                }
+
+                if let Some(pb) = &progress {
+                    let done = hashed.fetch_add(1, Ordering::Relaxed) + 1;
+                    if done.is_multiple_of(512) {
+                        pb.set_position(done);
+                    }
+                }
+
+                (path, stamp, hasher.finish())
+            })
+            .collect()
+    });
+
+    if let Some(pb) = progress {
+        pb.finish_and_clear();
+    }
 
-            hash_metadata(&mut hasher, path);
+    // rewritten whenever a file was hashed afresh or one disappeared, so the sidecar only ever holds the current tree
+    if let Some(sidecar) = sidecar
+        && save
+        && (reused.into_inner() as usize != hashes.len() || cached.len() != hashes.len())
+    {
+        let entries: Vec<_> = hashes.iter().filter_map(|(path, stamp, hash)| Some((*path, (*stamp)?, *hash))).collect();
+        save_sidecar(sidecar, &entries)?;
+    }
 
-            if should_sample(path) {
-                hash_file_sample(&mut hasher, path);
-            }
+    let final_hash = hashes.iter().fold(0u64, |a, (_, _, b)| a ^ b);
 
-            hasher.finish()
+    Ok(format!("{:x}", final_hash))
+}
+
+/// The object `HEAD` records for `dir`, when git tracks it and the working tree matches it exactly.
+fn git_object(dir: &str) -> Option<String> {
+    let path = Path::new(dir);
+    let (cwd, spec) = match path.is_dir() {
+        true => (path, "./".to_string()),
+        false => (
+            path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+            format!("./{}", path.file_name()?.to_str()?),
+        ),
+    };
+
+    let git = |args: &[&str]| Command::new("git").arg("-C").arg(cwd).args(args).output().ok().filter(|output| output.status.success());
+
+    let object = git(&["rev-parse", "--verify", "--quiet", &format!("HEAD:{spec}")])?;
+    // submodules are keyed by the commits the index pins them at, so their checkouts can't make the tree look dirty
+    let status = git(&["status", "--porcelain", "--ignore-submodules=all", "--", &spec])?;
+
+    match status.stdout.is_empty() {
+        true => Some(String::from_utf8(object.stdout).ok()?.trim().to_string()),
+        false => None,
+    }
+}
+
+/// Submodules the index records under `dir`, as the paths the file walk would reach them by and the commits they're pinned at.
+fn submodules(dir: &str) -> Vec<(String, String)> {
+    if !Path::new(dir).is_dir() {
+        return Vec::new();
+    }
+
+    let Some(output) = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["ls-files", "--stage", "-z", "--", "."])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+    else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter_map(|entry| {
+            let (stage, path) = entry.split_once('\t')?;
+            let mut fields = stage.split(' ');
+            match (fields.next()?, fields.next()?) {
+                ("160000", commit) => Some((format!("{}/{path}", dir.trim_end_matches('/')), commit.to_string())),
+                _ => None,
+            }
         })
-        .collect();
+        .collect()
+}
 
-    let final_hash = hashes.iter().fold(0u64, |a, b| a ^ b);
+/// Keys `dirs` by the tree and blob ids git has for them, which stay the same across clean checkouts whatever their mtimes.
+/// Anything untracked, outside a repository or with uncommitted changes is hashed from the filesystem by `fallback` instead,
+/// which is handed the submodule paths to leave out of its walk. Submodules count by their recorded commit either way,
+/// whether they're checked out, checked out elsewhere, or not initialized at all.
+pub fn compute_git(dirs: &[String], fallback: impl Fn(&String, &[String]) -> Result<String, std::io::Error>) -> Result<String, std::io::Error> {
+    if dirs.is_empty() {
+        return Ok(DEFAULT_HASH.to_string());
+    }
 
-    Ok(format!("{:x}", final_hash))
+    let mut hasher = Sha256::new();
+
+    for dir in dirs {
+        let submodules = submodules(dir);
+        let skip: Vec<String> = submodules.iter().map(|(path, _)| path.clone()).collect();
+
+        let key = match git_object(dir) {
+            Some(object) => format!("git:{object}"),
+            None => format!("fs:{}", fallback(dir, &skip)?),
+        };
+
+        hasher.update(format!("{dir} {key}\n"));
+
+        for (path, commit) in &submodules {
+            hasher.update(format!("{path} submodule:{commit}\n"));
+        }
+    }
+
+    Ok(bytes_to_hex(hasher.finalize()))
 }
 
-fn count_files_in_dir(dir: &str) -> usize { walkdir::WalkDir::new(dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).count() }
+/// Files under `dirs` larger than `max_bytes`, with their sizes, found the way `archive::build` walks them.
+pub fn oversized(dirs: &[String], exclude: &[String], max_bytes: u64) -> Vec<(PathBuf, u64)> {
+    dirs.iter()
+        .flat_map(|dir| pattern::walk(walkdir::WalkDir::new(dir).follow_links(true).sort_by_file_name(), exclude))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| Some((e.path().to_owned(), e.metadata().ok()?.len())))
+        .filter(|(_, size)| *size > max_bytes)
+        .collect()
+}
 
-pub fn compute_cache(dirs: &[String]) -> Result<String, std::io::Error> {
+pub fn count_files_in_dir(dir: &str, exclude: &[String]) -> usize { pattern::walk(walkdir::WalkDir::new(dir), exclude).filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).count() }
+
+/// Hashes `dirs`, reusing per-file hashes from `sidecar` for files whose size and mtime haven't changed when it's given,
+/// and rewriting it with the fresh ones unless `save` is off.
+/// `ignore_mtime` keeps modification times out of the sampled file hashes, so machines with skewed clocks still agree.
+/// `merkle_budget` caps how long a Merkle build may take before the sampling walk is used instead.
+/// `max_open` caps the threads hashing runs on, and so the files it holds open at once, see `bounded`.
+pub fn compute_cache(
+    dirs: &[String],
+    exclude: &[String],
+    sidecar: Option<&Path>,
+    save: bool,
+    ignore_mtime: bool,
+    merkle_budget: Option<Duration>,
+    max_open: Option<usize>,
+) -> Result<String, std::io::Error> {
     if dirs.is_empty() {
         return Ok(DEFAULT_HASH.to_string());
     }
 
+    // merkle trees can't skip paths or reuse earlier file hashes, so both go through the file walk
+    if !exclude.is_empty() || sidecar.is_some() {
+        return compute_cache_sampling(dirs, exclude, sidecar, save, ignore_mtime, max_open);
+    }
+
     if dirs.len() == 1 {
-        return compute_cache_merkle(&dirs[0]);
+        return compute_cache_merkle_within(dirs, ignore_mtime, merkle_budget, max_open);
     }
 
-    let total_files: usize = dirs.iter().map(|d| count_files_in_dir(d)).sum();
+    let total_files: usize = dirs.iter().map(|d| count_files_in_dir(d, &[])).sum();
 
     match total_files <= MERKLE_TREE_THRESHOLD {
-        true => compute_cache_merkle_multi(dirs),
-        false => compute_cache_sampling(dirs),
+        true => compute_cache_merkle_within(dirs, ignore_mtime, merkle_budget, max_open),
+        false => compute_cache_sampling(dirs, &[], None, false, ignore_mtime, max_open),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(path: &Path) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hash_file_sample(&mut hasher, path);
+        hasher.finish()
+    }
+
+    #[test]
+    fn sample_sees_a_changed_tail() {
+        let path = std::env::temp_dir().join(format!("volt-sample-{}", std::process::id()));
+        let mut content = vec![7u8; TAIL_THRESHOLD as usize + CHUNK_SIZE];
+
+        std::fs::write(&path, &content).unwrap();
+        let before = sample(&path);
+
+        *content.last_mut().unwrap() = 8;
+        std::fs::write(&path, &content).unwrap();
+        let after = sample(&path);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn sample_sees_a_changed_length() {
+        let path = std::env::temp_dir().join(format!("volt-sample-length-{}", std::process::id()));
+        let content = vec![7u8; TAIL_THRESHOLD as usize + CHUNK_SIZE];
+
+        std::fs::write(&path, &content).unwrap();
+        let before = sample(&path);
+
+        std::fs::write(&path, &content[..content.len() - 1]).unwrap();
+        let after = sample(&path);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(before, after);
     }
 }
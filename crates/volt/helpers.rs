@@ -1,8 +1,157 @@
-use super::{anyhow, config::Server, Client, Result, VoltConfig};
+use super::{anyhow, cert, colors, config::Server, failure::Failure, Client, Result, StatusCode, VoltConfig};
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::header::{HeaderMap, HeaderValue};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Read,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::error::Elapsed;
+
+const TICKS: [&str; 11] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏", "✓"];
 
 pub fn create_client(config: &mut VoltConfig) -> Result<Client> {
     config.load_servers()?;
-    Ok(Client::builder().build()?)
+
+    let mut pins: HashMap<String, Vec<String>> = HashMap::new();
+    for server in config.servers.values().filter(|server| server.tls) {
+        if let Some(pin) = &server.pinned_cert_sha256 {
+            pins.entry(server_host(&server.address)).or_default().push(pin.clone());
+        }
+    }
+
+    let version = env!("CARGO_PKG_VERSION");
+    let user_agent = match (&config.network.user_agent, &config.network.user_agent_suffix) {
+        (Some(user_agent), _) => user_agent.clone(),
+        (None, Some(suffix)) => format!("volt/{version} {suffix}"),
+        (None, None) => format!("volt/{version}"),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Volt-Client-Version", HeaderValue::from_static(version));
+
+    let builder = Client::builder().user_agent(user_agent).default_headers(headers);
+
+    if pins.is_empty() {
+        return Ok(builder.build()?);
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = cert::PinnedVerifier::new(pins, provider.clone())?;
+
+    let tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(builder.use_preconfigured_tls(tls).build()?)
+}
+
+/// Reads the config passed as `--path -` from stdin, or fetches the one passed as an `https://` URL.
+/// `http://` is refused unless `allow_insecure` is set, the config decides what gets run and where the cache goes.
+/// Any other path is a file for `VoltConfig::init` to read, or create.
+pub async fn read_inline_config(path: &Path, allow_insecure: bool) -> Result<Option<String>> {
+    let Some(source) = path.to_str() else { return Ok(None) };
+
+    if source == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content).context("Failed to read config from stdin")?;
+        return Ok(Some(content));
+    }
+
+    if source.starts_with("http://") && !allow_insecure {
+        return Err(anyhow!("refusing to fetch config over plain http from {source}, use https or pass --allow-insecure-config"));
+    }
+
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return Ok(None);
+    }
+
+    let client = Client::builder().user_agent(format!("volt/{}", env!("CARGO_PKG_VERSION"))).timeout(Duration::from_secs(30)).build()?;
+    let response = match client.get(source).send().await {
+        Ok(response) => response,
+        Err(err) => return Err(anyhow!("Failed to fetch config from {source}: {}", explain_request_error(&err)).context(Failure::Network)),
+    };
+
+    if !response.status().is_success() {
+        return Err(status_error(response.status()).context(format!("Failed to fetch config from {source}")));
+    }
+
+    Ok(Some(response.text().await.with_context(|| format!("Failed to read config from {source}"))?))
+}
+
+/// The host part of a `host:port` address, lowercased the way TLS server names are compared.
+fn server_host(address: &str) -> String {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()
+}
+
+/// Hands `command` the write end of a pipe through `VOLT_READY_FD`, which the child closes once it holds the push lock.
+/// The caller drops the returned writer after spawning, so the reader sees EOF as soon as the child is done with it.
+#[cfg(unix)]
+pub fn ready_pipe(command: &mut std::process::Command) -> std::io::Result<(std::io::PipeReader, std::io::PipeWriter)> {
+    use std::os::{fd::AsRawFd, unix::process::CommandExt};
+
+    let (reader, writer) = std::io::pipe()?;
+    let fd = writer.as_raw_fd();
+    command.env("VOLT_READY_FD", fd.to_string());
+
+    // std opens everything close-on-exec, the child's copy has to survive into `volt push`
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    Ok((reader, writer))
+}
+
+/// Waits until the child given `reader`'s pipe has signalled or exited, for at most `timeout`.
+#[cfg(unix)]
+pub async fn wait_ready(reader: std::io::PipeReader, timeout: Duration) {
+    use tokio::io::AsyncReadExt;
+
+    let Ok(mut reader) = tokio::net::unix::pipe::Receiver::from_owned_fd(reader.into()) else { return };
+    let _ = tokio::time::timeout(timeout, reader.read(&mut [0])).await;
+}
+
+/// Closes the pipe from [`ready_pipe`] when this is a background push, telling the `volt run` that spawned it to go on.
+#[cfg(unix)]
+pub fn signal_ready() {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    static SIGNALLED: std::sync::Once = std::sync::Once::new();
+
+    SIGNALLED.call_once(|| {
+        if let Some(fd) = std::env::var("VOLT_READY_FD").ok().and_then(|fd| fd.parse().ok()) {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+    });
+}
+
+pub fn spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    let style = ProgressStyle::with_template("\n{spinner:.green} {msg}").unwrap().tick_strings(&TICKS);
+
+    pb.set_style(style);
+    pb.enable_steady_tick(Duration::from_millis(80));
+    pb
+}
+
+pub fn progress(total: u64, message: &'static str) -> ProgressBar {
+    let pb = ProgressBar::new(total);
+    let style = ProgressStyle::with_template("{spinner:.green} {msg} [{bar:30.cyan/blue}] {pos}/{len}").unwrap().tick_strings(&TICKS);
+
+    pb.set_style(style);
+    pb.set_message(message);
+    pb.enable_steady_tick(Duration::from_millis(80));
+    pb
 }
 
 pub fn parse_server(line: &str) -> Result<Server> {
@@ -16,14 +165,200 @@ pub fn parse_server(line: &str) -> Result<Server> {
     let rest = if tls { rest } else { line };
 
     let (token, address) = rest.split_once('@').map_or((None, rest), |(t, a)| (Some(t), a));
+    let (address, pin) = address.split_once('#').map_or((address, None), |(a, p)| (a, Some(p)));
+
+    let pinned_cert_sha256 = match pin {
+        None => None,
+        Some(_) if !tls => return Err(anyhow!("A pinned certificate requires a tls:// server")),
+        Some(pin) => Some(cert::normalize_pin(pin).ok_or_else(|| anyhow!("pinned_cert_sha256 must be 64 hex characters, got '{pin}'"))?),
+    };
 
     Ok(Server {
         tls,
         address: address.to_string(),
         token: token.map(ToString::to_string),
+        pinned_cert_sha256,
     })
 }
 
+/// The `[tls://][token@]address[#pin]` line a server file holds, the inverse of `parse_server`.
+pub fn format_server(server: &Server) -> String {
+    let protocol = if server.tls { "tls://" } else { "" };
+    let token = server.token.as_ref().map_or(String::new(), |token| format!("{token}@"));
+    let pin = server.pinned_cert_sha256.as_ref().map_or(String::new(), |pin| format!("#{pin}"));
+
+    format!("{protocol}{token}{}{pin}", server.address)
+}
+
+/// A 503 the server sent while in maintenance, told apart from a saturated server by its `X-Volt-Maintenance` header.
+#[derive(Debug)]
+pub struct Maintenance {
+    pub message: String,
+    pub retry_after: Option<u64>,
+}
+
+impl Display for Maintenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.retry_after {
+            Some(secs) => write!(f, "the server is in maintenance, try again in {secs}s: {}", self.message),
+            None => write!(f, "the server is in maintenance: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Maintenance {}
+
+/// `status_error` for a whole response, reading the body of a maintenance 503 into a `Maintenance` error.
+pub async fn response_error(response: reqwest::Response) -> anyhow::Error {
+    let status = response.status();
+
+    if status != StatusCode::SERVICE_UNAVAILABLE || !response.headers().contains_key("X-Volt-Maintenance") {
+        return status_error(status);
+    }
+
+    let retry_after = response.headers().get("Retry-After").and_then(|value| value.to_str().ok()?.parse().ok());
+    let message = response.text().await.unwrap_or_default().trim().to_string();
+
+    anyhow::Error::new(Maintenance { message, retry_after }).context(Failure::Network)
+}
+
+pub fn is_maintenance(err: &anyhow::Error) -> bool { err.downcast_ref::<Maintenance>().is_some() }
+
+pub fn status_error(status: StatusCode) -> anyhow::Error {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => anyhow!(status).context(Failure::Auth),
+        _ => anyhow!(status),
+    }
+}
+
+/// Turns a failed request into a hint about which layer broke: DNS, TCP, TLS or a timeout.
+pub fn explain_request_error(err: &reqwest::Error) -> String {
+    if err.is_timeout() {
+        return "Timed out waiting for the server, is the host reachable?".to_string();
+    }
+
+    let mut source = std::error::Error::source(err);
+
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>()
+            && io.kind() == std::io::ErrorKind::ConnectionRefused
+        {
+            return "Connection refused, is volt-server running on that port?".to_string();
+        }
+
+        let message = cause.to_string().to_lowercase();
+
+        if message.contains("dns error") || message.contains("failed to lookup") {
+            return "Could not resolve the server address (DNS failure)".to_string();
+        }
+
+        if message.contains("certificate") || message.contains("tls") || message.contains("ssl") {
+            return format!("TLS handshake failed: {cause}");
+        }
+
+        source = cause.source();
+    }
+
+    format!("Connection failed: {err}")
+}
+
+/// Reads `max-age` from a `Cache-Control` header, in seconds.
+pub fn max_age(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    let cache_control = headers.get("Cache-Control")?.to_str().ok()?;
+    cache_control.split(',').find_map(|directive| directive.trim().strip_prefix("max-age=")?.parse().ok())
+}
+
+/// Clock difference to the server past which file mtimes, and so cache keys, are likely to drift from other machines'.
+const CLOCK_SKEW_WARNING: Duration = Duration::from_secs(120);
+
+/// Compares the server's `Date` header with the local clock and warns when they're far enough apart to split cache keys.
+pub fn warn_clock_skew(headers: &reqwest::header::HeaderMap) {
+    let Some(server) = headers.get("Date").and_then(|date| date.to_str().ok()).and_then(|date| httpdate::parse_http_date(date).ok()) else {
+        return;
+    };
+
+    let (skew, direction) = match SystemTime::now().duration_since(server) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(behind) => (behind.duration(), "behind"),
+    };
+
+    if skew > CLOCK_SKEW_WARNING {
+        eprintln!(
+            "{} This machine's clock is {} {direction} the server's. File mtimes feed the cache key, so identical files may hash differently here than elsewhere; fix the clock or set `[hash] ignore_mtime = true`",
+            colors::WARN,
+            format_age(skew).yellow()
+        );
+    }
+}
+
+/// Flattens a timed out or failed step into the message `server diag` prints for it.
+pub fn within<T, E: Display>(result: Result<Result<T, E>, Elapsed>) -> Result<T, String> {
+    match result {
+        Ok(result) => result.map_err(|err| err.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+/// Prints one `server diag` stage with how long it took, handing back its value when it passed.
+pub fn diag_stage<T>(stage: &str, started: Instant, outcome: Result<(String, T), String>) -> Option<T> {
+    let elapsed = format!("{}ms", started.elapsed().as_millis());
+
+    match outcome {
+        Ok((detail, value)) => {
+            println!("  {} {stage:<5} {:>7}  {detail}", colors::OK, elapsed.dimmed());
+            Some(value)
+        }
+        Err(err) => {
+            println!("  {} {stage:<5} {:>7}  {}", colors::FAIL, elapsed.dimmed(), err.red());
+            None
+        }
+    }
+}
+
+/// Parses an age like `90s`, `45m`, `12h` or `7d`, a bare number being seconds.
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid age '{value}', expected e.g. 90m, 12h or 7d"))?;
+
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown unit '{unit}', expected s, m, h or d")),
+    };
+
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Rounds an age down to its largest whole unit, like `3h` or `2d`.
+pub fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ ..60 => format!("{secs}s"),
+        secs @ ..3600 => format!("{}m", secs / 60),
+        secs @ ..86_400 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86_400),
+    }
+}
+
+/// Parses sizes like `512kb`, `100mb` or `2gb`, in the same 1024-based units `format_size` prints.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim().to_ascii_lowercase();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: usize = number.parse().map_err(|_| format!("invalid size '{value}', expected e.g. 512kb, 100mb or 2gb"))?;
+
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "kb" | "k" => 1024,
+        "mb" | "m" => 1024 * 1024,
+        "gb" | "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown unit '{unit}', expected b, kb, mb or gb")),
+    };
+
+    number.checked_mul(multiplier).ok_or_else(|| format!("size '{value}' is too large"))
+}
+
 pub fn format_size(bytes: usize) -> String {
     const UNITS: [&str; 4] = ["b", "kb", "mb", "gb"];
     let mut size = bytes as f64;
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use std::{
+    cmp::Reverse,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Compressed archives kept on disk under `<home>/cache/<volt_id>/<hash>.zst`, evicted least recently used first.
+pub struct ArchiveCache {
+    root: PathBuf,
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+}
+
+/// What a prune removed.
+#[derive(Default)]
+pub struct Pruned {
+    pub archives: usize,
+    pub bytes: u64,
+}
+
+impl ArchiveCache {
+    pub fn new(home: &Path, volt_id: &str, max_bytes: u64, max_age: Option<Duration>) -> Self {
+        let root = home.join("cache");
+        let dir = root.join(volt_id);
+
+        ArchiveCache { root, dir, max_bytes, max_age }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        let path = self.dir.join(format!("{hash}.zst"));
+        let archive = fs::read(&path).ok()?;
+
+        // reads count as use, so bump the mtime the eviction order is based on
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        Some(archive)
+    }
+
+    pub fn put(&self, hash: &str, archive: &[u8]) -> Result<()> {
+        if archive.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)?;
+
+        let staging = self.dir.join(format!("{hash}.zst.tmp"));
+        fs::write(&staging, archive)?;
+        fs::rename(&staging, self.dir.join(format!("{hash}.zst")))?;
+
+        self.prune().map(drop)
+    }
+
+    /// Removes archives unused for longer than `max_age`, then the least recently used ones until every project's fit in `max_bytes`.
+    /// Project directories left empty go too.
+    pub fn prune(&self) -> Result<Pruned> {
+        let mut entries = Vec::new();
+        let mut pruned = Pruned::default();
+        let now = SystemTime::now();
+
+        let projects = match fs::read_dir(&self.root) {
+            Ok(projects) => projects,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(pruned),
+            Err(e) => return Err(e.into()),
+        };
+
+        for project in projects {
+            let project = project?.path();
+            if !project.is_dir() {
+                continue;
+            }
+
+            for entry in fs::read_dir(&project)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        entries.sort_by_key(|(modified, ..)| Reverse(*modified));
+
+        let mut total = 0;
+        for (modified, size, path) in entries {
+            let expired = self.max_age.is_some_and(|max_age| now.duration_since(modified).is_ok_and(|age| age > max_age));
+
+            if expired || total + size > self.max_bytes {
+                fs::remove_file(&path)?;
+                pruned.archives += 1;
+                pruned.bytes += size;
+
+                if let Some(project) = path.parent() {
+                    let _ = fs::remove_dir(project);
+                }
+                continue;
+            }
+
+            total += size;
+        }
+
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+use walkdir::{DirEntry, WalkDir};
+
+/// Runs `walker`, skipping anything matched by `exclude` along with everything under an excluded directory.
+pub fn walk(walker: WalkDir, exclude: &[String]) -> impl Iterator<Item = walkdir::Result<DirEntry>> + '_ { walker.into_iter().filter_entry(move |entry| !is_excluded(entry.path(), exclude)) }
+
+/// Patterns containing a `/` match the whole path, where `*` and `?` stay within a segment and `**` spans any number of them.
+/// Patterns without a `/` match any single path component, like in `.gitignore`.
+pub fn is_excluded(path: &Path, patterns: &[String]) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+
+    let path = path.to_string_lossy().replace('\\', "/");
+    let segments: Vec<&str> = path.trim_start_matches("./").split('/').collect();
+
+    patterns.iter().any(|pattern| match pattern.contains('/') {
+        true => {
+            let pattern: Vec<&str> = pattern.trim_start_matches("./").trim_end_matches('/').split('/').collect();
+            matches_segments(&pattern, &segments)
+        }
+        false => segments.iter().any(|name| matches_name(pattern.as_bytes(), name.as_bytes())),
+    })
+}
+
+fn matches_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(name, path)| matches_name(first.as_bytes(), name.as_bytes()) && matches_segments(rest, path)),
+    }
+}
+
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => matches_name(rest, name) || (!name.is_empty() && matches_name(pattern, &name[1..])),
+        (Some((b'?', rest)), Some((_, name))) => matches_name(rest, name),
+        (Some((expected, rest)), Some((actual, name))) => expected == actual && matches_name(rest, name),
+        _ => false,
+    }
+}
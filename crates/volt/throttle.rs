@@ -0,0 +1,57 @@
+use bytes::Bytes;
+use futures::{Stream, StreamExt, stream};
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct TokenBucket {
+    rate: f64,
+    allowance: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            allowance: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    async fn acquire(&mut self, amount: usize) {
+        let now = Instant::now();
+
+        self.allowance = (self.allowance + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.allowance -= amount as f64;
+        self.last = now;
+
+        if self.allowance < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.allowance / self.rate)).await;
+        }
+    }
+}
+
+/// Splits an in-memory payload into a stream of chunks suitable for throttling.
+pub fn chunked(payload: Bytes) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let len = payload.len();
+    stream::iter((0..len).step_by(CHUNK_SIZE).map(move |start| Ok(payload.slice(start..(start + CHUNK_SIZE).min(len)))))
+}
+
+/// Limits a byte stream to `rate` bytes per second, zero or unset means unlimited.
+pub fn throttle<S, E>(inner: S, rate: Option<u64>) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let bucket = rate.filter(|rate| *rate > 0).map(TokenBucket::new);
+
+    stream::unfold((Box::pin(inner), bucket), |(mut inner, mut bucket)| async move {
+        let item = inner.next().await?;
+
+        if let (Ok(chunk), Some(bucket)) = (&item, bucket.as_mut()) {
+            bucket.acquire(chunk.len()).await;
+        }
+
+        Some((item, (inner, bucket)))
+    })
+}